@@ -0,0 +1,248 @@
+// Administrative roles and the admin action log.
+//
+// Principals are granted a role in a stable registry. Admins may manage roles
+// and resume operations; operators may run the operational endpoints (audits,
+// sweeps, deposit scans, reserve reports, the dev faucet). Every admin can do what an operator can. The canister's
+// controllers hold the admin role implicitly and cannot be removed from it.
+// Only controllers may change the configuration, as it decides which ledger
+// and minter hold the users' funds.
+//
+// The registry is seeded with the `admins` of the install and upgrade
+// arguments. Every privileged call that passes the check is appended to an
+// append-only log with its caller and time.
+
+use candid::{CandidType, Deserialize, Principal};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use serde::Serialize;
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+use crate::error::WalletError;
+use crate::memory::{self, Memory};
+use crate::runtime;
+use crate::StorablePrincipal;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    Admin,
+    Operator,
+}
+
+impl Role {
+    // Whether holding this role allows acting as `required`
+    fn covers(self, required: Role) -> bool {
+        self == Role::Admin || self == required
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct RoleAssignment {
+    pub principal: Principal,
+    pub role: Role,
+    pub granted_by: Option<Principal>,  // None when seeded from an install or upgrade argument
+    pub granted_at: u64,
+}
+
+// One privileged call
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct AdminAction {
+    pub id: u64,
+    pub caller: Principal,
+    pub action: String,  // Endpoint called and its arguments
+    pub timestamp: u64,
+}
+
+impl Storable for RoleAssignment {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let bytes = candid::encode_one(self).expect("Failed to encode RoleAssignment");
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode RoleAssignment")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for AdminAction {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let bytes = candid::encode_one(self).expect("Failed to encode AdminAction");
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode AdminAction")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    static ROLES: RefCell<StableBTreeMap<StorablePrincipal, RoleAssignment, Memory>> = RefCell::new(
+        StableBTreeMap::init(memory::get(memory::ROLES_MEMORY_ID))
+    );
+
+    // Admin actions by id, ids assigned sequentially starting at 1
+    static ADMIN_LOG: RefCell<StableBTreeMap<u64, AdminAction, Memory>> = RefCell::new(
+        StableBTreeMap::init(memory::get(memory::ADMIN_LOG_MEMORY_ID))
+    );
+}
+
+// Role of `principal`; controllers are admins
+pub fn role_of(principal: &Principal) -> Option<Role> {
+    if runtime::is_controller(principal) {
+        return Some(Role::Admin);
+    }
+    ROLES.with(|roles| roles.borrow().get(&StorablePrincipal::from(*principal)).map(|assignment| assignment.role))
+}
+
+// Check that the caller holds `required` and return the caller. For queries,
+// which cannot write the log; updates use authorize.
+pub fn require(required: Role) -> Result<Principal, WalletError> {
+    let caller = runtime::caller();
+    match role_of(&caller) {
+        Some(role) if role.covers(required) => Ok(caller),
+        _ => Err(WalletError::Unauthorized),
+    }
+}
+
+// Check that the caller holds `required` and log `action` on their behalf
+pub fn authorize(required: Role, action: &str) -> Result<Principal, WalletError> {
+    let caller = require(required)?;
+    log(caller, action);
+    Ok(caller)
+}
+
+// Check that the caller is a controller and log `action` on their behalf
+pub fn authorize_controller(action: &str) -> Result<Principal, WalletError> {
+    let caller = runtime::caller();
+    if !runtime::is_controller(&caller) {
+        return Err(WalletError::Unauthorized);
+    }
+    log(caller, action);
+    Ok(caller)
+}
+
+fn log(caller: Principal, action: &str) {
+    ADMIN_LOG.with(|log| {
+        let mut log = log.borrow_mut();
+        let id = log.last_key_value().map(|(id, _)| id + 1).unwrap_or(1);
+        log.insert(id, AdminAction {
+            id,
+            caller,
+            action: action.to_string(),
+            timestamp: runtime::time(),
+        });
+    });
+    ic_cdk::println!("[ADMIN] {} by {}", action, caller);
+}
+
+pub fn grant(principal: Principal, role: Role, granted_by: Option<Principal>) -> Result<RoleAssignment, WalletError> {
+    if principal == Principal::anonymous() {
+        return Err(WalletError::InvalidRequest("The anonymous principal cannot hold a role".to_string()));
+    }
+    let assignment = RoleAssignment { principal, role, granted_by, granted_at: runtime::time() };
+    ROLES.with(|roles| roles.borrow_mut().insert(StorablePrincipal::from(principal), assignment.clone()));
+    Ok(assignment)
+}
+
+pub fn revoke(principal: Principal) -> Result<RoleAssignment, WalletError> {
+    if runtime::is_controller(&principal) {
+        return Err(WalletError::InvalidRequest(format!(
+            "{} is a controller and keeps the admin role",
+            principal
+        )));
+    }
+    ROLES.with(|roles| roles.borrow_mut().remove(&StorablePrincipal::from(principal))).ok_or_else(|| {
+        WalletError::InvalidRequest(format!("{} does not hold a role", principal))
+    })
+}
+
+// Grant the admin role to each of `admins` that does not already hold it.
+// Traps on an invalid principal so a bad install or upgrade argument is rolled back.
+pub fn seed(admins: &[Principal]) {
+    for admin in admins {
+        if role_of(admin) == Some(Role::Admin) {
+            continue;
+        }
+        if let Err(e) = grant(*admin, Role::Admin, None) {
+            ic_cdk::trap(&format!("Invalid admin {}: {}", admin, e));
+        }
+    }
+}
+
+pub fn assignments() -> Vec<RoleAssignment> {
+    ROLES.with(|roles| roles.borrow().iter().map(|(_, assignment)| assignment).collect())
+}
+
+// Most recent actions first
+pub fn latest_actions(limit: usize) -> Vec<AdminAction> {
+    ADMIN_LOG.with(|log| log.borrow().iter().rev().take(limit).map(|(_, action)| action).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::principal;
+
+    #[test]
+    fn roles_cover_what_they_should() {
+        let (admin, operator, user) = (principal(1), principal(2), principal(3));
+        grant(admin, Role::Admin, None).unwrap();
+        grant(operator, Role::Operator, Some(admin)).unwrap();
+
+        runtime::set_caller(operator);
+        assert_eq!(authorize(Role::Operator, "run_solvency_audit"), Ok(operator));
+        assert_eq!(authorize(Role::Admin, "resume_operations"), Err(WalletError::Unauthorized));
+
+        runtime::set_caller(admin);
+        assert_eq!(authorize(Role::Operator, "run_solvency_audit"), Ok(admin));
+        assert_eq!(authorize(Role::Admin, "resume_operations"), Ok(admin));
+
+        runtime::set_caller(user);
+        assert_eq!(require(Role::Operator), Err(WalletError::Unauthorized));
+
+        // Only the calls that passed the check are logged
+        let logged: Vec<_> = latest_actions(10).into_iter().map(|action| (action.caller, action.action)).collect();
+        assert_eq!(logged, vec![
+            (admin, "resume_operations".to_string()),
+            (admin, "run_solvency_audit".to_string()),
+            (operator, "run_solvency_audit".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn controllers_are_admins_without_being_listed() {
+        let controller = principal(1);
+        runtime::add_controller(controller);
+
+        assert_eq!(role_of(&controller), Some(Role::Admin));
+        seed(&[controller]);
+        assert!(assignments().is_empty());
+        assert!(matches!(revoke(controller), Err(WalletError::InvalidRequest(_))));
+        assert_eq!(role_of(&controller), Some(Role::Admin));
+
+        runtime::set_caller(controller);
+        assert_eq!(authorize_controller("update_config"), Ok(controller));
+    }
+
+    #[test]
+    fn granting_replaces_and_revoking_removes() {
+        let (admin, other) = (principal(1), principal(2));
+        seed(&[admin]);
+        assert_eq!(role_of(&admin), Some(Role::Admin));
+        assert_eq!(assignments()[0].granted_by, None);
+
+        grant(admin, Role::Operator, Some(other)).unwrap();
+        assert_eq!(role_of(&admin), Some(Role::Operator));
+        runtime::set_caller(admin);
+        assert_eq!(authorize_controller("update_config"), Err(WalletError::Unauthorized));
+
+        assert_eq!(revoke(admin).unwrap().role, Role::Operator);
+        assert_eq!(role_of(&admin), None);
+        assert!(matches!(revoke(admin), Err(WalletError::InvalidRequest(_))));
+        assert!(matches!(grant(Principal::anonymous(), Role::Admin, None), Err(WalletError::InvalidRequest(_))));
+    }
+}
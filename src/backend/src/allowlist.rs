@@ -0,0 +1,246 @@
+// Withdrawal address allowlists.
+//
+// A user can register the Bitcoin addresses they withdraw to and switch on
+// allowlist mode, after which withdraw_testbtc only pays out to registered
+// addresses. So that a compromised session cannot quickly redirect funds,
+// everything that loosens the list waits out the configured cooling-off
+// period: a new address becomes usable only once it has passed, and switching
+// the mode off takes effect only then. Removing an address and switching the
+// mode on apply at once.
+
+use candid::{CandidType, Deserialize, Principal};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use serde::Serialize;
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+use crate::config;
+use crate::error::WalletError;
+use crate::memory::{self, Memory};
+use crate::runtime;
+use crate::StorablePrincipal;
+
+const MAX_ADDRESSES: usize = 20;
+const MAX_LABEL_LENGTH: usize = 64;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct AllowlistEntry {
+    pub address: String,
+    pub label: Option<String>,
+    pub added_at: u64,
+    pub active_at: u64,  // Usable for withdrawals from this time on
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct WithdrawalAllowlist {
+    pub enabled: bool,                  // withdraw_testbtc only pays out to active entries
+    pub disabled_at: Option<u64>,       // Switching off was requested and takes effect at this time
+    pub entries: Vec<AllowlistEntry>,
+}
+
+impl WithdrawalAllowlist {
+    fn enforced(&self, now: u64) -> bool {
+        self.enabled && self.disabled_at.is_none_or(|at| now < at)
+    }
+
+    // Apply a pending switch-off whose time has come
+    fn settle(mut self, now: u64) -> Self {
+        if !self.enforced(now) {
+            self.enabled = false;
+            self.disabled_at = None;
+        }
+        self
+    }
+}
+
+impl Storable for WithdrawalAllowlist {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let bytes = candid::encode_one(self).expect("Failed to encode WithdrawalAllowlist");
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode WithdrawalAllowlist")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    static ALLOWLISTS: RefCell<StableBTreeMap<StorablePrincipal, WithdrawalAllowlist, Memory>> = RefCell::new(
+        StableBTreeMap::init(memory::get(memory::ADDRESS_ALLOWLISTS_MEMORY_ID))
+    );
+}
+
+// End of a cooling-off period starting at `now`
+fn cooled_off(now: u64) -> u64 {
+    now.saturating_add(u64::try_from(config::allowlist_delay().as_nanos()).unwrap_or(u64::MAX))
+}
+
+pub fn get(user: Principal) -> WithdrawalAllowlist {
+    let allowlist = ALLOWLISTS.with(|allowlists| {
+        allowlists.borrow().get(&StorablePrincipal::from(user)).unwrap_or_default()
+    });
+    allowlist.settle(runtime::time())
+}
+
+fn store(user: Principal, allowlist: WithdrawalAllowlist) -> WithdrawalAllowlist {
+    ALLOWLISTS.with(|allowlists| {
+        let mut allowlists = allowlists.borrow_mut();
+        let key = StorablePrincipal::from(user);
+        if !allowlist.enabled && allowlist.entries.is_empty() {
+            allowlists.remove(&key);
+        } else {
+            allowlists.insert(key, allowlist.clone());
+        }
+    });
+    allowlist
+}
+
+// Register `address`, which becomes usable after the cooling-off period. The
+// address must already have passed the format check.
+pub fn add(user: Principal, address: String, label: Option<String>) -> Result<AllowlistEntry, WalletError> {
+    if label.as_ref().is_some_and(|label| label.chars().count() > MAX_LABEL_LENGTH) {
+        return Err(WalletError::InvalidRequest(format!(
+            "Labels are limited to {} characters",
+            MAX_LABEL_LENGTH
+        )));
+    }
+
+    let mut allowlist = get(user);
+    if allowlist.entries.iter().any(|entry| entry.address == address) {
+        return Err(WalletError::InvalidRequest(format!("{} is already on the allowlist", address)));
+    }
+    if allowlist.entries.len() >= MAX_ADDRESSES {
+        return Err(WalletError::InvalidRequest(format!(
+            "The allowlist holds at most {} addresses",
+            MAX_ADDRESSES
+        )));
+    }
+
+    let now = runtime::time();
+    let entry = AllowlistEntry {
+        address,
+        label,
+        added_at: now,
+        active_at: cooled_off(now),
+    };
+    allowlist.entries.push(entry.clone());
+    store(user, allowlist);
+    Ok(entry)
+}
+
+pub fn remove(user: Principal, address: &str) -> Result<AllowlistEntry, WalletError> {
+    let mut allowlist = get(user);
+    let position = allowlist
+        .entries
+        .iter()
+        .position(|entry| entry.address == address)
+        .ok_or_else(|| WalletError::InvalidRequest(format!("{} is not on the allowlist", address)))?;
+    let entry = allowlist.entries.remove(position);
+    store(user, allowlist);
+    Ok(entry)
+}
+
+// Switching on applies at once; switching off once the cooling-off period has passed
+pub fn set_enabled(user: Principal, enabled: bool) -> WithdrawalAllowlist {
+    let mut allowlist = get(user);
+    if enabled {
+        allowlist.enabled = true;
+        allowlist.disabled_at = None;
+    } else if allowlist.enabled && allowlist.disabled_at.is_none() {
+        allowlist.disabled_at = Some(cooled_off(runtime::time()));
+    }
+    store(user, allowlist)
+}
+
+// Refuse a withdrawal to `address` while allowlist mode is on and the address
+// is not an active entry
+pub fn check(user: Principal, address: &str) -> Result<(), WalletError> {
+    let now = runtime::time();
+    let allowlist = get(user);
+    if !allowlist.enforced(now) {
+        return Ok(());
+    }
+    match allowlist.entries.iter().find(|entry| entry.address == address) {
+        Some(entry) if entry.active_at <= now => Ok(()),
+        Some(entry) => Err(WalletError::AddressNotAllowed { active_at: Some(entry.active_at) }),
+        None => Err(WalletError::AddressNotAllowed { active_at: None }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::UpgradeArgs;
+    use crate::testing::principal;
+
+    const HOME: &str = "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx";
+    const OTHER: &str = "tb1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3q0sl5k7";
+    const DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+    #[test]
+    fn new_address_waits_out_the_cooling_off() {
+        let alice = principal(1);
+        set_enabled(alice, true);
+        let entry = add(alice, HOME.to_string(), Some("cold wallet".to_string())).unwrap();
+        assert_eq!(entry.active_at, runtime::time() + DAY);
+
+        assert_eq!(check(alice, HOME), Err(WalletError::AddressNotAllowed { active_at: Some(entry.active_at) }));
+        assert_eq!(check(alice, OTHER), Err(WalletError::AddressNotAllowed { active_at: None }));
+        runtime::advance(DAY - 1);
+        assert!(check(alice, HOME).is_err());
+        runtime::advance(1);
+        assert_eq!(check(alice, HOME), Ok(()));
+
+        // Other users' lists are their own
+        assert_eq!(check(principal(2), OTHER), Ok(()));
+    }
+
+    #[test]
+    fn removal_applies_at_once() {
+        let alice = principal(1);
+        add(alice, HOME.to_string(), None).unwrap();
+        set_enabled(alice, true);
+        runtime::advance(DAY);
+        assert_eq!(check(alice, HOME), Ok(()));
+
+        remove(alice, HOME).unwrap();
+        assert_eq!(check(alice, HOME), Err(WalletError::AddressNotAllowed { active_at: None }));
+        assert!(matches!(remove(alice, HOME), Err(WalletError::InvalidRequest(_))));
+    }
+
+    #[test]
+    fn switching_off_waits_out_the_cooling_off() {
+        let alice = principal(1);
+        set_enabled(alice, true);
+        assert!(check(alice, OTHER).is_err());
+
+        let allowlist = set_enabled(alice, false);
+        assert!(allowlist.enabled);
+        assert_eq!(allowlist.disabled_at, Some(runtime::time() + DAY));
+        runtime::advance(DAY - 1);
+        assert!(check(alice, OTHER).is_err());
+        runtime::advance(1);
+        assert_eq!(check(alice, OTHER), Ok(()));
+        assert!(!get(alice).enabled);
+
+        // Switching back on is immediate and drops a pending switch-off
+        set_enabled(alice, true);
+        set_enabled(alice, false);
+        let allowlist = set_enabled(alice, true);
+        assert_eq!(allowlist.disabled_at, None);
+        runtime::advance(DAY);
+        assert!(check(alice, OTHER).is_err());
+    }
+
+    #[test]
+    fn cooling_off_follows_the_configured_delay() {
+        let alice = principal(1);
+        config::update(UpgradeArgs { allowlist_delay: Some(Some(60)), ..UpgradeArgs::default() }).unwrap();
+        let entry = add(alice, HOME.to_string(), None).unwrap();
+        assert_eq!(entry.active_at, runtime::time() + 60 * 1_000_000_000);
+        assert!(matches!(add(alice, HOME.to_string(), None), Err(WalletError::InvalidRequest(_))));
+    }
+}
@@ -0,0 +1,177 @@
+// Satoshi amounts.
+//
+// Amounts arrive as Candid `nat` and are kept as u64 satoshis. Every amount a
+// user asks to move goes through Amount::parse: it must fit in a u64 and must
+// not be zero. Endpoints with a higher floor (e.g. a withdrawal that deducts
+// the ledger fee) check it with at_least. Arithmetic on balances uses
+// checked_add and checked_sub, which report overflow as an error instead of
+// wrapping or trapping.
+
+use candid::Nat;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::Storable;
+use std::borrow::Cow;
+use std::fmt;
+
+use crate::error::WalletError;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Amount(u64);
+
+impl Amount {
+    pub const ZERO: Amount = Amount(0);
+
+    pub const fn from_sats(sats: u64) -> Self {
+        Amount(sats)
+    }
+
+    pub const fn sats(self) -> u64 {
+        self.0
+    }
+
+    pub const fn is_zero(self) -> bool {
+        self.0 == 0
+    }
+
+    // Any amount that fits in a u64, zero included
+    pub fn from_nat(value: &Nat) -> Result<Self, WalletError> {
+        u64::try_from(&value.0).map(Amount).map_err(|_| {
+            WalletError::InvalidAmount(format!("Amount {} exceeds the maximum of {} satoshis", value, u64::MAX))
+        })
+    }
+
+    // An amount a user asks to move: fits in a u64 and is not zero
+    pub fn parse(value: &Nat) -> Result<Self, WalletError> {
+        let amount = Self::from_nat(value)?;
+        if amount.is_zero() {
+            return Err(WalletError::InvalidAmount("Amount must be greater than zero".to_string()));
+        }
+        Ok(amount)
+    }
+
+    pub fn at_least(self, minimum: Amount) -> Result<Self, WalletError> {
+        if self < minimum {
+            return Err(WalletError::AmountTooLow { minimum: minimum.into() });
+        }
+        Ok(self)
+    }
+
+    pub fn checked_add(self, other: Amount) -> Result<Self, WalletError> {
+        self.0.checked_add(other.0).map(Amount).ok_or_else(|| {
+            WalletError::InvalidAmount(format!("{} + {} satoshis overflows", self, other))
+        })
+    }
+
+    // Fails with InsufficientFunds when `other` is larger than `self`
+    pub fn checked_sub(self, other: Amount) -> Result<Self, WalletError> {
+        self.0.checked_sub(other.0).map(Amount).ok_or(WalletError::InsufficientFunds {
+            balance: self.into(),
+            required: Some(other.into()),
+        })
+    }
+}
+
+impl From<Amount> for Nat {
+    fn from(amount: Amount) -> Self {
+        Nat::from(amount.0)
+    }
+}
+
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+// Same encoding as u64, so maps that stored u64 satoshis read back unchanged
+impl Storable for Amount {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(self.0.to_bytes().into_owned())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Amount(u64::from_bytes(bytes))
+    }
+
+    const BOUND: Bound = <u64 as Storable>::BOUND;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::Nat;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn nat_round_trips(sats in any::<u64>()) {
+            let amount = Amount::from_nat(&Nat::from(sats)).unwrap();
+            prop_assert_eq!(amount.sats(), sats);
+            prop_assert_eq!(Nat::from(amount), Nat::from(sats));
+        }
+
+        #[test]
+        fn nat_above_u64_is_rejected(excess in 1u64..) {
+            let value = Nat::from(u64::MAX) + Nat::from(excess);
+            prop_assert!(matches!(Amount::from_nat(&value), Err(WalletError::InvalidAmount(_))));
+            prop_assert!(matches!(Amount::parse(&value), Err(WalletError::InvalidAmount(_))));
+        }
+
+        #[test]
+        fn parse_accepts_every_non_zero_amount(sats in 1u64..) {
+            prop_assert_eq!(Amount::parse(&Nat::from(sats)).unwrap(), Amount::from_sats(sats));
+        }
+
+        #[test]
+        fn add_matches_u64(a in any::<u64>(), b in any::<u64>()) {
+            let sum = Amount::from_sats(a).checked_add(Amount::from_sats(b));
+            match a.checked_add(b) {
+                Some(expected) => prop_assert_eq!(sum.unwrap().sats(), expected),
+                None => prop_assert!(matches!(sum, Err(WalletError::InvalidAmount(_)))),
+            }
+        }
+
+        #[test]
+        fn sub_matches_u64(a in any::<u64>(), b in any::<u64>()) {
+            let difference = Amount::from_sats(a).checked_sub(Amount::from_sats(b));
+            match a.checked_sub(b) {
+                Some(expected) => prop_assert_eq!(difference.unwrap().sats(), expected),
+                None => prop_assert_eq!(difference, Err(WalletError::InsufficientFunds {
+                    balance: Nat::from(a),
+                    required: Some(Nat::from(b)),
+                })),
+            }
+        }
+
+        #[test]
+        fn add_then_sub_restores(a in any::<u64>(), b in any::<u64>()) {
+            let (a, b) = (Amount::from_sats(a), Amount::from_sats(b));
+            if let Ok(sum) = a.checked_add(b) {
+                prop_assert_eq!(sum.checked_sub(b).unwrap(), a);
+            }
+        }
+
+        #[test]
+        fn at_least_matches_ordering(sats in any::<u64>(), minimum in any::<u64>()) {
+            let result = Amount::from_sats(sats).at_least(Amount::from_sats(minimum));
+            if sats >= minimum {
+                prop_assert_eq!(result.unwrap().sats(), sats);
+            } else {
+                prop_assert_eq!(result, Err(WalletError::AmountTooLow { minimum: Nat::from(minimum) }));
+            }
+        }
+
+        #[test]
+        fn storable_matches_u64(sats in any::<u64>()) {
+            let amount = Amount::from_sats(sats);
+            prop_assert_eq!(amount.to_bytes(), sats.to_bytes());
+            prop_assert_eq!(Amount::from_bytes(sats.to_bytes()), amount);
+        }
+    }
+
+    #[test]
+    fn zero_is_not_a_valid_request() {
+        assert_eq!(Amount::from_nat(&Nat::from(0u64)), Ok(Amount::ZERO));
+        assert!(matches!(Amount::parse(&Nat::from(0u64)), Err(WalletError::InvalidAmount(_))));
+    }
+}
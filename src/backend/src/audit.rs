@@ -0,0 +1,290 @@
+// Scheduled solvency audits and the withdrawal circuit breaker.
+//
+// A timer runs a reserve check against the ledger every AUDIT_INTERVAL and
+// appends the outcome to a stable audit log. An audit that finds the backend
+// holding less than it owes trips the circuit breaker, which pauses every
+// operation that moves funds out of custody or between users.
+//
+// The balances are read one account at a time while deposits keep being
+// credited, so a deposit landing in a subaccount after it was read counts as
+// owed without counting as held. Such a deficit is gone on the next read, so an
+// audit that finds one reads the reserves again and trips the breaker only if
+// the second read still finds a deficit. A ledger that cannot be read never
+// trips it. The breaker stays
+// tripped until an admin acknowledges it and resumes operations; later
+// audits never reset it on their own.
+
+use candid::{CandidType, Deserialize, Principal};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, StableCell, Storable};
+use serde::Serialize;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::time::Duration;
+
+use crate::error::WalletError;
+use crate::guard::TaskGuard;
+use crate::ledger::{IcLedger, Ledger};
+use crate::memory::{self, Memory};
+use crate::runtime;
+use crate::reserves;
+
+const AUDIT_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// Outcome of one solvency audit
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct AuditRecord {
+    pub id: u64,
+    pub timestamp: u64,
+    pub total_virtual_balances: u64,
+    pub backend_actual_balance: u64,
+    pub is_solvent: bool,
+    pub deficit: u64,
+    pub error: Option<String>,  // Set when the ledger could not be read; nothing was compared
+}
+
+impl Storable for AuditRecord {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let bytes = candid::encode_one(self).expect("Failed to encode AuditRecord");
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode AuditRecord")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct CircuitBreaker {
+    pub tripped: bool,
+    pub tripped_at: Option<u64>,
+    pub tripped_by_audit: Option<u64>,      // Audit that found the deficit
+    pub acknowledged_by: Option<Principal>, // Admin that last resumed operations
+    pub acknowledged_at: Option<u64>,
+}
+
+impl Storable for CircuitBreaker {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let bytes = candid::encode_one(self).expect("Failed to encode CircuitBreaker");
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode CircuitBreaker")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    // Audit results by id, ids assigned sequentially starting at 1
+    static AUDIT_LOG: RefCell<StableBTreeMap<u64, AuditRecord, Memory>> = RefCell::new(
+        StableBTreeMap::init(memory::get(memory::AUDIT_LOG_MEMORY_ID))
+    );
+
+    static CIRCUIT_BREAKER: RefCell<StableCell<CircuitBreaker, Memory>> = RefCell::new(
+        StableCell::init(memory::get(memory::CIRCUIT_BREAKER_MEMORY_ID), CircuitBreaker::default())
+            .expect("Failed to init circuit breaker")
+    );
+}
+
+// Timers do not survive upgrades; call from both init and post_upgrade
+pub fn start_timer() {
+    ic_cdk_timers::set_timer_interval(AUDIT_INTERVAL, || ic_cdk::spawn(async {
+        run(&IcLedger).await;
+    }));
+}
+
+fn append(mut record: AuditRecord) -> AuditRecord {
+    AUDIT_LOG.with(|log| {
+        let mut log = log.borrow_mut();
+        record.id = log.last_key_value().map(|(id, _)| id + 1).unwrap_or(1);
+        log.insert(record.id, record.clone());
+    });
+    record
+}
+
+fn trip(audit_id: u64) {
+    CIRCUIT_BREAKER.with(|breaker| {
+        let mut breaker = breaker.borrow_mut();
+        let mut state = breaker.get().clone();
+        if state.tripped {
+            return;
+        }
+        state.tripped = true;
+        state.tripped_at = Some(runtime::time());
+        state.tripped_by_audit = Some(audit_id);
+        breaker.set(state).expect("Failed to write circuit breaker");
+    });
+}
+
+// Check reserves against the ledger, log the result and trip the breaker on a
+// deficit found by two reads in a row. Returns None if another audit or a sweep
+// is still in progress.
+pub async fn run(ledger: &impl Ledger) -> Option<AuditRecord> {
+    let _running = TaskGuard::new(&reserves::POOL_TASK_RUNNING)?;
+    let result = match reserves::check(ledger).await {
+        Ok(status) if !status.is_solvent => {
+            ic_cdk::println!(
+                "[AUDIT] Deficit of {} satoshis on first read, reading reserves again",
+                status.deficit
+            );
+            reserves::check(ledger).await
+        }
+        result => result,
+    };
+
+    let timestamp = runtime::time();
+    let record = match result {
+        Ok(status) => AuditRecord {
+            id: 0,
+            timestamp,
+            total_virtual_balances: status.total_virtual_balances,
+            backend_actual_balance: status.backend_actual_balance,
+            is_solvent: status.is_solvent,
+            deficit: status.deficit,
+            error: None,
+        },
+        Err(e) => AuditRecord {
+            id: 0,
+            timestamp,
+            total_virtual_balances: reserves::total_virtual_balances(),
+            backend_actual_balance: 0,
+            is_solvent: false,
+            deficit: 0,
+            error: Some(e),
+        },
+    };
+    let record = append(record);
+
+    match &record.error {
+        Some(e) => ic_cdk::println!("[AUDIT] Audit {} could not read reserves: {}", record.id, e),
+        None if record.is_solvent => ic_cdk::println!(
+            "[AUDIT] Audit {}: solvent, held {} against {} owed",
+            record.id, record.backend_actual_balance, record.total_virtual_balances
+        ),
+        None => {
+            ic_cdk::println!(
+                "[AUDIT] Audit {}: INSOLVENT, deficit {} satoshis - pausing withdrawals and transfers",
+                record.id, record.deficit
+            );
+            trip(record.id);
+        }
+    }
+
+    Some(record)
+}
+
+pub fn circuit_breaker() -> CircuitBreaker {
+    CIRCUIT_BREAKER.with(|breaker| breaker.borrow().get().clone())
+}
+
+// Acknowledge a tripped breaker and resume operations
+pub fn resume(by: Principal) -> Result<CircuitBreaker, WalletError> {
+    CIRCUIT_BREAKER.with(|breaker| {
+        let mut breaker = breaker.borrow_mut();
+        let mut state = breaker.get().clone();
+        if !state.tripped {
+            return Err(WalletError::InvalidRequest("Circuit breaker is not tripped".to_string()));
+        }
+        state.tripped = false;
+        state.acknowledged_by = Some(by);
+        state.acknowledged_at = Some(runtime::time());
+        breaker.set(state.clone()).expect("Failed to write circuit breaker");
+        Ok(state)
+    })
+}
+
+// Most recent audits, newest first
+pub fn latest(limit: usize) -> Vec<AuditRecord> {
+    AUDIT_LOG.with(|log| {
+        log.borrow()
+            .iter()
+            .rev()
+            .take(limit)
+            .map(|(_, record)| record)
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::Amount;
+    use crate::pause::{self, Operation};
+    use crate::testing::{backend_account, block_on, principal, MockLedger};
+    use crate::{balances, custody, deposits};
+
+    // Owe `owed` satoshis to a user while holding `held` in the omnibus account
+    fn reserves(owed: u64, held: u64) -> MockLedger {
+        let ledger = MockLedger::new(10);
+        ledger.mint(backend_account(None), held);
+        balances::credit(principal(1), Amount::from_sats(owed)).unwrap();
+        ledger
+    }
+
+    #[test]
+    fn deficit_trips_the_breaker_until_an_admin_resumes() {
+        let ledger = reserves(1_000, 400);
+
+        let record = block_on(run(&ledger)).unwrap();
+        assert!(!record.is_solvent);
+        assert_eq!(record.deficit, 600);
+        assert_eq!(circuit_breaker().tripped_by_audit, Some(record.id));
+        assert!(matches!(
+            pause::ensure_enabled(Operation::WithdrawToLedger),
+            Err(WalletError::Paused { .. })
+        ));
+        assert!(pause::ensure_enabled(Operation::Deposit).is_ok());
+
+        // Covering the deficit does not resume operations by itself
+        ledger.mint(backend_account(None), 600);
+        assert!(block_on(run(&ledger)).unwrap().is_solvent);
+        assert!(circuit_breaker().tripped);
+
+        let admin = principal(9);
+        let breaker = resume(admin).unwrap();
+        assert!(!breaker.tripped);
+        assert_eq!(breaker.acknowledged_by, Some(admin));
+        assert!(pause::ensure_enabled(Operation::WithdrawToLedger).is_ok());
+        assert!(matches!(resume(admin), Err(WalletError::InvalidRequest(_))));
+    }
+
+    #[test]
+    fn deposit_credited_mid_audit_does_not_trip() {
+        let alice = principal(1);
+        let slot = backend_account(Some(custody::subaccount_for(alice)));
+        let ledger = MockLedger::new(10);
+        let block = ledger.mint(slot.clone(), 1_000);
+        deposits::credit(alice, block, Amount::from_sats(1_000), None, slot.clone()).unwrap();
+
+        // A second deposit is credited right after the audit read alice's slot
+        let mut pending = true;
+        ledger.after_balance_read(move |ledger, account| {
+            if *account == slot && std::mem::take(&mut pending) {
+                let block = ledger.mint(slot.clone(), 500);
+                deposits::credit(alice, block, Amount::from_sats(500), None, slot.clone()).unwrap();
+            }
+        });
+
+        let record = block_on(run(&ledger)).unwrap();
+        assert!(record.is_solvent);
+        assert_eq!(record.total_virtual_balances, 1_500);
+        assert_eq!(record.backend_actual_balance, 1_500);
+        assert!(!circuit_breaker().tripped);
+        assert_eq!(latest(10).len(), 1);
+    }
+
+    #[test]
+    fn unreadable_ledger_does_not_trip() {
+        let ledger = reserves(1_000, 0);
+        *ledger.balance_error.borrow_mut() = Some("ledger unavailable".to_string());
+
+        let record = block_on(run(&ledger)).unwrap();
+        assert_eq!(record.error.as_deref(), Some("ledger unavailable"));
+        assert!(!circuit_breaker().tripped);
+        assert!(pause::ensure_enabled(Operation::WithdrawToLedger).is_ok());
+    }
+}
@@ -0,0 +1,83 @@
+// Virtual balance bookkeeping on USER_BALANCES.
+//
+// Endpoints that pay out across an inter-canister call debit the balance before
+// the call and credit it back if the call fails, so the funds in flight can never
+// be spent a second time by an interleaved call.
+
+use candid::Principal;
+
+use crate::amount::Amount;
+use crate::error::WalletError;
+use crate::{StorablePrincipal, USER_BALANCES};
+
+pub fn get(user: Principal) -> Amount {
+    USER_BALANCES.with(|balances| {
+        balances.borrow().get(&StorablePrincipal::from(user)).unwrap_or_default()
+    })
+}
+
+// Remove `amount` from the user's balance and return the new balance
+pub fn debit(user: Principal, amount: Amount) -> Result<Amount, WalletError> {
+    USER_BALANCES.with(|balances| {
+        let mut balances = balances.borrow_mut();
+        let user = StorablePrincipal::from(user);
+        let new_balance = balances.get(&user).unwrap_or_default().checked_sub(amount)?;
+        balances.insert(user, new_balance);
+        Ok(new_balance)
+    })
+}
+
+// Add `amount` to the user's balance and return the new balance
+pub fn credit(user: Principal, amount: Amount) -> Result<Amount, WalletError> {
+    USER_BALANCES.with(|balances| {
+        let mut balances = balances.borrow_mut();
+        let user = StorablePrincipal::from(user);
+        let new_balance = balances.get(&user).unwrap_or_default().checked_add(amount)?;
+        balances.insert(user, new_balance);
+        Ok(new_balance)
+    })
+}
+
+// Move `amount` from one user's balance to another's. Both balances are
+// checked before either is written, so a failure leaves both unchanged.
+// Returns the two new balances.
+pub fn transfer(from: Principal, to: Principal, amount: Amount) -> Result<(Amount, Amount), WalletError> {
+    USER_BALANCES.with(|balances| {
+        let mut balances = balances.borrow_mut();
+        let (from, to) = (StorablePrincipal::from(from), StorablePrincipal::from(to));
+        let new_from_balance = balances.get(&from).unwrap_or_default().checked_sub(amount)?;
+        let new_to_balance = balances.get(&to).unwrap_or_default().checked_add(amount)?;
+        balances.insert(from, new_from_balance);
+        balances.insert(to, new_to_balance);
+        Ok((new_from_balance, new_to_balance))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn funded_user(byte: u8, sats: u64) -> Principal {
+        let user = Principal::from_slice(&[byte; 29]);
+        credit(user, Amount::from_sats(sats)).unwrap();
+        user
+    }
+
+    #[test]
+    fn debit_never_overdraws() {
+        let alice = funded_user(1, 1_000);
+        assert_eq!(debit(alice, Amount::from_sats(400)), Ok(Amount::from_sats(600)));
+        assert!(matches!(debit(alice, Amount::from_sats(601)), Err(WalletError::InsufficientFunds { .. })));
+        assert_eq!(get(alice), Amount::from_sats(600));
+        assert_eq!(credit(alice, Amount::from_sats(400)), Ok(Amount::from_sats(1_000)));
+    }
+
+    #[test]
+    fn failed_transfer_leaves_both_balances_unchanged() {
+        let (alice, bob) = (funded_user(4, 100), funded_user(5, u64::MAX));
+        assert!(transfer(alice, bob, Amount::from_sats(50)).is_err());
+        assert!(transfer(alice, funded_user(6, 0), Amount::from_sats(150)).is_err());
+        assert_eq!(get(alice), Amount::from_sats(100));
+        assert_eq!(get(bob), Amount::from_sats(u64::MAX));
+    }
+}
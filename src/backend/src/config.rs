@@ -0,0 +1,250 @@
+// Runtime configuration.
+//
+// Which ledger and minter the backend talks to, the Bitcoin network, fee
+// overrides, withdrawal limits and the Bitcoin withdrawal and allowlist delays
+// are set by the install argument, can be changed by the upgrade argument or by an admin
+// through update_config, and are kept in stable memory so they survive upgrades. Anything not given falls back
+// to the build's defaults: the local mock canisters for development builds, the
+// IC ckTestBTC canisters otherwise. The `admins` of an argument are granted the
+// admin role (see admin) rather than stored here.
+
+use candid::{CandidType, Deserialize, Principal};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableCell, Storable};
+use serde::Serialize;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::time::Duration;
+
+use crate::admin;
+use crate::error::WalletError;
+use crate::memory::{self, Memory};
+use crate::runtime;
+
+const DEFAULT_ALLOWLIST_DELAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+// Bitcoin network the minter settles on
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Network {
+    Testnet,
+    Regtest,  // Local replica with the mock minter
+}
+
+// Enforced by limits; None for no cap
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct WithdrawalLimits {
+    pub min_withdrawal: u64,          // Smallest amount withdraw_funds and withdraw_testbtc accept
+    pub max_withdrawal: Option<u64>,  // Largest single withdrawal
+    pub per_user_daily: Option<u64>,  // Most one user may withdraw in any 24 hours
+    pub global_daily: Option<u64>,    // Most all users together may withdraw in any 24 hours
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Config {
+    pub ledger_id: Principal,
+    pub minter_id: Principal,
+    pub network: Network,
+    pub ledger_fee: Option<u64>,  // Fee paid on ledger transfers instead of the one read from the ledger
+    pub limits: WithdrawalLimits,
+    pub btc_withdrawal_delay: Option<u64>,  // Seconds withdraw_testbtc requests wait, cancellable; None sends them at once
+    pub allowlist_delay: Option<u64>,       // Seconds before allowlist changes that loosen it apply; None for a day
+    pub updated_at: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct InitArgs {
+    pub ledger_id: Option<Principal>,
+    pub minter_id: Option<Principal>,
+    pub network: Option<Network>,
+    pub ledger_fee: Option<u64>,
+    pub limits: Option<WithdrawalLimits>,
+    pub btc_withdrawal_delay: Option<u64>,
+    pub allowlist_delay: Option<u64>,
+    pub admins: Option<Vec<Principal>>,  // Granted the admin role
+}
+
+// Fields left out keep their current value
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct UpgradeArgs {
+    pub ledger_id: Option<Principal>,
+    pub minter_id: Option<Principal>,
+    pub network: Option<Network>,
+    pub ledger_fee: Option<Option<u64>>,  // Some(None) goes back to the ledger's fee
+    pub limits: Option<WithdrawalLimits>,
+    pub btc_withdrawal_delay: Option<Option<u64>>,  // Some(None) sends withdrawals at once again
+    pub allowlist_delay: Option<Option<u64>>,       // Some(None) goes back to the default
+    pub admins: Option<Vec<Principal>>,   // Granted the admin role in addition to the current admins
+}
+
+// Argument of both install and upgrade, as the ckBTC canisters take it
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub enum BackendArg {
+    Init(InitArgs),
+    Upgrade(Option<UpgradeArgs>),
+}
+
+impl Storable for Config {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let bytes = candid::encode_one(self).expect("Failed to encode Config");
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode Config")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[cfg(feature = "development")]
+mod defaults {
+    use super::Network;
+
+    pub const LEDGER_ID: &str = match option_env!("LOCAL_MOCK_LEDGER_CANISTER_ID") {
+        Some(id) => id,
+        None => "umunu-kh777-77774-qaaca-cai", // Default local mock ledger
+    };
+    pub const MINTER_ID: &str = match option_env!("LOCAL_MOCK_MINTER_CANISTER_ID") {
+        Some(id) => id,
+        None => "ulvla-h7777-77774-qaacq-cai", // Default local mock minter
+    };
+    pub const NETWORK: Network = Network::Regtest;
+}
+
+#[cfg(not(feature = "development"))]
+mod defaults {
+    use super::Network;
+
+    pub const LEDGER_ID: &str = match option_env!("IC_CKTESTBTC_CANISTER_ID") {
+        Some(id) => id,
+        None => "g4xu7-jiaaa-aaaan-aaaaq-cai", // Default mainnet ckTestBTC
+    };
+    pub const MINTER_ID: &str = match option_env!("IC_MINTER_CANISTER_ID") {
+        Some(id) => id,
+        None => "mqygn-kiaaa-aaaar-qaadq-cai", // Default IC ckTestBTC minter
+    };
+    pub const NETWORK: Network = Network::Testnet;
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            ledger_id: Principal::from_text(defaults::LEDGER_ID).expect("Invalid default ledger principal"),
+            minter_id: Principal::from_text(defaults::MINTER_ID).expect("Invalid default minter principal"),
+            network: defaults::NETWORK,
+            ledger_fee: None,
+            limits: WithdrawalLimits::default(),
+            btc_withdrawal_delay: None,
+            allowlist_delay: None,
+            updated_at: 0,
+        }
+    }
+}
+
+thread_local! {
+    static CONFIG: RefCell<StableCell<Config, Memory>> = RefCell::new(
+        StableCell::init(memory::get(memory::CONFIG_MEMORY_ID), Config::default())
+            .expect("Failed to init config")
+    );
+}
+
+pub fn get() -> Config {
+    CONFIG.with(|config| config.borrow().get().clone())
+}
+
+pub fn ledger_id() -> Principal {
+    CONFIG.with(|config| config.borrow().get().ledger_id)
+}
+
+pub fn minter_id() -> Principal {
+    CONFIG.with(|config| config.borrow().get().minter_id)
+}
+
+pub fn network() -> Network {
+    CONFIG.with(|config| config.borrow().get().network)
+}
+
+// How long withdraw_testbtc requests are held before they are sent, if at all
+pub fn btc_withdrawal_delay() -> Option<Duration> {
+    CONFIG.with(|config| config.borrow().get().btc_withdrawal_delay)
+        .filter(|seconds| *seconds > 0)
+        .map(Duration::from_secs)
+}
+
+// Cooling-off period of withdrawal allowlist changes (see allowlist)
+pub fn allowlist_delay() -> Duration {
+    CONFIG.with(|config| config.borrow().get().allowlist_delay)
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_ALLOWLIST_DELAY)
+}
+
+fn validate(config: &Config) -> Result<(), WalletError> {
+    if config.ledger_id == Principal::anonymous() || config.minter_id == Principal::anonymous() {
+        return Err(WalletError::InvalidRequest("Ledger and minter ids must not be anonymous".to_string()));
+    }
+    if let Some(max) = config.limits.max_withdrawal {
+        if max < config.limits.min_withdrawal {
+            return Err(WalletError::InvalidRequest(format!(
+                "Maximum withdrawal {} is below the minimum {}",
+                max, config.limits.min_withdrawal
+            )));
+        }
+    }
+    Ok(())
+}
+
+fn store(config: Config) -> Result<Config, WalletError> {
+    validate(&config)?;
+    CONFIG.with(|cell| cell.borrow_mut().set(config.clone()).expect("Failed to write config"));
+    Ok(config)
+}
+
+// Replace the configuration on install; traps on an invalid argument so the
+// install fails instead of leaving the canister misconfigured
+pub fn init(args: InitArgs) {
+    let defaults = Config::default();
+    let config = Config {
+        ledger_id: args.ledger_id.unwrap_or(defaults.ledger_id),
+        minter_id: args.minter_id.unwrap_or(defaults.minter_id),
+        network: args.network.unwrap_or(defaults.network),
+        ledger_fee: args.ledger_fee,
+        limits: args.limits.unwrap_or(defaults.limits),
+        btc_withdrawal_delay: args.btc_withdrawal_delay,
+        allowlist_delay: args.allowlist_delay,
+        updated_at: runtime::time(),
+    };
+    if let Err(e) = store(config) {
+        ic_cdk::trap(&format!("Invalid init argument: {}", e));
+    }
+    admin::seed(&args.admins.unwrap_or_default());
+}
+
+// Apply the fields given in `args` on top of the stored configuration
+pub fn update(args: UpgradeArgs) -> Result<Config, WalletError> {
+    let mut config = get();
+    if let Some(ledger_id) = args.ledger_id {
+        config.ledger_id = ledger_id;
+    }
+    if let Some(minter_id) = args.minter_id {
+        config.minter_id = minter_id;
+    }
+    if let Some(network) = args.network {
+        config.network = network;
+    }
+    if let Some(ledger_fee) = args.ledger_fee {
+        config.ledger_fee = ledger_fee;
+    }
+    if let Some(limits) = args.limits {
+        config.limits = limits;
+    }
+    if let Some(btc_withdrawal_delay) = args.btc_withdrawal_delay {
+        config.btc_withdrawal_delay = btc_withdrawal_delay;
+    }
+    if let Some(allowlist_delay) = args.allowlist_delay {
+        config.allowlist_delay = allowlist_delay;
+    }
+    config.updated_at = runtime::time();
+    let config = store(config)?;
+    admin::seed(&args.admins.unwrap_or_default());
+    Ok(config)
+}
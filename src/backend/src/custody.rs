@@ -0,0 +1,85 @@
+// Registry of the backend's per-user custodial subaccounts.
+//
+// Custody model: a user's virtual balance in USER_BALANCES is the only record of
+// what the backend owes them. Every ledger account the backend owns - its
+// default (omnibus) account and one subaccount per user derived by
+// generate_subaccount_for_user - is part of a single pool backing those
+// balances. A user's subaccount is just the slot their deposits arrive in:
+// funds landing there are credited to the virtual balance once (see deposits),
+// and its ledger balance says nothing about what the user owns afterwards.
+//
+// The ledger cannot list the subaccounts of an owner, so every subaccount that
+// has received a deposit is recorded here together with the user it belongs
+// to. Handing a subaccount out registers nothing: reserve checks and sweeps
+// read every registered subaccount, and any principal can ask for its deposit
+// slot, so only a credited deposit adds to that work. The scanner cannot tell
+// whose an unregistered slot is when someone else funded it; its owner claims
+// such a transfer with notify_deposit, which accepts any sender, and from then
+// on the slot is registered and scanned.
+
+use candid::Principal;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+use crate::memory::{self, Memory};
+use crate::{generate_subaccount_for_user, StorablePrincipal};
+
+thread_local! {
+    // Custodial subaccount -> user it was derived for
+    static CUSTODIAL_SUBACCOUNTS: RefCell<StableBTreeMap<[u8; 32], StorablePrincipal, Memory>> = RefCell::new(
+        StableBTreeMap::init(memory::get(memory::CUSTODIAL_SUBACCOUNTS_MEMORY_ID))
+    );
+}
+
+// Custodial subaccount of `user`. Not registered until a deposit into it is credited.
+pub fn subaccount_for(user: Principal) -> Vec<u8> {
+    generate_subaccount_for_user(user)
+}
+
+// Record `user`'s subaccount so reserve checks and sweeps include it
+pub fn register(user: Principal) {
+    let subaccount: [u8; 32] = generate_subaccount_for_user(user)
+        .try_into()
+        .expect("Custodial subaccounts are 32 bytes");
+    CUSTODIAL_SUBACCOUNTS.with(|subaccounts| {
+        let mut subaccounts = subaccounts.borrow_mut();
+        if !subaccounts.contains_key(&subaccount) {
+            subaccounts.insert(subaccount, StorablePrincipal::from(user));
+        }
+    });
+}
+
+// User a custodial subaccount belongs to. A subaccount that is not registered
+// yet is recognised when it was funded by its owner.
+pub fn owner_of(subaccount: &[u8], sender: Option<Principal>) -> Option<Principal> {
+    let key: [u8; 32] = subaccount.try_into().ok()?;
+    if let Some(user) = CUSTODIAL_SUBACCOUNTS.with(|subaccounts| subaccounts.borrow().get(&key)) {
+        return Some(user.0);
+    }
+    sender.filter(|sender| generate_subaccount_for_user(*sender) == subaccount)
+}
+
+// Every registered (user, custodial subaccount) pair
+pub fn accounts() -> Vec<(Principal, Vec<u8>)> {
+    CUSTODIAL_SUBACCOUNTS.with(|subaccounts| {
+        subaccounts
+            .borrow()
+            .iter()
+            .map(|(subaccount, user)| (user.0, subaccount.to_vec()))
+            .collect()
+    })
+}
+
+pub fn len() -> u64 {
+    CUSTODIAL_SUBACCOUNTS.with(|subaccounts| subaccounts.borrow().len())
+}
+
+// Register the subaccount of every user the backend already knows about
+// (layout v3 -> v4). Subaccounts handed out before the registry existed can
+// only belong to users with a virtual balance or a journal entry.
+pub fn register_known_users(users: impl IntoIterator<Item = Principal>) {
+    for user in users {
+        register(user);
+    }
+    ic_cdk::println!("[UPGRADE] Registered {} custodial subaccounts", len());
+}
@@ -0,0 +1,440 @@
+// Crediting of ledger deposits into custodial subaccounts.
+//
+// A deposit is a ledger block that moves funds into a user's custodial
+// subaccount. Crediting it adds the on-chain amount to the user's virtual
+// balance and journals it. Every credited block index is kept in a stable set,
+// so a block is credited at most once however it is reported: by the user
+// through notify_deposit, or by the scanner that tails the ledger.
+
+use candid::{CandidType, Deserialize, Nat, Principal};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, StableCell, Storable};
+use serde::Serialize;
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::time::Duration;
+
+use crate::amount::Amount;
+use crate::error::WalletError;
+use crate::guard::TaskGuard;
+use crate::journal::{self, Counterparty, JournalRecord, TransactionChannel};
+use crate::ledger::{IcLedger, Ledger, LedgerTransaction, LedgerTransfer};
+use crate::memory::{self, Memory};
+use crate::runtime;
+use crate::{balances, custody, Account, TransactionStatus, TransactionType};
+
+thread_local! {
+    // Ledger block index -> journal id of the deposit it was credited as
+    static CREDITED_BLOCKS: RefCell<StableBTreeMap<u64, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(memory::get(memory::CREDITED_BLOCKS_MEMORY_ID))
+    );
+}
+
+pub fn is_credited(block_index: u64) -> bool {
+    CREDITED_BLOCKS.with(|credited| credited.borrow().contains_key(&block_index))
+}
+
+// Credit `amount` from ledger block `block_index` to `user` and register their
+// subaccount. `from` is the sending account, None for a mint. Returns the
+// journal id of the deposit.
+pub fn credit(
+    user: Principal,
+    block_index: u64,
+    amount: Amount,
+    from: Option<Account>,
+    to: Account,
+) -> Result<u64, WalletError> {
+    if is_credited(block_index) {
+        return Err(WalletError::AlreadyCredited { block_index: Nat::from(block_index) });
+    }
+
+    let new_balance = balances::credit(user, amount)?;
+    custody::register(user);
+    let tx_id = journal::record(JournalRecord {
+        tx_type: TransactionType::Deposit,
+        channel: TransactionChannel::Ledger,
+        from: from.map(Counterparty::Account),
+        to: Some(Counterparty::Account(to)),
+        amount: Nat::from(amount),
+        virtual_amount: Some(amount.sats()),
+        fee: None,
+        status: TransactionStatus::Confirmed,
+        block_index: Some(Nat::from(block_index)),
+    });
+    CREDITED_BLOCKS.with(|credited| credited.borrow_mut().insert(block_index, tx_id));
+
+    ic_cdk::println!(
+        "[DEPOSITS] Credited block {} to {}: {} satoshis, virtual balance {}",
+        block_index, user, amount, new_balance
+    );
+    Ok(tx_id)
+}
+
+// Credit ledger block `block_index` to `user` after checking with the ledger
+// that it transferred exactly `amount` into `user`'s custodial subaccount. The
+// sender does not matter: a subaccount funded by someone else before its owner
+// deposited is only credited this way, as the scanner cannot attribute it yet.
+pub async fn notify(
+    ledger: &impl Ledger,
+    user: Principal,
+    block_index: u64,
+    amount: &Nat,
+) -> Result<LedgerTransfer, WalletError> {
+    if is_credited(block_index) {
+        return Err(WalletError::AlreadyCredited { block_index: Nat::from(block_index) });
+    }
+
+    // Verify the transfer against the ledger's own record of the block
+    let transfer = match ledger.get_block(block_index).await.map_err(WalletError::LedgerUnavailable)? {
+        Some(transaction) => transaction.transfer.ok_or_else(|| {
+            WalletError::InvalidRequest(format!("Block {} is a {} block, not a transfer", block_index, transaction.kind))
+        })?,
+        None => {
+            return Err(WalletError::InvalidRequest(format!("Block {} does not exist on the ledger", block_index)))
+        }
+    };
+
+    let custodial_account = Account {
+        owner: runtime::id(),
+        subaccount: Some(custody::subaccount_for(user)),
+    };
+    if transfer.to != custodial_account {
+        return Err(WalletError::InvalidRequest(format!(
+            "Block {} is not a transfer into the caller's custodial account",
+            block_index
+        )));
+    }
+    if transfer.amount != *amount {
+        return Err(WalletError::InvalidRequest(format!(
+            "Block {} transferred {} satoshis, not the notified {}",
+            block_index, transfer.amount, amount
+        )));
+    }
+
+    let credited = Amount::parse(&transfer.amount)?;
+    credit(user, block_index, credited, Some(transfer.from.clone()), custodial_account)?;
+    Ok(transfer)
+}
+
+// ============================================================
+// LEDGER SCANNER - credit deposits nobody reported
+// ============================================================
+
+// Blocks requested from the ledger per call, and calls per timer tick
+const SCAN_BATCH_SIZE: u64 = 1_000;
+const SCAN_BATCHES_PER_TICK: u32 = 10;
+const SCAN_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ScannerState {
+    pub next_block: u64,             // First ledger block not yet scanned
+    pub last_scan_at: Option<u64>,
+    pub last_error: Option<String>,
+}
+
+impl Storable for ScannerState {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let bytes = candid::encode_one(self).expect("Failed to encode ScannerState");
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode ScannerState")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    static SCANNER: RefCell<StableCell<ScannerState, Memory>> = RefCell::new(
+        StableCell::init(memory::get(memory::DEPOSIT_SCANNER_MEMORY_ID), ScannerState::default())
+            .expect("Failed to init deposit scanner state")
+    );
+
+    // Set while a scan is waiting on the ledger so timer ticks don't overlap
+    static SCAN_RUNNING: Cell<bool> = const { Cell::new(false) };
+}
+
+// Timers do not survive upgrades; call from both init and post_upgrade
+pub fn start_scanner() {
+    ic_cdk_timers::set_timer_interval(SCAN_INTERVAL, || ic_cdk::spawn(async {
+        scan(&IcLedger).await;
+    }));
+}
+
+pub fn scanner_state() -> ScannerState {
+    SCANNER.with(|scanner| scanner.borrow().get().clone())
+}
+
+fn set_scanner_state(state: ScannerState) {
+    SCANNER.with(|scanner| {
+        scanner
+            .borrow_mut()
+            .set(state)
+            .expect("Failed to write deposit scanner state");
+    });
+}
+
+// Start over on a new ledger. Its block indices have nothing to do with the
+// old ledger's, so neither the credited blocks nor the scanner cursor apply.
+pub fn reset_for_new_ledger() {
+    CREDITED_BLOCKS.with(|credited| credited.borrow_mut().clear_new());
+    set_scanner_state(ScannerState::default());
+}
+
+// The deposit in a block, if it moves funds into a custodial subaccount from
+// outside the backend: (user, amount, sender, custodial account)
+fn deposit_in(block: &LedgerTransaction) -> Option<(Principal, Nat, Option<Account>, Account)> {
+    let backend = runtime::id();
+    let (from, to, amount) = match (&block.transfer, &block.mint) {
+        (Some(transfer), _) => (Some(transfer.from.clone()), &transfer.to, &transfer.amount),
+        (None, Some(mint)) => (None, &mint.to, &mint.amount),
+        (None, None) => return None,
+    };
+
+    if to.owner != backend || from.as_ref().is_some_and(|from| from.owner == backend) {
+        return None;
+    }
+    let user = custody::owner_of(to.subaccount.as_deref()?, from.as_ref().map(|from| from.owner))?;
+    Some((user, amount.clone(), from, to.clone()))
+}
+
+// Tail the ledger from the persisted cursor and credit every deposit into a
+// custodial subaccount that has not been credited yet. Credits and the cursor
+// move together after each batch, so a scan that fails part way resumes where
+// it stopped. Returns the number of deposits credited.
+pub async fn scan(ledger: &impl Ledger) -> u64 {
+    let Some(_running) = TaskGuard::new(&SCAN_RUNNING) else {
+        return 0;
+    };
+
+    let mut credited = 0u64;
+    let mut state = scanner_state();
+    state.last_error = None;
+
+    for _ in 0..SCAN_BATCHES_PER_TICK {
+        let range = match ledger.get_transactions(state.next_block, SCAN_BATCH_SIZE).await {
+            Ok(range) => range,
+            Err(e) => {
+                ic_cdk::println!("[DEPOSITS] Ledger scan at block {} failed: {}", state.next_block, e);
+                state.last_error = Some(e);
+                break;
+            }
+        };
+
+        // Never skip over blocks the ledger did not return
+        if let Some((first, _)) = range.blocks.first().filter(|(first, _)| *first > state.next_block) {
+            let e = format!("Ledger returned blocks from {} when {} was requested", first, state.next_block);
+            ic_cdk::println!("[DEPOSITS] {}", e);
+            state.last_error = Some(e);
+            break;
+        }
+
+        let mut next_block = state.next_block;
+        for (index, block) in range.blocks.iter() {
+            if *index < next_block {
+                continue;
+            }
+            next_block = index + 1;
+
+            let Some((user, amount, from, to)) = deposit_in(block) else {
+                continue;
+            };
+            if is_credited(*index) {
+                continue;
+            }
+            let amount = match Amount::from_nat(&amount) {
+                Ok(amount) => amount,
+                Err(e) => {
+                    ic_cdk::println!("[DEPOSITS] Skipping block {}: {}", index, e);
+                    continue;
+                }
+            };
+            match credit(user, *index, amount, from, to) {
+                Ok(_) => credited += 1,
+                Err(e) => ic_cdk::println!("[DEPOSITS] Could not credit block {}: {}", index, e),
+            }
+        }
+
+        let caught_up = next_block == state.next_block;
+        state.next_block = next_block;
+        state.last_scan_at = Some(runtime::time());
+        set_scanner_state(state.clone());
+        if caught_up || (range.blocks.len() as u64) < SCAN_BATCH_SIZE {
+            break;
+        }
+    }
+
+    set_scanner_state(state);
+    credited
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{backend_account, block_on, principal, user_account, MockLedger};
+
+    fn slot_of(user: Principal) -> Account {
+        backend_account(Some(custody::subaccount_for(user)))
+    }
+
+    #[test]
+    fn owner_claims_a_deposit_someone_else_made() {
+        let (alice, bob) = (principal(1), principal(2));
+        let ledger = MockLedger::new(10);
+        ledger.mint(user_account(bob), 10_000);
+        let gift = ledger.send(user_account(bob), slot_of(alice), 2_500);
+
+        // Nobody can tell the scanner whose unregistered slot that is
+        assert_eq!(block_on(scan(&ledger)), 0);
+        assert!(!is_credited(gift));
+
+        // The sender cannot claim it, and the owner has to name the right amount
+        let result = block_on(notify(&ledger, bob, gift, &Nat::from(2_500u64)));
+        assert!(matches!(result, Err(WalletError::InvalidRequest(_))));
+        let result = block_on(notify(&ledger, alice, gift, &Nat::from(2_000u64)));
+        assert!(matches!(result, Err(WalletError::InvalidRequest(_))));
+
+        let transfer = block_on(notify(&ledger, alice, gift, &Nat::from(2_500u64))).unwrap();
+        assert_eq!(transfer.from, user_account(bob));
+        assert_eq!(balances::get(alice), Amount::from_sats(2_500));
+        assert_eq!(balances::get(bob), Amount::ZERO);
+        assert_eq!(custody::accounts(), vec![(alice, custody::subaccount_for(alice))]);
+        let result = block_on(notify(&ledger, alice, gift, &Nat::from(2_500u64)));
+        assert_eq!(result.err(), Some(WalletError::AlreadyCredited { block_index: Nat::from(gift) }));
+
+        // Once registered, the slot's later deposits are found by the scanner
+        ledger.send(user_account(bob), slot_of(alice), 1_000);
+        assert_eq!(block_on(scan(&ledger)), 1);
+        assert_eq!(balances::get(alice), Amount::from_sats(3_500));
+    }
+
+    #[test]
+    fn notify_checks_the_block_against_the_ledger() {
+        let (alice, bob) = (principal(1), principal(2));
+        let ledger = MockLedger::new(10);
+        let minted = ledger.mint(user_account(bob), 10_000);
+        let elsewhere = ledger.send(user_account(bob), slot_of(bob), 1_000);
+        let deposit = ledger.send(user_account(bob), slot_of(alice), 1_000);
+        let amount = Nat::from(1_000u64);
+
+        for block in [minted, elsewhere, 99] {
+            let result = block_on(notify(&ledger, alice, block, &amount));
+            assert!(matches!(result, Err(WalletError::InvalidRequest(_))), "block {}", block);
+        }
+        assert_eq!(balances::get(alice), Amount::ZERO);
+        assert!(custody::accounts().is_empty());
+
+        block_on(notify(&ledger, alice, deposit, &amount)).unwrap();
+        assert_eq!(balances::get(alice), Amount::from_sats(1_000));
+    }
+
+    #[test]
+    fn a_block_is_credited_once() {
+        let alice = principal(1);
+        let slot = slot_of(alice);
+        let amount = Amount::from_sats(700);
+
+        let tx_id = credit(alice, 5, amount, None, slot.clone()).unwrap();
+        let result = credit(alice, 5, amount, None, slot.clone());
+        assert_eq!(result.err(), Some(WalletError::AlreadyCredited { block_index: Nat::from(5u64) }));
+        assert_eq!(balances::get(alice), amount);
+        assert!(is_credited(5));
+        assert_eq!(CREDITED_BLOCKS.with(|credited| credited.borrow().get(&5)), Some(tx_id));
+
+        // Blocks are deduplicated by index alone, whoever claims them
+        let result = credit(principal(2), 5, amount, None, slot);
+        assert!(matches!(result, Err(WalletError::AlreadyCredited { .. })));
+        assert_eq!(balances::get(principal(2)), Amount::ZERO);
+    }
+
+    #[test]
+    fn scanner_skips_the_backends_own_transfers() {
+        let alice = principal(1);
+        let ledger = MockLedger::new(10);
+        custody::register(alice);
+        ledger.mint(backend_account(None), 5_000);
+        ledger.send(backend_account(None), slot_of(alice), 1_000);
+
+        assert_eq!(block_on(scan(&ledger)), 0);
+        assert_eq!(balances::get(alice), Amount::ZERO);
+        assert_eq!(scanner_state().next_block, 2);
+    }
+
+    #[test]
+    fn scanner_cursor_spans_batches_and_ticks() {
+        let alice = principal(1);
+        let ledger = MockLedger::new(10);
+        custody::register(alice);
+        let filler = user_account(principal(2));
+        let per_tick = SCAN_BATCH_SIZE * SCAN_BATCHES_PER_TICK as u64;
+
+        for _ in 0..1_500 {
+            ledger.mint(filler.clone(), 1);
+        }
+        let first = ledger.mint(slot_of(alice), 300);
+        while ledger.mint(filler.clone(), 1) < per_tick + 200 {}
+        let second = ledger.mint(slot_of(alice), 400);
+
+        // One tick reads SCAN_BATCHES_PER_TICK full batches and stops there
+        assert_eq!(block_on(scan(&ledger)), 1);
+        assert!(is_credited(first) && !is_credited(second));
+        assert_eq!(scanner_state().next_block, per_tick);
+
+        assert_eq!(block_on(scan(&ledger)), 1);
+        assert_eq!(balances::get(alice), Amount::from_sats(700));
+        assert_eq!(scanner_state().next_block, second + 1);
+
+        // Caught up: nothing new, the cursor stays put
+        assert_eq!(block_on(scan(&ledger)), 0);
+        assert_eq!(scanner_state().next_block, second + 1);
+        assert_eq!(scanner_state().last_error, None);
+    }
+
+    #[test]
+    fn scanner_stops_at_a_gap_in_the_ledger() {
+        let alice = principal(1);
+        let ledger = MockLedger::new(10);
+        custody::register(alice);
+        ledger.mint(slot_of(alice), 100);
+        ledger.mint(slot_of(alice), 200);
+        ledger.first_served_block.set(1);
+
+        assert_eq!(block_on(scan(&ledger)), 0);
+        let state = scanner_state();
+        assert_eq!(state.next_block, 0);
+        assert!(state.last_error.is_some());
+        assert_eq!(balances::get(alice), Amount::ZERO);
+
+        ledger.first_served_block.set(0);
+        assert_eq!(block_on(scan(&ledger)), 2);
+        assert_eq!(scanner_state().last_error, None);
+        assert_eq!(balances::get(alice), Amount::from_sats(300));
+    }
+
+    #[test]
+    fn notify_then_scan_credits_once() {
+        let (alice, bob) = (principal(1), principal(2));
+        let ledger = MockLedger::new(10);
+        ledger.mint(user_account(bob), 10_000);
+        let deposit = ledger.send(user_account(bob), slot_of(alice), 1_000);
+
+        block_on(notify(&ledger, alice, deposit, &Nat::from(1_000u64))).unwrap();
+        assert_eq!(block_on(scan(&ledger)), 0);
+        assert_eq!(balances::get(alice), Amount::from_sats(1_000));
+    }
+
+    #[test]
+    fn scan_then_notify_credits_once() {
+        let alice = principal(1);
+        let ledger = MockLedger::new(10);
+        ledger.mint(user_account(alice), 10_000);
+        let deposit = ledger.send(user_account(alice), slot_of(alice), 1_000);
+
+        // The owner sent it, so the scanner attributes the unregistered slot
+        assert_eq!(block_on(scan(&ledger)), 1);
+        let result = block_on(notify(&ledger, alice, deposit, &Nat::from(1_000u64)));
+        assert_eq!(result.err(), Some(WalletError::AlreadyCredited { block_index: Nat::from(deposit) }));
+        assert_eq!(balances::get(alice), Amount::from_sats(1_000));
+    }
+}
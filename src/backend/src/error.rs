@@ -0,0 +1,281 @@
+// Typed errors of the backend's Candid API.
+//
+// The v2 endpoints return a WalletError so clients can match on the variant.
+// The original endpoints keep returning text. Errors they could already return
+// keep the wording they had, which clients may parse (see legacy_text); the
+// rest are rendered through Display. A failed inter-canister call is reported
+// through Display everywhere, as the original text was the raw rejection.
+
+use candid::{CandidType, Deserialize, Nat};
+use serde::Serialize;
+use std::fmt;
+
+use crate::ledger::TransferFromError;
+use crate::limits::LimitScope;
+use crate::minter::RetrieveBtcError;
+use crate::pause::Operation;
+use crate::{format_transfer_error, DepositError, TransferError};
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum WalletError {
+    // Balance too low; `required` is set when the backend knows the full amount needed
+    InsufficientFunds { balance: Nat, required: Option<Nat> },
+    InsufficientAllowance { allowance: Nat, required: Option<Nat> },  // Approve the backend for `required` first
+    BadFee { expected_fee: Nat },
+    AmountTooLow { minimum: Nat },
+    WithdrawalLimitExceeded { scope: LimitScope, limit: Nat, remaining: Nat },
+    InvalidAmount(String),
+    InvalidAddress(String),
+    AddressNotAllowed { active_at: Option<u64> },  // Allowlist mode is on; set when the address is still cooling off
+    Duplicate { duplicate_of: Nat },
+    AlreadyCredited { block_index: Nat },
+    LedgerUnavailable(String),   // Ledger call failed or the ledger asked to retry later
+    LedgerRejected(String),      // Ledger refused the request for another reason
+    MinterUnavailable(String),
+    MinterRejected(String),
+    Paused { operation: Operation, reason: String },  // Switched off or circuit breaker tripped
+    Unauthorized,
+    OperationInProgress,         // Another call for the same user has not finished
+    InvalidRequest(String),
+    Internal(String),
+}
+
+// Original string endpoints that word some errors their own way
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LegacyEndpoint {
+    WithdrawFunds,
+    Transfer,
+    VirtualTransfer,
+    WithdrawTestbtc,
+    Faucet,
+}
+
+impl WalletError {
+    // Text `endpoint` returned for this error before WalletError existed
+    pub fn legacy_text(&self, endpoint: LegacyEndpoint) -> String {
+        match (endpoint, self) {
+            (_, WalletError::InvalidAmount(_)) => "Invalid amount format".to_string(),
+            (LegacyEndpoint::Transfer, WalletError::InsufficientFunds { balance, required: Some(required) }) => {
+                format!("Insufficient balance. Required: {}, Available: {}", required, balance)
+            }
+            (_, WalletError::InsufficientFunds { balance, required: Some(required) }) => {
+                format!("Insufficient virtual balance. Available: {}, Requested: {}", balance, required)
+            }
+            (LegacyEndpoint::WithdrawFunds, error) if error.is_rejection() => {
+                format!("Withdrawal transfer failed: {}", error)
+            }
+            (LegacyEndpoint::Faucet, error) if error.is_rejection() => format!("Mint failed: {}", error),
+            (LegacyEndpoint::WithdrawTestbtc, WalletError::InvalidAddress(_)) => {
+                "Invalid TestBTC address format".to_string()
+            }
+            (LegacyEndpoint::WithdrawTestbtc, error) if error.is_rejection() => {
+                format!("Withdrawal failed: {}", error)
+            }
+            (_, error) => error.to_string(),
+        }
+    }
+
+    // Refused by the ledger or the minter, as opposed to by the backend
+    fn is_rejection(&self) -> bool {
+        matches!(
+            self,
+            WalletError::InsufficientFunds { required: None, .. }
+                | WalletError::BadFee { .. }
+                | WalletError::Duplicate { .. }
+                | WalletError::LedgerRejected(_)
+                | WalletError::MinterRejected(_)
+        )
+    }
+}
+
+impl fmt::Display for WalletError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WalletError::InsufficientFunds { balance, required: Some(required) } => {
+                write!(f, "Insufficient balance. Available: {}, Requested: {}", balance, required)
+            }
+            WalletError::InsufficientFunds { balance, required: None } => {
+                write!(f, "Insufficient funds. Balance: {} satoshis", balance)
+            }
+            WalletError::InsufficientAllowance { allowance, required: Some(required) } => {
+                write!(f, "Insufficient allowance. Approved: {}, Required: {}", allowance, required)
+            }
+            WalletError::InsufficientAllowance { allowance, required: None } => {
+                write!(f, "Insufficient allowance. Approved: {}", allowance)
+            }
+            WalletError::BadFee { expected_fee } => {
+                write!(f, "Bad fee. Expected: {} satoshis", expected_fee)
+            }
+            WalletError::AmountTooLow { minimum } => {
+                write!(f, "Amount too low. Minimum: {} satoshis", minimum)
+            }
+            WalletError::WithdrawalLimitExceeded { scope: LimitScope::PerTransaction, limit, .. } => {
+                write!(f, "Amount exceeds the maximum withdrawal of {} satoshis", limit)
+            }
+            WalletError::WithdrawalLimitExceeded { scope: LimitScope::UserDaily, limit, remaining } => write!(
+                f,
+                "Daily withdrawal limit of {} satoshis reached. Remaining: {} satoshis",
+                limit, remaining
+            ),
+            WalletError::WithdrawalLimitExceeded { scope: LimitScope::GlobalDaily, remaining, .. } => write!(
+                f,
+                "Withdrawals are over the backend's daily limit. Remaining: {} satoshis",
+                remaining
+            ),
+            WalletError::AddressNotAllowed { active_at: Some(active_at) } => {
+                write!(f, "Address is on the allowlist but only usable from {}", active_at)
+            }
+            WalletError::AddressNotAllowed { active_at: None } => {
+                write!(f, "Address is not on the withdrawal allowlist")
+            }
+            WalletError::Duplicate { duplicate_of } => {
+                write!(f, "Duplicate transaction. Original block: {}", duplicate_of)
+            }
+            WalletError::AlreadyCredited { block_index } => {
+                write!(f, "Block {} has already been credited", block_index)
+            }
+            WalletError::Paused { reason, .. } => write!(f, "{}", reason),
+            WalletError::Unauthorized => write!(f, "Caller does not hold the role this method requires"),
+            WalletError::OperationInProgress => write!(
+                f,
+                "Another operation for this user is already in progress, try again once it completes"
+            ),
+            WalletError::InvalidAmount(message)
+            | WalletError::InvalidAddress(message)
+            | WalletError::LedgerUnavailable(message)
+            | WalletError::LedgerRejected(message)
+            | WalletError::MinterUnavailable(message)
+            | WalletError::MinterRejected(message)
+            | WalletError::InvalidRequest(message)
+            | WalletError::Internal(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl From<TransferError> for WalletError {
+    fn from(error: TransferError) -> Self {
+        match error {
+            TransferError::InsufficientFunds { balance } => {
+                WalletError::InsufficientFunds { balance, required: None }
+            }
+            TransferError::BadFee { expected_fee } => WalletError::BadFee { expected_fee },
+            TransferError::BadBurn { min_burn_amount } => {
+                WalletError::AmountTooLow { minimum: min_burn_amount }
+            }
+            TransferError::Duplicate { duplicate_of } => WalletError::Duplicate { duplicate_of },
+            TransferError::TemporarilyUnavailable => {
+                WalletError::LedgerUnavailable(format_transfer_error(&error))
+            }
+            TransferError::TooOld
+            | TransferError::CreatedInFuture { .. }
+            | TransferError::GenericError { .. } => {
+                WalletError::LedgerRejected(format_transfer_error(&error))
+            }
+        }
+    }
+}
+
+impl From<TransferFromError> for WalletError {
+    fn from(error: TransferFromError) -> Self {
+        match error {
+            TransferFromError::InsufficientAllowance { allowance } => {
+                WalletError::InsufficientAllowance { allowance, required: None }
+            }
+            TransferFromError::InsufficientFunds { balance } => TransferError::InsufficientFunds { balance }.into(),
+            TransferFromError::BadFee { expected_fee } => TransferError::BadFee { expected_fee }.into(),
+            TransferFromError::BadBurn { min_burn_amount } => TransferError::BadBurn { min_burn_amount }.into(),
+            TransferFromError::TooOld => TransferError::TooOld.into(),
+            TransferFromError::CreatedInFuture { ledger_time } => {
+                TransferError::CreatedInFuture { ledger_time }.into()
+            }
+            TransferFromError::Duplicate { duplicate_of } => TransferError::Duplicate { duplicate_of }.into(),
+            TransferFromError::TemporarilyUnavailable => TransferError::TemporarilyUnavailable.into(),
+            TransferFromError::GenericError { error_code, message } => {
+                TransferError::GenericError { error_code, message }.into()
+            }
+        }
+    }
+}
+
+impl From<RetrieveBtcError> for WalletError {
+    fn from(error: RetrieveBtcError) -> Self {
+        match error {
+            RetrieveBtcError::MalformedAddress(message) => {
+                WalletError::InvalidAddress(format!("Invalid TestBTC address: {}", message))
+            }
+            RetrieveBtcError::AlreadyProcessing => WalletError::OperationInProgress,
+            RetrieveBtcError::AmountTooLow(minimum) => WalletError::AmountTooLow { minimum: Nat::from(minimum) },
+            RetrieveBtcError::InsufficientFunds { balance } => {
+                WalletError::InsufficientFunds { balance: Nat::from(balance), required: None }
+            }
+            RetrieveBtcError::TemporarilyUnavailable(message) => WalletError::MinterUnavailable(message),
+            RetrieveBtcError::GenericError { error_message, error_code } => {
+                WalletError::MinterRejected(format!("Error {}: {}", error_code, error_message))
+            }
+        }
+    }
+}
+
+// deposit_to_custody keeps the error type it had before WalletError existed
+impl From<WalletError> for DepositError {
+    fn from(error: WalletError) -> Self {
+        match error {
+            WalletError::InsufficientAllowance { allowance, required } => DepositError::InsufficientAllowance {
+                required: required.unwrap_or_else(|| allowance.clone()),
+                allowance,
+            },
+            WalletError::InsufficientFunds { balance, required } => DepositError::InsufficientFunds {
+                required: required.unwrap_or_else(|| balance.clone()),
+                balance,
+            },
+            error @ (WalletError::BadFee { .. }
+            | WalletError::Duplicate { .. }
+            | WalletError::LedgerUnavailable(_)
+            | WalletError::LedgerRejected(_)) => DepositError::LedgerError(error.to_string()),
+            error => DepositError::Other(error.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn insufficient(balance: u64, required: u64) -> WalletError {
+        WalletError::InsufficientFunds { balance: Nat::from(balance), required: Some(Nat::from(required)) }
+    }
+
+    #[test]
+    fn string_endpoints_keep_their_wording() {
+        let invalid = WalletError::InvalidAmount("Amount must be greater than zero".to_string());
+        assert_eq!(invalid.legacy_text(LegacyEndpoint::VirtualTransfer), "Invalid amount format");
+        assert_eq!(
+            insufficient(5, 7).legacy_text(LegacyEndpoint::WithdrawFunds),
+            "Insufficient virtual balance. Available: 5, Requested: 7"
+        );
+        assert_eq!(
+            insufficient(5, 7).legacy_text(LegacyEndpoint::Transfer),
+            "Insufficient balance. Required: 7, Available: 5"
+        );
+
+        let bad_fee = WalletError::from(TransferError::BadFee { expected_fee: Nat::from(10u64) });
+        assert_eq!(
+            bad_fee.legacy_text(LegacyEndpoint::WithdrawFunds),
+            "Withdrawal transfer failed: Bad fee. Expected: 10 satoshis"
+        );
+        assert_eq!(bad_fee.legacy_text(LegacyEndpoint::Transfer), "Bad fee. Expected: 10 satoshis");
+        assert!(bad_fee.legacy_text(LegacyEndpoint::Faucet).starts_with("Mint failed: "));
+
+        let address = WalletError::InvalidAddress("Invalid TestBTC address: bad checksum".to_string());
+        assert_eq!(address.legacy_text(LegacyEndpoint::WithdrawTestbtc), "Invalid TestBTC address format");
+        let rejected = WalletError::from(RetrieveBtcError::GenericError {
+            error_message: "no UTXOs".to_string(),
+            error_code: 3,
+        });
+        assert_eq!(rejected.legacy_text(LegacyEndpoint::WithdrawTestbtc), "Withdrawal failed: Error 3: no UTXOs");
+
+        // Errors the original endpoints never returned read as in v2
+        let paused = WalletError::Paused { operation: Operation::WithdrawToLedger, reason: "All operations are paused".to_string() };
+        assert_eq!(paused.legacy_text(LegacyEndpoint::WithdrawFunds), paused.to_string());
+    }
+}
@@ -0,0 +1,60 @@
+// Ledger fees paid by the custody pool.
+//
+// Every transfer out of one of the backend's ledger accounts - a withdrawal to a
+// user or a sweep into the omnibus account - costs the pool the ledger fee. A
+// withdrawal's fee is charged to the user it is made for and a sweep's to the
+// owner of the swept subaccount, so the pool stays whole. The running total
+// kept here shows how much has been paid.
+
+use candid::{CandidType, Deserialize};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableCell, Storable};
+use serde::Serialize;
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+use crate::memory::{self, Memory};
+use crate::runtime;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct PoolFees {
+    pub total_paid: u64,        // Satoshis paid to the ledger in fees
+    pub transfers: u64,         // Transfers the fees were paid on
+    pub last_paid_at: Option<u64>,
+}
+
+impl Storable for PoolFees {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let bytes = candid::encode_one(self).expect("Failed to encode PoolFees");
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode PoolFees")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    static POOL_FEES: RefCell<StableCell<PoolFees, Memory>> = RefCell::new(
+        StableCell::init(memory::get(memory::POOL_FEES_MEMORY_ID), PoolFees::default())
+            .expect("Failed to init pool fee totals")
+    );
+}
+
+pub fn totals() -> PoolFees {
+    POOL_FEES.with(|fees| fees.borrow().get().clone())
+}
+
+// Add the fee of a completed transfer out of a backend account
+pub fn record_paid(fee: u64) {
+    POOL_FEES.with(|fees| {
+        let mut fees = fees.borrow_mut();
+        let mut totals = fees.get().clone();
+        totals.total_paid = totals.total_paid.saturating_add(fee);
+        totals.transfers += 1;
+        totals.last_paid_at = Some(runtime::time());
+        fees.set(totals).expect("Failed to write pool fee totals");
+    });
+}
@@ -0,0 +1,92 @@
+// Per-principal in-flight lock.
+//
+// An update call that awaits another canister can interleave with other calls
+// from the same user. Endpoints that change a user's balances across such an
+// await hold a PrincipalGuard for the whole call, so a second call from that
+// user is rejected instead of racing the first.
+
+use candid::Principal;
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeSet;
+use std::thread::LocalKey;
+
+use crate::error::WalletError;
+
+thread_local! {
+    static IN_FLIGHT: RefCell<BTreeSet<Principal>> = const { RefCell::new(BTreeSet::new()) };
+}
+
+pub struct PrincipalGuard {
+    principal: Principal,
+}
+
+impl PrincipalGuard {
+    pub fn new(principal: Principal) -> Result<Self, WalletError> {
+        IN_FLIGHT.with(|in_flight| {
+            if !in_flight.borrow_mut().insert(principal) {
+                return Err(WalletError::OperationInProgress);
+            }
+            Ok(PrincipalGuard { principal })
+        })
+    }
+}
+
+// Released when the call finishes, including when it traps after an await:
+// the CDK drops the pending future during cleanup
+impl Drop for PrincipalGuard {
+    fn drop(&mut self) {
+        IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().remove(&self.principal));
+    }
+}
+
+// Single-flight lock for a background task. Timer ticks and manual triggers
+// that find the task already running skip instead of overlapping it.
+pub struct TaskGuard {
+    running: &'static LocalKey<Cell<bool>>,
+}
+
+impl TaskGuard {
+    pub fn new(running: &'static LocalKey<Cell<bool>>) -> Option<Self> {
+        if running.with(|running| running.replace(true)) {
+            return None;
+        }
+        Some(TaskGuard { running })
+    }
+}
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        self.running.with(|running| running.set(false));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    thread_local! {
+        static RUNNING: Cell<bool> = const { Cell::new(false) };
+    }
+
+    #[test]
+    fn second_guard_for_same_principal_waits_for_the_first() {
+        let (alice, bob) = (Principal::from_slice(&[1; 29]), Principal::from_slice(&[2; 29]));
+
+        let first = PrincipalGuard::new(alice).unwrap();
+        assert!(matches!(PrincipalGuard::new(alice), Err(WalletError::OperationInProgress)));
+        // Other users are not held up
+        let other = PrincipalGuard::new(bob).unwrap();
+
+        drop(first);
+        assert!(PrincipalGuard::new(alice).is_ok());
+        drop(other);
+    }
+
+    #[test]
+    fn task_guard_skips_overlapping_runs() {
+        let first = TaskGuard::new(&RUNNING).unwrap();
+        assert!(TaskGuard::new(&RUNNING).is_none());
+        drop(first);
+        assert!(TaskGuard::new(&RUNNING).is_some());
+    }
+}
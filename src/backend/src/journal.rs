@@ -0,0 +1,796 @@
+// Unified transaction journal.
+//
+// Every operation the backend performs - ledger transfers, virtual balance
+// transfers and Bitcoin network withdrawals - is recorded as one JournalEntry in
+// a single stable log keyed by id. The legacy Transaction and
+// CustodialTransaction API types are projections of these entries.
+//
+// A secondary index keyed by (principal, id) lists the entries each user is a
+// party to, so per-user history never scans the whole log.
+
+use candid::{CandidType, Deserialize, Nat, Principal};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, StableVec, Storable};
+use serde::Serialize;
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+use crate::custody;
+use crate::memory::{self, Memory};
+use crate::runtime;
+use crate::{
+    generate_subaccount_for_user, Account, CustodialTransaction, StorablePrincipal, Transaction,
+    TransactionStatus, TransactionType,
+};
+
+// Where the value moved
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum TransactionChannel {
+    Ledger,  // ckTestBTC ledger transfer
+    Virtual, // Virtual balance transfer inside the backend
+    Bitcoin, // TestBTC network via the minter
+}
+
+// One side of a journal entry
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum Counterparty {
+    Principal(Principal),  // Wallet user holding a virtual balance
+    Account(Account),      // ICRC-1 ledger account
+    BtcAddress(String),    // Bitcoin testnet address
+}
+
+impl Counterparty {
+    pub fn account(owner: Principal, subaccount: Option<Vec<u8>>) -> Self {
+        Counterparty::Account(Account { owner, subaccount })
+    }
+
+    // Wallet user behind this counterparty, if any. A custodial subaccount of the
+    // backend is attributed to the user it belongs to; the omnibus account to nobody.
+    pub fn user(&self) -> Option<Principal> {
+        match self {
+            Counterparty::Principal(p) => Some(*p),
+            Counterparty::Account(account) if account.owner != runtime::id() => Some(account.owner),
+            Counterparty::Account(account) => custody::owner_of(account.subaccount.as_deref()?, None),
+            _ => None,
+        }
+    }
+
+    // Whether this side of an entry is `filter`. A principal matches itself and
+    // any ledger account it owns; a default subaccount matches `None`.
+    fn matches(&self, filter: &Counterparty) -> bool {
+        fn normalized(subaccount: &Option<Vec<u8>>) -> Option<&[u8]> {
+            subaccount
+                .as_deref()
+                .filter(|subaccount| subaccount.iter().any(|byte| *byte != 0))
+        }
+
+        match (self, filter) {
+            (Counterparty::Principal(p), Counterparty::Principal(f)) => p == f,
+            (Counterparty::Account(account), Counterparty::Principal(f)) => account.owner == *f,
+            (Counterparty::Account(account), Counterparty::Account(f)) => {
+                account.owner == f.owner && normalized(&account.subaccount) == normalized(&f.subaccount)
+            }
+            (Counterparty::BtcAddress(address), Counterparty::BtcAddress(f)) => address == f,
+            _ => false,
+        }
+    }
+
+    fn to_legacy_text(&self) -> String {
+        match self {
+            Counterparty::Principal(p) => p.to_text(),
+            Counterparty::Account(account) if account.owner == runtime::id() => {
+                "Custodial Wallet".to_string()
+            }
+            Counterparty::Account(account) => account.owner.to_text(),
+            Counterparty::BtcAddress(address) => address.clone(),
+        }
+    }
+}
+
+// Caller-supplied part of a journal entry
+#[derive(Clone, Debug)]
+pub struct JournalRecord {
+    pub tx_type: TransactionType,
+    pub channel: TransactionChannel,
+    pub from: Option<Counterparty>,     // None for mints
+    pub to: Option<Counterparty>,
+    pub amount: Nat,                    // Amount moved on the channel, in satoshis
+    pub virtual_amount: Option<u64>,    // Virtual balance change, if any
+    pub fee: Option<Nat>,               // Ledger fee the backend paid on top of `amount`
+    pub status: TransactionStatus,
+    pub block_index: Option<Nat>,       // Ledger or minter block reference
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct JournalEntry {
+    pub id: u64,
+    pub tx_type: TransactionType,
+    pub channel: TransactionChannel,
+    pub from: Option<Counterparty>,
+    pub to: Option<Counterparty>,
+    pub amount: Nat,
+    pub virtual_amount: Option<u64>,
+    pub fee: Option<Nat>,  // None on entries recorded before fees were tracked
+    pub status: TransactionStatus,
+    pub timestamp: u64,
+    pub block_index: Option<Nat>,
+}
+
+impl JournalEntry {
+    fn new(id: u64, timestamp: u64, record: JournalRecord) -> Self {
+        JournalEntry {
+            id,
+            tx_type: record.tx_type,
+            channel: record.channel,
+            from: record.from,
+            to: record.to,
+            amount: record.amount,
+            virtual_amount: record.virtual_amount,
+            fee: record.fee,
+            status: record.status,
+            timestamp,
+            block_index: record.block_index,
+        }
+    }
+
+    // Wallet users this entry belongs to (sender and/or recipient)
+    pub fn users(&self) -> Vec<Principal> {
+        let mut users: Vec<Principal> = [&self.from, &self.to]
+            .into_iter()
+            .flatten()
+            .filter_map(Counterparty::user)
+            .collect();
+        users.dedup();
+        users
+    }
+
+    pub fn involves(&self, user: Principal) -> bool {
+        self.users().contains(&user)
+    }
+
+    // Entries that moved virtual balances make up the custodial history
+    pub fn is_custodial(&self) -> bool {
+        self.virtual_amount.is_some()
+    }
+
+    // Legacy view of the entry as seen by `viewer`: a transfer the viewer
+    // received is reported as Receive
+    pub fn to_transaction_for(&self, viewer: Principal) -> Transaction {
+        let mut transaction = self.to_transaction();
+        let sent_by_viewer = self.from.as_ref().and_then(Counterparty::user) == Some(viewer);
+        if matches!(self.tx_type, TransactionType::Send) && !sent_by_viewer {
+            transaction.tx_type = TransactionType::Receive;
+        }
+        transaction
+    }
+
+    pub fn to_transaction(&self) -> Transaction {
+        let from = match &self.from {
+            Some(counterparty) => counterparty.to_legacy_text(),
+            None => "faucet".to_string(),
+        };
+        let to = self
+            .to
+            .as_ref()
+            .map(Counterparty::to_legacy_text)
+            .unwrap_or_default();
+
+        Transaction {
+            id: self.id,
+            tx_type: self.tx_type.clone(),
+            token: "ckTestBTC".to_string(),
+            amount: self.amount.clone(),
+            from,
+            to,
+            status: self.status.clone(),
+            timestamp: self.timestamp,
+            block_index: self.block_index.clone(),
+        }
+    }
+
+    pub fn to_custodial_transaction(&self) -> CustodialTransaction {
+        let on_chain_amount = match self.channel {
+            TransactionChannel::Virtual => None,
+            _ => Some(self.amount.clone()),
+        };
+
+        CustodialTransaction {
+            id: self.id,
+            tx_type: self.tx_type.clone(),
+            from_user: self.from.as_ref().and_then(Counterparty::user),
+            to_user: self.to.as_ref().and_then(Counterparty::user),
+            virtual_amount: self.virtual_amount,
+            on_chain_amount,
+            block_index: self.block_index.clone(),
+            status: self.status.clone(),
+            timestamp: self.timestamp,
+        }
+    }
+}
+
+impl Storable for JournalEntry {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let bytes = candid::encode_one(self).expect("Failed to encode JournalEntry");
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode JournalEntry")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    // Journal entries by id. New entries take the id after the highest one in
+    // use; entries migrated from the legacy logs keep their legacy id.
+    static JOURNAL: RefCell<StableBTreeMap<u64, JournalEntry, Memory>> = RefCell::new(
+        StableBTreeMap::init(memory::get(memory::JOURNAL_MEMORY_ID))
+    );
+
+    // (user, journal id) for every user that is a party to an entry
+    static USER_TRANSACTIONS: RefCell<StableBTreeMap<(StorablePrincipal, u64), (), Memory>> = RefCell::new(
+        StableBTreeMap::init(memory::get(memory::USER_TRANSACTIONS_MEMORY_ID))
+    );
+}
+
+fn index_entry(entry: &JournalEntry) {
+    USER_TRANSACTIONS.with(|index| {
+        let mut index = index.borrow_mut();
+        for user in entry.users() {
+            index.insert((StorablePrincipal::from(user), entry.id), ());
+        }
+    });
+}
+
+fn next_id() -> u64 {
+    JOURNAL.with(|journal| {
+        journal
+            .borrow()
+            .last_key_value()
+            .map(|(id, _)| id + 1)
+            .unwrap_or(1)
+    })
+}
+
+// Append a record to the journal and return its id
+pub fn record(record: JournalRecord) -> u64 {
+    let id = next_id();
+    let entry = JournalEntry::new(id, runtime::time(), record);
+    index_entry(&entry);
+    JOURNAL.with(|journal| journal.borrow_mut().insert(id, entry));
+    id
+}
+
+pub fn get(id: u64) -> Option<JournalEntry> {
+    JOURNAL.with(|journal| journal.borrow().get(&id))
+}
+
+pub fn len() -> u64 {
+    JOURNAL.with(|journal| journal.borrow().len())
+}
+
+// Most recent entries `user` is a party to and that match `filter`, newest first.
+// Like a history page, at most MAX_SCANNED_PER_PAGE of the user's entries are examined.
+pub fn latest_for_user(
+    user: Principal,
+    limit: usize,
+    filter: impl Fn(&JournalEntry) -> bool,
+) -> Vec<JournalEntry> {
+    let user = StorablePrincipal::from(user);
+    USER_TRANSACTIONS.with(|index| {
+        index
+            .borrow()
+            .range((user.clone(), 0)..=(user, u64::MAX))
+            .rev()
+            .take(MAX_SCANNED_PER_PAGE)
+            .filter_map(|((_, id), ())| get(id))
+            .filter(|entry| filter(entry))
+            .take(limit)
+            .collect()
+    })
+}
+
+// Every user that is a party to at least one entry
+pub fn users() -> Vec<Principal> {
+    USER_TRANSACTIONS.with(|index| {
+        let mut users: Vec<Principal> = Vec::new();
+        for ((user, _), ()) in index.borrow().iter() {
+            if users.last() != Some(&user.0) {
+                users.push(user.0);
+            }
+        }
+        users
+    })
+}
+
+// Index every journal entry by user (layout v2 -> v3)
+pub fn build_user_index() {
+    let mut indexed = 0u64;
+    JOURNAL.with(|journal| {
+        for (_, entry) in journal.borrow().iter() {
+            index_entry(&entry);
+            indexed += 1;
+        }
+    });
+    ic_cdk::println!("[UPGRADE] Indexed {} journal entries by user", indexed);
+}
+
+// ============================================================
+// PAGINATED HISTORY
+// ============================================================
+
+const DEFAULT_PAGE_SIZE: u32 = 50;
+const MAX_PAGE_SIZE: u32 = 200;
+
+// Upper bound on index entries examined per page (and per latest_for_user call)
+// so that very selective filters stay within the query instruction limit
+const MAX_SCANNED_PER_PAGE: usize = 10_000;
+
+// All set fields must match. Timestamps are nanoseconds since the epoch, inclusive.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct HistoryFilter {
+    pub tx_types: Option<Vec<TransactionType>>,
+    pub statuses: Option<Vec<TransactionStatus>>,
+    pub from_timestamp: Option<u64>,
+    pub to_timestamp: Option<u64>,
+    pub counterparty: Option<Counterparty>,
+}
+
+impl HistoryFilter {
+    fn matches(&self, entry: &JournalEntry) -> bool {
+        if let Some(tx_types) = &self.tx_types {
+            if !tx_types.contains(&entry.tx_type) {
+                return false;
+            }
+        }
+        if let Some(statuses) = &self.statuses {
+            if !statuses.contains(&entry.status) {
+                return false;
+            }
+        }
+        if self.from_timestamp.is_some_and(|from| entry.timestamp < from) {
+            return false;
+        }
+        if self.to_timestamp.is_some_and(|to| entry.timestamp > to) {
+            return false;
+        }
+        if let Some(counterparty) = &self.counterparty {
+            let matches_side = |side: &Option<Counterparty>| {
+                side.as_ref().is_some_and(|side| side.matches(counterparty))
+            };
+            if !matches_side(&entry.from) && !matches_side(&entry.to) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct HistoryRequest {
+    pub cursor: Option<u64>,        // Only entries with id < cursor; None starts at the newest
+    pub page_size: Option<u32>,     // Defaults to 50, capped at 200
+    pub filter: Option<HistoryFilter>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct HistoryPage {
+    pub entries: Vec<JournalEntry>, // Newest first
+    pub next_cursor: Option<u64>,   // Cursor for the next page, None when exhausted
+}
+
+// One page of `user`'s history, walking the user index from newest to oldest
+pub fn history_page(user: Principal, request: HistoryRequest) -> HistoryPage {
+    let page_size = request
+        .page_size
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE) as usize;
+    let filter = request.filter.unwrap_or_default();
+    let upper = request.cursor.unwrap_or(u64::MAX);
+    if upper == 0 {
+        return HistoryPage { entries: Vec::new(), next_cursor: None };
+    }
+
+    let user = StorablePrincipal::from(user);
+    USER_TRANSACTIONS.with(|index| {
+        let index = index.borrow();
+        let mut ids = index
+            .range((user.clone(), 0)..(user.clone(), upper))
+            .rev()
+            .map(|((_, id), ())| id);
+
+        let mut entries = Vec::new();
+        let mut last_scanned = None;
+        for _ in 0..MAX_SCANNED_PER_PAGE {
+            let Some(id) = ids.next() else {
+                // Reached the user's oldest entry
+                return HistoryPage { entries, next_cursor: None };
+            };
+            last_scanned = Some(id);
+
+            let Some(entry) = get(id) else { continue };
+            if filter.matches(&entry) {
+                entries.push(entry);
+                if entries.len() == page_size {
+                    break;
+                }
+            }
+        }
+
+        // Page is full or the scan budget ran out; resume below the last id examined
+        // unless nothing older is left
+        let next_cursor = last_scanned.filter(|_| ids.next().is_some());
+        HistoryPage { entries, next_cursor }
+    })
+}
+
+// ============================================================
+// MIGRATION FROM THE LEGACY LOGS (layout v1 -> v2)
+// ============================================================
+
+fn legacy_text_to_counterparty(text: &str, owner: Option<Principal>) -> Option<Counterparty> {
+    match text {
+        "faucet" | "" => None,
+        "Custodial Wallet" => owner.map(|owner| {
+            Counterparty::account(runtime::id(), Some(generate_subaccount_for_user(owner)))
+        }),
+        _ => match Principal::from_text(text) {
+            Ok(principal) => Some(Counterparty::account(principal, None)),
+            Err(_) => Some(Counterparty::BtcAddress(text.to_string())),
+        },
+    }
+}
+
+fn migrate_transaction(tx: Transaction) -> (u64, JournalRecord) {
+    let from_principal = Principal::from_text(&tx.from).ok();
+    let channel = match tx.tx_type {
+        TransactionType::Withdraw => TransactionChannel::Bitcoin,
+        _ => TransactionChannel::Ledger,
+    };
+    let from = match (&tx.tx_type, from_principal) {
+        // BTC withdrawals were initiated on behalf of the user, not from a ledger account
+        (TransactionType::Withdraw, Some(principal)) => Some(Counterparty::Principal(principal)),
+        _ => legacy_text_to_counterparty(&tx.from, from_principal),
+    };
+
+    let record = JournalRecord {
+        to: legacy_text_to_counterparty(&tx.to, from_principal),
+        tx_type: tx.tx_type,
+        channel,
+        from,
+        amount: tx.amount,
+        virtual_amount: None,
+        fee: None,
+        status: tx.status,
+        block_index: tx.block_index,
+    };
+    (tx.timestamp, record)
+}
+
+fn migrate_custodial_transaction(tx: CustodialTransaction) -> (u64, JournalRecord) {
+    let backend_account = || Counterparty::account(runtime::id(), None);
+    let user_account = |user: Principal| Counterparty::account(user, None);
+
+    let (channel, from, to) = match tx.tx_type {
+        TransactionType::Deposit => (
+            TransactionChannel::Ledger,
+            tx.from_user.map(user_account),
+            Some(backend_account()),
+        ),
+        TransactionType::Withdraw => (
+            TransactionChannel::Ledger,
+            Some(backend_account()),
+            tx.to_user.map(user_account),
+        ),
+        _ => (
+            TransactionChannel::Virtual,
+            tx.from_user.map(Counterparty::Principal),
+            tx.to_user.map(Counterparty::Principal),
+        ),
+    };
+
+    let amount = tx
+        .on_chain_amount
+        .unwrap_or_else(|| Nat::from(tx.virtual_amount.unwrap_or(0)));
+
+    let record = JournalRecord {
+        tx_type: tx.tx_type,
+        channel,
+        from,
+        to,
+        amount,
+        virtual_amount: tx.virtual_amount,
+        fee: None,
+        status: tx.status,
+        block_index: tx.block_index,
+    };
+    (tx.timestamp, record)
+}
+
+// Legacy ids that are already taken move up by a multiple of this, so their
+// low 32 bits still show the id the legacy log gave them
+const LEGACY_ID_NAMESPACE: u64 = 1 << 32;
+
+// Merge the legacy TRANSACTIONS and STABLE_TRANSACTIONS logs (plus any custodial
+// transactions salvaged from the unmanaged layout) into the journal. Entries keep
+// the id their log gave them, so ids handed out before the upgrade still resolve
+// through get_transaction. Both logs numbered their ids from 1 and the custodial
+// counter restarted after every upgrade, so an id that is already taken goes to
+// the first namespace where it is free (id + k * 2^32). The legacy memories are
+// left untouched. Returns the number of entries migrated.
+pub fn migrate_legacy_logs(unmanaged: Vec<CustodialTransaction>) -> usize {
+    let transactions: StableVec<Transaction, Memory> =
+        StableVec::init(memory::get(memory::TRANSACTIONS_MEMORY_ID))
+            .expect("Failed to open legacy transactions");
+    let custodial_transactions: StableVec<CustodialTransaction, Memory> =
+        StableVec::init(memory::get(memory::STABLE_TRANSACTIONS_MEMORY_ID))
+            .expect("Failed to open legacy custodial transactions");
+
+    let ledger_log = transactions
+        .iter()
+        // Receive entries only mirrored the matching Send of a transfer
+        .filter(|tx| !matches!(tx.tx_type, TransactionType::Receive))
+        .map(|tx| (tx.id, migrate_transaction(tx)));
+    let custodial_log = custodial_transactions
+        .iter()
+        .chain(unmanaged)
+        .map(|tx| (tx.id, migrate_custodial_transaction(tx)));
+
+    let mut migrated = 0;
+    JOURNAL.with(|journal| {
+        let mut journal = journal.borrow_mut();
+        for (legacy_id, (timestamp, record)) in ledger_log.chain(custodial_log) {
+            let id = (0..)
+                .map_while(|namespace: u64| LEGACY_ID_NAMESPACE.checked_mul(namespace)?.checked_add(legacy_id))
+                .find(|id| !journal.contains_key(id))
+                .expect("No free journal id for a legacy transaction");
+            journal.insert(id, JournalEntry::new(id, timestamp, record));
+            migrated += 1;
+        }
+    });
+    migrated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::principal;
+
+    fn virtual_send(from: Principal, to: Principal, sats: u64) -> u64 {
+        record(JournalRecord {
+            tx_type: TransactionType::Send,
+            channel: TransactionChannel::Virtual,
+            from: Some(Counterparty::Principal(from)),
+            to: Some(Counterparty::Principal(to)),
+            amount: Nat::from(sats),
+            virtual_amount: Some(sats),
+            fee: None,
+            status: TransactionStatus::Confirmed,
+            block_index: None,
+        })
+    }
+
+    fn page(user: Principal, cursor: Option<u64>, page_size: Option<u32>, filter: Option<HistoryFilter>) -> HistoryPage {
+        history_page(user, HistoryRequest { cursor, page_size, filter })
+    }
+
+    fn ids(entries: &[JournalEntry]) -> Vec<u64> {
+        entries.iter().map(|entry| entry.id).collect()
+    }
+
+    fn ledger_send(from: Principal, to: Principal, sats: u64) -> u64 {
+        record(JournalRecord {
+            tx_type: TransactionType::Send,
+            channel: TransactionChannel::Ledger,
+            from: Some(Counterparty::account(from, None)),
+            to: Some(Counterparty::account(to, None)),
+            amount: Nat::from(sats),
+            virtual_amount: None,
+            fee: None,
+            status: TransactionStatus::Confirmed,
+            block_index: Some(Nat::from(sats)),
+        })
+    }
+
+    #[test]
+    fn virtual_transfer_projects_to_both_legacy_types() {
+        let (alice, bob) = (principal(1), principal(2));
+        let entry = get(virtual_send(alice, bob, 250)).unwrap();
+
+        let sent = entry.to_transaction_for(alice);
+        assert_eq!(sent.tx_type, TransactionType::Send);
+        assert_eq!((sent.from, sent.to), (alice.to_text(), bob.to_text()));
+        assert_eq!(sent.amount, Nat::from(250u64));
+        // The recipient sees the same entry as Receive
+        assert_eq!(entry.to_transaction_for(bob).tx_type, TransactionType::Receive);
+
+        let custodial = entry.to_custodial_transaction();
+        assert_eq!((custodial.from_user, custodial.to_user), (Some(alice), Some(bob)));
+        assert_eq!(custodial.virtual_amount, Some(250));
+        assert_eq!(custodial.on_chain_amount, None);
+        assert!(entry.is_custodial());
+    }
+
+    #[test]
+    fn custodial_accounts_project_to_their_owner() {
+        let alice = principal(1);
+        let slot = generate_subaccount_for_user(alice);
+        custody::register(alice);
+
+        // Deposit into alice's custodial subaccount, then a payout from the omnibus account
+        let deposit = get(record(JournalRecord {
+            tx_type: TransactionType::Deposit,
+            channel: TransactionChannel::Ledger,
+            from: Some(Counterparty::account(alice, None)),
+            to: Some(Counterparty::account(runtime::id(), Some(slot))),
+            amount: Nat::from(1_000u64),
+            virtual_amount: Some(1_000),
+            fee: None,
+            status: TransactionStatus::Confirmed,
+            block_index: Some(Nat::from(7u64)),
+        }))
+        .unwrap();
+        let withdrawal = get(record(JournalRecord {
+            tx_type: TransactionType::Withdraw,
+            channel: TransactionChannel::Ledger,
+            from: Some(Counterparty::account(runtime::id(), None)),
+            to: Some(Counterparty::account(alice, None)),
+            amount: Nat::from(400u64),
+            virtual_amount: Some(410),
+            fee: Some(Nat::from(10u64)),
+            status: TransactionStatus::Confirmed,
+            block_index: Some(Nat::from(8u64)),
+        }))
+        .unwrap();
+
+        assert_eq!(deposit.users(), vec![alice]);
+        let custodial = deposit.to_custodial_transaction();
+        assert_eq!((custodial.from_user, custodial.to_user), (Some(alice), Some(alice)));
+        assert_eq!(custodial.on_chain_amount, Some(Nat::from(1_000u64)));
+        assert_eq!(deposit.to_transaction().to, "Custodial Wallet");
+
+        // The omnibus account belongs to nobody
+        let custodial = withdrawal.to_custodial_transaction();
+        assert_eq!((custodial.from_user, custodial.to_user), (None, Some(alice)));
+        assert_eq!(custodial.virtual_amount, Some(410));
+        let transaction = withdrawal.to_transaction_for(alice);
+        assert_eq!((transaction.from, transaction.to), ("Custodial Wallet".to_string(), alice.to_text()));
+        assert_eq!(transaction.block_index, Some(Nat::from(8u64)));
+    }
+
+    #[test]
+    fn ledger_transfers_are_not_custodial() {
+        let (alice, bob) = (principal(1), principal(2));
+        let entry = get(ledger_send(alice, bob, 90)).unwrap();
+        assert!(!entry.is_custodial());
+        assert_eq!(entry.users(), vec![alice, bob]);
+        assert_eq!(entry.to_custodial_transaction().on_chain_amount, Some(Nat::from(90u64)));
+    }
+
+    #[test]
+    fn latest_for_user_lists_only_the_users_entries() {
+        let (alice, bob, carol) = (principal(1), principal(2), principal(3));
+        let first = virtual_send(alice, bob, 1);
+        let unrelated = virtual_send(bob, carol, 2);
+        let second = ledger_send(carol, alice, 3);
+        let third = virtual_send(alice, carol, 4);
+
+        let history = latest_for_user(alice, 100, |_| true);
+        assert_eq!(ids(&history), vec![third, second, first]);
+        assert!(history.iter().all(|entry| entry.involves(alice)));
+        assert!(!get(unrelated).unwrap().involves(alice));
+
+        let latest = latest_for_user(alice, 2, JournalEntry::is_custodial);
+        assert_eq!(ids(&latest), vec![third, first]);
+        assert!(latest_for_user(principal(4), 100, |_| true).is_empty());
+    }
+
+    #[test]
+    fn latest_for_user_stops_at_the_scan_budget() {
+        let (alice, bob) = (principal(1), principal(2));
+        virtual_send(alice, bob, 1);
+        for _ in 0..MAX_SCANNED_PER_PAGE {
+            ledger_send(alice, bob, 1);
+        }
+
+        // The only custodial entry lies beyond the budget
+        assert!(latest_for_user(alice, 100, JournalEntry::is_custodial).is_empty());
+        assert_eq!(latest_for_user(alice, 1, |_| true).len(), 1);
+
+        let newest = virtual_send(bob, alice, 1);
+        let found = latest_for_user(alice, 100, JournalEntry::is_custodial);
+        assert_eq!(ids(&found), vec![newest]);
+    }
+
+    #[test]
+    fn page_size_defaults_to_50_and_is_clamped() {
+        let (alice, bob) = (principal(1), principal(2));
+        let sent: Vec<u64> = (0..250).map(|sats| virtual_send(alice, bob, sats)).collect();
+
+        let first = page(alice, None, None, None);
+        assert_eq!(ids(&first.entries), sent[200..].iter().rev().copied().collect::<Vec<_>>());
+        assert_eq!(first.next_cursor, Some(sent[200]));
+
+        assert_eq!(page(alice, None, Some(0), None).entries.len(), 1);
+        let largest = page(alice, None, Some(1_000), None);
+        assert_eq!(largest.entries.len(), MAX_PAGE_SIZE as usize);
+        assert_eq!(largest.next_cursor, Some(sent[50]));
+    }
+
+    #[test]
+    fn cursor_walks_back_to_the_oldest_entry() {
+        let (alice, bob) = (principal(1), principal(2));
+        let sent: Vec<u64> = (0..5).map(|sats| virtual_send(alice, bob, sats)).collect();
+        virtual_send(bob, principal(3), 9);
+
+        let first = page(alice, None, Some(2), None);
+        assert_eq!(ids(&first.entries), vec![sent[4], sent[3]]);
+        let second = page(alice, first.next_cursor, Some(2), None);
+        assert_eq!(ids(&second.entries), vec![sent[2], sent[1]]);
+        let last = page(alice, second.next_cursor, Some(2), None);
+        assert_eq!(ids(&last.entries), vec![sent[0]]);
+        assert_eq!(last.next_cursor, None);
+
+        // A page that ends on the oldest entry has no next cursor either
+        let exact = page(alice, Some(sent[2]), Some(2), None);
+        assert_eq!(ids(&exact.entries), vec![sent[1], sent[0]]);
+        assert_eq!(exact.next_cursor, None);
+        assert!(page(alice, Some(0), None, None).entries.is_empty());
+    }
+
+    #[test]
+    fn filters_match_every_set_field() {
+        let (alice, bob, carol) = (principal(1), principal(2), principal(3));
+        let old = ledger_send(alice, bob, 1);
+        runtime::advance(1_000);
+        let since = runtime::time();
+        let to_bob = virtual_send(alice, bob, 2);
+        let to_carol = ledger_send(alice, carol, 3);
+
+        let recent = HistoryFilter { from_timestamp: Some(since), ..Default::default() };
+        let found = page(alice, None, None, Some(recent));
+        assert_eq!(ids(&found.entries), vec![to_carol, to_bob]);
+        assert_eq!(found.next_cursor, None);
+
+        // A principal matches its ledger accounts too
+        let with_bob = HistoryFilter { counterparty: Some(Counterparty::Principal(bob)), ..Default::default() };
+        assert_eq!(ids(&page(alice, None, None, Some(with_bob)).entries), vec![to_bob, old]);
+
+        let recent_ledger = HistoryFilter {
+            tx_types: Some(vec![TransactionType::Send]),
+            statuses: Some(vec![TransactionStatus::Confirmed]),
+            from_timestamp: Some(since),
+            to_timestamp: Some(since),
+            counterparty: Some(Counterparty::account(carol, None)),
+        };
+        assert_eq!(ids(&page(alice, None, None, Some(recent_ledger)).entries), vec![to_carol]);
+    }
+
+    #[test]
+    fn page_stops_at_the_scan_budget() {
+        let (alice, bob) = (principal(1), principal(2));
+        let failed = record(JournalRecord {
+            tx_type: TransactionType::Send,
+            channel: TransactionChannel::Ledger,
+            from: Some(Counterparty::account(alice, None)),
+            to: Some(Counterparty::account(bob, None)),
+            amount: Nat::from(5u64),
+            virtual_amount: None,
+            fee: None,
+            status: TransactionStatus::Failed,
+            block_index: None,
+        });
+        let confirmed: Vec<u64> = (0..MAX_SCANNED_PER_PAGE).map(|_| ledger_send(alice, bob, 1)).collect();
+
+        let failures = || Some(HistoryFilter { statuses: Some(vec![TransactionStatus::Failed]), ..Default::default() });
+        // Nothing matched within the budget; the cursor resumes below the last id examined
+        let first = page(alice, None, None, failures());
+        assert!(first.entries.is_empty());
+        assert_eq!(first.next_cursor, Some(confirmed[0]));
+
+        let second = page(alice, first.next_cursor, None, failures());
+        assert_eq!(ids(&second.entries), vec![failed]);
+        assert_eq!(second.next_cursor, None);
+    }
+}
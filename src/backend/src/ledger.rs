@@ -0,0 +1,319 @@
+// Calls to the ckTestBTC ledger shared by the backend's bookkeeping tasks.
+//
+// Payouts, sweeps, the deposit scanner and reserve checks reach the ledger
+// through the Ledger trait, so their accounting can be unit-tested against a
+// ledger held in memory. IcLedger is the ledger canister from the configuration.
+
+use candid::{CandidType, Deserialize, Int, Nat};
+use ic_cdk::api::call::CallResult;
+use serde::Serialize;
+
+use crate::{config, runtime, Account, TransferArgs, TransferError};
+
+pub trait Ledger {
+    // ICRC-1 balance of `account`
+    async fn balance_of(&self, account: Account) -> Result<Nat, String>;
+
+    // Fee the ledger currently charges per transfer, in satoshis. Callers use the
+    // cached value in token instead of asking the ledger every time.
+    async fn fee(&self) -> Result<u64, String>;
+
+    async fn decimals(&self) -> Result<u8, String>;
+
+    async fn metadata(&self) -> Result<Vec<(String, MetadataValue)>, String>;
+
+    // ICRC-1 transfer out of one of the backend's own accounts
+    async fn transfer(&self, args: TransferArgs) -> Result<Result<Nat, TransferError>, String>;
+
+    // Up to `length` blocks starting at `start`, without gaps before the first
+    // block the ledger still holds
+    async fn get_transactions(&self, start: u64, length: u64) -> Result<BlockRange, String>;
+
+    // A single block, or None if the ledger has not created it
+    async fn get_block(&self, index: u64) -> Result<Option<LedgerTransaction>, String> {
+        let range = self.get_transactions(index, 1).await?;
+        Ok(range
+            .blocks
+            .into_iter()
+            .find(|(block_index, _)| *block_index == index)
+            .map(|(_, block)| block))
+    }
+}
+
+// The configured ledger canister
+pub struct IcLedger;
+
+impl Ledger for IcLedger {
+    async fn balance_of(&self, account: Account) -> Result<Nat, String> {
+        let token_canister = config::ledger_id();
+        let result: CallResult<(Nat,)> =
+            ic_cdk::call(token_canister, "icrc1_balance_of", (account,)).await;
+        result
+            .map(|(balance,)| balance)
+            .map_err(|e| format!("Failed to get balance: {:?}", e))
+    }
+
+    async fn fee(&self) -> Result<u64, String> {
+        let token_canister = config::ledger_id();
+        let result: CallResult<(Nat,)> = ic_cdk::call(token_canister, "icrc1_fee", ()).await;
+        let (fee,) = result.map_err(|e| format!("Failed to get ledger fee: {:?}", e))?;
+        nat_to_u64(&fee)
+    }
+
+    async fn decimals(&self) -> Result<u8, String> {
+        let token_canister = config::ledger_id();
+        let result: CallResult<(u8,)> = ic_cdk::call(token_canister, "icrc1_decimals", ()).await;
+        result
+            .map(|(decimals,)| decimals)
+            .map_err(|e| format!("Failed to get ledger decimals: {:?}", e))
+    }
+
+    async fn metadata(&self) -> Result<Vec<(String, MetadataValue)>, String> {
+        let token_canister = config::ledger_id();
+        let result: CallResult<(Vec<(String, MetadataValue)>,)> =
+            ic_cdk::call(token_canister, "icrc1_metadata", ()).await;
+        result
+            .map(|(metadata,)| metadata)
+            .map_err(|e| format!("Failed to get ledger metadata: {:?}", e))
+    }
+
+    async fn transfer(&self, args: TransferArgs) -> Result<Result<Nat, TransferError>, String> {
+        let token_canister = config::ledger_id();
+        let result: CallResult<(Result<Nat, TransferError>,)> =
+            ic_cdk::call(token_canister, "icrc1_transfer", (args,)).await;
+        result
+            .map(|(result,)| result)
+            .map_err(|e| format!("Failed to call transfer: {:?}", e))
+    }
+
+    // Blocks the ledger has moved to an archive canister are fetched from the archive
+    async fn get_transactions(&self, start: u64, length: u64) -> Result<BlockRange, String> {
+        let token_canister = config::ledger_id();
+        let request = GetTransactionsRequest { start: Nat::from(start), length: Nat::from(length) };
+        let result: CallResult<(GetTransactionsResponse,)> =
+            ic_cdk::call(token_canister, "get_transactions", (request,)).await;
+        let response = result
+            .map(|(response,)| response)
+            .map_err(|e| format!("Failed to get ledger blocks: {:?}", e))?;
+
+        let mut archived = Vec::new();
+        for range in &response.archived_transactions {
+            let request = GetTransactionsRequest { start: range.start.clone(), length: range.length.clone() };
+            let result: CallResult<(TransactionRange,)> =
+                ic_cdk::call(range.callback.0.principal, &range.callback.0.method, (request,)).await;
+            let range = result
+                .map(|(range,)| range)
+                .map_err(|e| format!("Failed to get archived ledger blocks: {:?}", e))?;
+            archived.push(range);
+        }
+
+        block_range(response, archived)
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum MetadataValue {
+    Nat(Nat),
+    Int(Int),
+    Text(String),
+    Blob(Vec<u8>),
+}
+
+// Ledger block log, as served by the ICRC-1 ledger's get_transactions. Only the
+// block kinds the backend inspects are decoded; approvals are skipped.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct LedgerMint {
+    pub to: Account,
+    pub amount: Nat,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct LedgerTransfer {
+    pub from: Account,
+    pub to: Account,
+    pub spender: Option<Account>,
+    pub amount: Nat,
+    pub fee: Option<Nat>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct LedgerTransaction {
+    pub kind: String,
+    pub mint: Option<LedgerMint>,
+    pub transfer: Option<LedgerTransfer>,
+    pub timestamp: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct GetTransactionsRequest {
+    pub start: Nat,
+    pub length: Nat,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct TransactionRange {
+    pub transactions: Vec<LedgerTransaction>,
+}
+
+candid::define_function!(pub QueryArchiveFn : (GetTransactionsRequest) -> (TransactionRange) query);
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ArchivedRange {
+    pub start: Nat,
+    pub length: Nat,
+    pub callback: QueryArchiveFn,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct GetTransactionsResponse {
+    pub log_length: Nat,
+    pub first_index: Nat,
+    pub transactions: Vec<LedgerTransaction>,
+    pub archived_transactions: Vec<ArchivedRange>,
+}
+
+// Consecutive blocks read from the ledger
+pub struct BlockRange {
+    pub blocks: Vec<(u64, LedgerTransaction)>,  // (block index, block) in index order
+}
+
+pub fn nat_to_u64(value: &Nat) -> Result<u64, String> {
+    u64::try_from(&value.0).map_err(|_| format!("{} does not fit in 64 bits", value))
+}
+
+// Number the blocks of a get_transactions response: each archived range in
+// `archived` answers the matching entry of `archived_transactions`, and the
+// blocks the ledger still holds follow from `first_index`
+fn block_range(response: GetTransactionsResponse, archived: Vec<TransactionRange>) -> Result<BlockRange, String> {
+    let mut blocks = Vec::new();
+    for (range, fetched) in response.archived_transactions.iter().zip(archived) {
+        let archive_start = nat_to_u64(&range.start)?;
+        blocks.extend((archive_start..).zip(fetched.transactions));
+    }
+
+    let first_index = nat_to_u64(&response.first_index)?;
+    blocks.extend((first_index..).zip(response.transactions));
+
+    Ok(BlockRange { blocks })
+}
+
+// ICRC-2 allowance and transfer_from, used to pull a user's funds into custody
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct AllowanceArgs {
+    pub account: Account,
+    pub spender: Account,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Allowance {
+    pub allowance: Nat,
+    pub expires_at: Option<u64>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct TransferFromArgs {
+    pub spender_subaccount: Option<Vec<u8>>,
+    pub from: Account,
+    pub to: Account,
+    pub amount: Nat,
+    pub fee: Option<Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub enum TransferFromError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    InsufficientAllowance { allowance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+// Allowance `account` has granted `spender`. An expired approval counts as none.
+pub async fn allowance(account: Account, spender: Account) -> Result<Nat, String> {
+    let token_canister = config::ledger_id();
+    let result: CallResult<(Allowance,)> =
+        ic_cdk::call(token_canister, "icrc2_allowance", (AllowanceArgs { account, spender },)).await;
+    let (allowance,) = result.map_err(|e| format!("Failed to get allowance: {:?}", e))?;
+
+    match allowance.expires_at {
+        Some(expires_at) if expires_at <= runtime::time() => Ok(Nat::from(0u64)),
+        _ => Ok(allowance.allowance),
+    }
+}
+
+pub async fn transfer_from(args: TransferFromArgs) -> Result<Result<Nat, TransferFromError>, String> {
+    let token_canister = config::ledger_id();
+    let result: CallResult<(Result<Nat, TransferFromError>,)> =
+        ic_cdk::call(token_canister, "icrc2_transfer_from", (args,)).await;
+    result
+        .map(|(result,)| result)
+        .map_err(|e| format!("Failed to call transfer_from: {:?}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::principal;
+
+    // A block told apart from the others by its timestamp
+    fn block(timestamp: u64) -> LedgerTransaction {
+        LedgerTransaction { kind: "burn".to_string(), mint: None, transfer: None, timestamp }
+    }
+
+    fn archived(start: u64, length: u64) -> ArchivedRange {
+        ArchivedRange {
+            start: Nat::from(start),
+            length: Nat::from(length),
+            callback: QueryArchiveFn::new(principal(7), "get_transactions".to_string()),
+        }
+    }
+
+    fn numbered(range: BlockRange) -> Vec<(u64, u64)> {
+        range.blocks.iter().map(|(index, block)| (*index, block.timestamp)).collect()
+    }
+
+    #[test]
+    fn archived_blocks_come_before_the_ledgers_own() {
+        let response = GetTransactionsResponse {
+            log_length: Nat::from(2_005u64),
+            first_index: Nat::from(2_003u64),
+            transactions: vec![block(3), block(4)],
+            archived_transactions: vec![archived(2_000, 2), archived(2_002, 1)],
+        };
+        let fetched = vec![
+            TransactionRange { transactions: vec![block(0), block(1)] },
+            TransactionRange { transactions: vec![block(2)] },
+        ];
+
+        let range = block_range(response, fetched).unwrap();
+        assert_eq!(
+            numbered(range),
+            vec![(2_000, 0), (2_001, 1), (2_002, 2), (2_003, 3), (2_004, 4)]
+        );
+    }
+
+    #[test]
+    fn range_without_archives_starts_at_first_index() {
+        let response = GetTransactionsResponse {
+            log_length: Nat::from(12u64),
+            first_index: Nat::from(10u64),
+            transactions: vec![block(10), block(11)],
+            archived_transactions: Vec::new(),
+        };
+        assert_eq!(numbered(block_range(response, Vec::new()).unwrap()), vec![(10, 10), (11, 11)]);
+
+        let response = GetTransactionsResponse {
+            log_length: Nat::from(0u64),
+            first_index: Nat::from(u128::from(u64::MAX) + 1),
+            transactions: Vec::new(),
+            archived_transactions: Vec::new(),
+        };
+        assert!(block_range(response, Vec::new()).is_err());
+    }
+}
@@ -5,17 +5,61 @@
 
 use candid::{CandidType, Deserialize, Nat, Principal};
 use ic_cdk::api::call::CallResult;
-use ic_cdk::{caller, query, update};
+use ic_cdk::{caller, init, post_upgrade, query, update};
 use serde::Serialize;
 use sha2::{Sha256, Digest};
 use std::cell::RefCell;
 
 // Stable memory imports
-use ic_stable_structures::{
-    DefaultMemoryImpl, StableBTreeMap, StableVec, Storable,
-};
+use ic_stable_structures::{StableBTreeMap, Storable};
 use std::borrow::Cow;
 
+mod admin;
+mod allowlist;
+mod amount;
+mod audit;
+mod balances;
+mod config;
+mod custody;
+mod deposits;
+mod error;
+mod fees;
+mod guard;
+mod journal;
+mod ledger;
+mod limits;
+mod memory;
+mod minter;
+mod pause;
+mod reserves;
+mod runtime;
+mod sweep;
+#[cfg(test)]
+mod testing;
+mod token;
+mod withdrawals;
+
+use admin::{AdminAction, Role, RoleAssignment};
+use allowlist::{AllowlistEntry, WithdrawalAllowlist};
+use amount::Amount;
+use btc_address::AddressType;
+use audit::{AuditRecord, CircuitBreaker};
+use config::{BackendArg, Config, InitArgs, Network, UpgradeArgs};
+use deposits::ScannerState;
+use error::{LegacyEndpoint, WalletError};
+use fees::PoolFees;
+use guard::PrincipalGuard;
+use journal::{Counterparty, HistoryPage, HistoryRequest, JournalRecord, TransactionChannel};
+use ledger::{IcLedger, Ledger};
+use minter::IcMinter;
+use limits::WithdrawalAllowance;
+use memory::Memory;
+use pause::{Operation, PauseState};
+use reserves::{ReconciliationReport, ReserveStatus};
+use sweep::SweepReport;
+use token::TokenInfo;
+use withdrawals::{BtcWithdrawal, QueuedWithdrawal};
+
 // Define a specific Result type for string operations
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
 pub enum TextResult {
@@ -26,10 +70,11 @@ pub enum TextResult {
 // Wallet status showing both custodial and personal balances
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
 pub struct WalletStatus {
-    pub custodial_balance: Nat,  // Funds in custody (backend's subaccount)
-    pub personal_balance: Nat,   // User's personal funds (not in custody)
-    pub total_available: Nat,    // Sum of both balances
-    pub can_deposit: bool,       // True if personal_balance > 0
+    pub custodial_balance: Nat,     // Funds in custody (virtual balance)
+    pub personal_balance: Nat,      // User's personal funds (not in custody)
+    pub total_available: Nat,       // Sum of both balances
+    pub can_deposit: bool,          // True if personal_balance > 0
+    pub deposit_slot_balance: Nat,  // Ledger balance of the user's deposit subaccount
 }
 
 // Receipt for deposit operations
@@ -37,21 +82,39 @@ pub struct WalletStatus {
 pub struct DepositReceipt {
     pub block_index: Nat,
     pub amount_deposited: Nat,
-    pub new_custodial_balance: Nat,
+    pub new_custodial_balance: Nat,  // Virtual balance after the deposit
     pub remaining_personal_balance: Nat,
 }
 
-// Transaction history types
+// Why deposit_to_custody did not move any funds
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub enum DepositError {
+    InsufficientAllowance { allowance: Nat, required: Nat },  // Approve the backend for `required` first
+    InsufficientFunds { balance: Nat, required: Nat },
+    LedgerError(String),
+    Other(String),
+}
+
+// Who bears the ledger fee of a withdrawal; the caller pays it either way
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WithdrawFeeMode {
+    #[default]
+    AddToAmount,       // `amount` arrives, `amount + fee` is debited
+    DeductFromAmount,  // `amount` is debited, `amount - fee` arrives
+}
+
+// Transaction history types
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub enum TransactionType {
     Send,
     Receive,
     Deposit,
     Withdraw,
     Mint,
+    Sweep,  // Custodial subaccount moved into the omnibus account
 }
 
-#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub enum TransactionStatus {
     Pending,
     Confirmed,
@@ -85,15 +148,6 @@ pub struct CustodialTransaction {
     pub timestamp: u64,
 }
 
-// Reserve status for backend solvency monitoring
-#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
-pub struct ReserveStatus {
-    pub total_virtual_balances: u64,  // Sum of all user virtual balances
-    pub backend_actual_balance: u64,  // Actual ckTestBTC held by backend
-    pub reserve_ratio: f64,           // actual / virtual (should be >= 1.0)
-    pub is_solvent: bool,             // backend_actual >= total_virtual
-}
-
 // Wrapper for Principal to implement Storable (orphan rule workaround)
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub struct StorablePrincipal(pub Principal);
@@ -112,7 +166,7 @@ impl From<StorablePrincipal> for Principal {
 
 // Stable memory implementations for StorablePrincipal
 impl Storable for StorablePrincipal {
-    fn to_bytes(&self) -> Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Borrowed(self.0.as_slice())
     }
 
@@ -126,9 +180,9 @@ impl Storable for StorablePrincipal {
     };
 }
 
-// Stable memory implementations for CustodialTransaction
+// Stable memory implementations for CustodialTransaction (legacy log, read during migration)
 impl Storable for CustodialTransaction {
-    fn to_bytes(&self) -> Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         let bytes = candid::encode_one(self).expect("Failed to encode CustodialTransaction");
         Cow::Owned(bytes)
     }
@@ -143,103 +197,122 @@ impl Storable for CustodialTransaction {
     };
 }
 
-// Storage for transaction history (legacy thread-local)
-thread_local! {
-    static TRANSACTIONS: RefCell<Vec<Transaction>> = RefCell::new(Vec::new());
-    static TRANSACTION_COUNTER: RefCell<u64> = RefCell::new(0);
+// Stable memory implementations for Transaction (legacy log, read during migration)
+impl Storable for Transaction {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let bytes = candid::encode_one(self).expect("Failed to encode Transaction");
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode Transaction")
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Bounded {
+        max_size: 1024,
+        is_fixed_size: false,
+    };
 }
 
-// Stable memory storage for custodial architecture
+// Stable memory storage - everything here survives canister upgrades
 thread_local! {
-    static MEMORY: RefCell<DefaultMemoryImpl> = RefCell::new(DefaultMemoryImpl::default());
-
     // User virtual balances (StorablePrincipal -> balance in satoshis)
-    static USER_BALANCES: RefCell<StableBTreeMap<StorablePrincipal, u64, DefaultMemoryImpl>> = RefCell::new(
-        StableBTreeMap::init(MEMORY.with(|m| m.borrow().clone()))
+    static USER_BALANCES: RefCell<StableBTreeMap<StorablePrincipal, Amount, Memory>> = RefCell::new(
+        StableBTreeMap::init(memory::get(memory::USER_BALANCES_MEMORY_ID))
     );
 
     // User deposit addresses (StorablePrincipal -> Bitcoin testnet address)
-    static USER_DEPOSIT_ADDRESSES: RefCell<StableBTreeMap<StorablePrincipal, String, DefaultMemoryImpl>> = RefCell::new(
-        StableBTreeMap::init(MEMORY.with(|m| m.borrow().clone()))
-    );
-
-    // Custodial transactions in stable memory
-    static STABLE_TRANSACTIONS: RefCell<StableVec<CustodialTransaction, DefaultMemoryImpl>> = RefCell::new(
-        StableVec::init(MEMORY.with(|m| m.borrow().clone())).expect("Failed to init stable transactions")
+    static USER_DEPOSIT_ADDRESSES: RefCell<StableBTreeMap<StorablePrincipal, String, Memory>> = RefCell::new(
+        StableBTreeMap::init(memory::get(memory::USER_DEPOSIT_ADDRESSES_MEMORY_ID))
     );
+}
 
-    // Transaction counter for stable transactions
-    static STABLE_TRANSACTION_COUNTER: RefCell<u64> = RefCell::new(0);
-}
-
-// Production canister IDs - only needed for production builds
-#[cfg(not(feature = "development"))]
-const IC_CKTESTBTC_CANISTER: &str = match option_env!("IC_CKTESTBTC_CANISTER_ID") {
-    Some(id) => id,
-    None => "g4xu7-jiaaa-aaaan-aaaaq-cai", // Default mainnet ckTestBTC
-};
-
-// Production minter canister
-#[cfg(not(feature = "development"))]
-const IC_MINTER_CANISTER: &str = match option_env!("IC_MINTER_CANISTER_ID") {
-    Some(id) => id,
-    None => "mqygn-kiaaa-aaaar-qaadq-cai", // Default IC ckTestBTC minter
-};
-
-// Environment detection moved to compile-time using Cargo features
-// Development builds: cargo build --features development
-// Production builds: cargo build --release (no features)
-
-// Get the appropriate token canister - production by default, development override
-fn get_token_canister() -> Result<Principal, String> {
-    #[cfg(feature = "development")]
-    {
-        // Development override: use local mock ledger with environment variables
-        const LOCAL_MOCK_LEDGER_CANISTER: &str = match option_env!("LOCAL_MOCK_LEDGER_CANISTER_ID") {
-            Some(id) => id,
-            None => "umunu-kh777-77774-qaaca-cai", // Default local mock ledger
-        };
-
-        if LOCAL_MOCK_LEDGER_CANISTER.is_empty() {
-            return Err("LOCAL_MOCK_LEDGER_CANISTER_ID environment variable not set".to_string());
-        }
-        return Principal::from_text(LOCAL_MOCK_LEDGER_CANISTER)
-            .map_err(|e| format!("Invalid local mock ledger principal: {e}"));
+// Fresh installs start on the current stable memory layout
+#[init]
+fn init(arg: Option<BackendArg>) {
+    match arg {
+        Some(BackendArg::Init(args)) => config::init(args),
+        Some(BackendArg::Upgrade(_)) => ic_cdk::trap("Expected an Init argument on install"),
+        None => config::init(InitArgs::default()),
     }
+    memory::set_layout_version(memory::STORAGE_LAYOUT_VERSION);
+    audit::start_timer();
+    deposits::start_scanner();
+    sweep::start_timer();
+    token::start_timer();
+    withdrawals::start_timer();
+}
 
-    #[cfg(not(feature = "development"))]
-    {
-        // Production default: use real IC ckTestBTC ledger
-        Principal::from_text(IC_CKTESTBTC_CANISTER)
-            .map_err(|e| format!("Invalid IC ckTestBTC principal: {e}"))
+// No pre_upgrade hook is needed: every piece of state lives in stable structures
+// that are written through on each update. post_upgrade checks the layout version,
+// migrates older layouts and touches every structure so that an incompatible
+// layout traps here, which rolls the upgrade back instead of breaking later calls.
+#[post_upgrade]
+fn post_upgrade(arg: Option<BackendArg>) {
+    let unmanaged = memory::take_unmanaged_state();
+    let stored_version = memory::check_layout_version();
+    if stored_version < memory::STORAGE_LAYOUT_VERSION {
+        ic_cdk::println!(
+            "[UPGRADE] Migrating stable memory layout v{} -> v{}",
+            stored_version, memory::STORAGE_LAYOUT_VERSION
+        );
     }
-}
 
-// Get the minter canister - production by default, development override
-fn get_minter_canister() -> Result<Principal, String> {
-    #[cfg(feature = "development")]
-    {
-        // Development override: use local mock minter with environment variables
-        const LOCAL_MOCK_MINTER_CANISTER: &str = match option_env!("LOCAL_MOCK_MINTER_CANISTER_ID") {
-            Some(id) => id,
-            None => "ulvla-h7777-77774-qaacq-cai", // Default local mock minter
-        };
+    restore_unmanaged_accounts(unmanaged.balances, unmanaged.deposit_addresses);
+    if stored_version < 2 {
+        let migrated = journal::migrate_legacy_logs(unmanaged.custodial_transactions);
+        ic_cdk::println!("[UPGRADE] Migrated {} legacy transactions into the journal", migrated);
+    }
+    if stored_version < 3 {
+        journal::build_user_index();
+    }
+    if stored_version < 4 {
+        let balance_holders: Vec<Principal> = USER_BALANCES.with(|b| b.borrow().iter().map(|(user, _)| user.0).collect());
+        custody::register_known_users(balance_holders.into_iter().chain(journal::users()));
+    }
 
-        if LOCAL_MOCK_MINTER_CANISTER.is_empty() {
-            return Err("LOCAL_MOCK_MINTER_CANISTER_ID environment variable not set".to_string());
+    match arg {
+        Some(BackendArg::Upgrade(Some(args))) => {
+            if let Err(e) = reconfigure(args) {
+                ic_cdk::trap(&format!("Invalid upgrade argument: {}", e));
+            }
         }
-        return Principal::from_text(LOCAL_MOCK_MINTER_CANISTER)
-            .map_err(|e| format!("Invalid local mock minter principal: {e}"));
+        Some(BackendArg::Init(_)) => ic_cdk::trap("Expected an Upgrade argument on upgrade"),
+        Some(BackendArg::Upgrade(None)) | None => {}
     }
 
-    #[cfg(not(feature = "development"))]
-    {
-        // Production default: use real IC ckTestBTC minter
-        Principal::from_text(IC_MINTER_CANISTER)
-            .map_err(|e| format!("Invalid IC ckTestBTC minter principal: {e}"))
-    }
+    let balances = USER_BALANCES.with(|b| b.borrow().len());
+    let addresses = USER_DEPOSIT_ADDRESSES.with(|a| a.borrow().len());
+    let journal_entries = journal::len();
+
+    memory::set_layout_version(memory::STORAGE_LAYOUT_VERSION);
+    audit::start_timer();
+    deposits::start_scanner();
+    sweep::start_timer();
+    token::start_timer();
+    withdrawals::start_timer();
+
+    ic_cdk::println!(
+        "[UPGRADE] Restored {} balances, {} deposit addresses, {} journal entries",
+        balances, addresses, journal_entries
+    );
 }
 
+// Move the balances and deposit addresses salvaged from the unmanaged layout
+// into their own memories. Balances are added to, so nothing already stored is lost.
+fn restore_unmanaged_accounts(balances: Vec<(Principal, Amount)>, deposit_addresses: Vec<(Principal, String)>) {
+    for (user, amount) in balances {
+        if let Err(e) = balances::credit(user, amount) {
+            ic_cdk::trap(&format!("Failed to restore the balance of {}: {}", user, e));
+        }
+    }
+    USER_DEPOSIT_ADDRESSES.with(|addresses| {
+        let mut addresses = addresses.borrow_mut();
+        for (user, address) in deposit_addresses {
+            addresses.insert(StorablePrincipal::from(user), address);
+        }
+    });
+}
 
 // Generate a deterministic subaccount for a user principal
 fn generate_subaccount_for_user(user: Principal) -> Vec<u8> {
@@ -250,40 +323,7 @@ fn generate_subaccount_for_user(user: Principal) -> Vec<u8> {
     hash[..32].to_vec()
 }
 
-// Helper to store a transaction
-fn store_transaction(
-    tx_type: TransactionType,
-    token: String,
-    amount: Nat,
-    from: String,
-    to: String,
-    status: TransactionStatus,
-    block_index: Option<Nat>,
-) -> u64 {
-    TRANSACTION_COUNTER.with(|counter| {
-        let mut c = counter.borrow_mut();
-        *c += 1;
-        let id = *c;
-
-        TRANSACTIONS.with(|txs| {
-            txs.borrow_mut().push(Transaction {
-                id,
-                tx_type,
-                token,
-                amount,
-                from,
-                to,
-                status,
-                timestamp: ic_cdk::api::time(),
-                block_index,
-            });
-        });
-
-        id
-    })
-}
-
-#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub struct Account {
     pub owner: Principal,
     pub subaccount: Option<Vec<u8>>,
@@ -315,6 +355,11 @@ pub enum TransferError {
 
 #[update]
 async fn get_balance() -> Result<Nat, String> {
+    get_balance_v2().await.map_err(|e| e.to_string())
+}
+
+#[update]
+async fn get_balance_v2() -> Result<Nat, WalletError> {
     let account = Account {
         owner: caller(),
         subaccount: None,
@@ -323,7 +368,7 @@ async fn get_balance() -> Result<Nat, String> {
     ic_cdk::println!("[GET_BALANCE] Called by principal: {}", caller());
     ic_cdk::println!("[GET_BALANCE] Account: {:?}", account);
 
-    let token_canister = get_token_canister()?;
+    let token_canister = config::ledger_id();
     ic_cdk::println!("[GET_BALANCE] Token canister: {}", token_canister);
 
     let result: CallResult<(Nat,)> = ic_cdk::call(token_canister, "icrc1_balance_of", (account,)).await;
@@ -335,7 +380,7 @@ async fn get_balance() -> Result<Nat, String> {
         },
         Err(e) => {
             ic_cdk::println!("[GET_BALANCE] Error: {e:?}");
-            Err(format!("Failed to get balance: {e:?}"))
+            Err(WalletError::LedgerUnavailable(format!("Failed to get balance: {e:?}")))
         },
     }
 }
@@ -349,47 +394,9 @@ async fn get_balance() -> Result<Nat, String> {
 // CUSTODIAL WALLET FUNCTIONS - Virtual Balance Management
 // ============================================================
 
-// Helper to store a custodial transaction
-fn store_custodial_transaction(
-    tx_type: TransactionType,
-    from_user: Option<Principal>,
-    to_user: Option<Principal>,
-    virtual_amount: Option<u64>,
-    on_chain_amount: Option<Nat>,
-    status: TransactionStatus,
-    block_index: Option<Nat>,
-) -> u64 {
-    STABLE_TRANSACTION_COUNTER.with(|counter| {
-        let mut c = counter.borrow_mut();
-        *c += 1;
-        let id = *c;
-
-        STABLE_TRANSACTIONS.with(|txs| {
-            let transaction = CustodialTransaction {
-                id,
-                tx_type,
-                from_user,
-                to_user,
-                virtual_amount,
-                on_chain_amount,
-                block_index,
-                status,
-                timestamp: ic_cdk::api::time(),
-            };
-
-            txs.borrow_mut().push(&transaction).expect("Failed to store custodial transaction");
-        });
-
-        id
-    })
-}
-
 #[query]
 fn get_virtual_balance() -> u64 {
-    let user = StorablePrincipal::from(caller());
-    USER_BALANCES.with(|balances| {
-        balances.borrow().get(&user).unwrap_or(0)
-    })
+    balances::get(caller()).sats()
 }
 
 #[query]
@@ -401,14 +408,23 @@ fn get_virtual_balance_formatted() -> Nat {
 // Get comprehensive wallet status showing both custodial and personal balances
 #[update]
 async fn get_wallet_status() -> Result<WalletStatus, String> {
+    get_wallet_status_v2().await.map_err(|e| e.to_string())
+}
+
+#[update]
+async fn get_wallet_status_v2() -> Result<WalletStatus, WalletError> {
     let caller_principal = caller();
-    let user_subaccount = generate_subaccount_for_user(caller_principal);
+    let user_subaccount = custody::subaccount_for(caller_principal);
 
     ic_cdk::println!("[WALLET_STATUS] Getting status for principal: {}", caller_principal);
 
-    // Get custodial balance (backend's subaccount for this user)
-    let custodial_account = Account {
-        owner: ic_cdk::api::id(),  // Backend canister owns this
+    // Custodial balance is the virtual balance; the backend's subaccount for
+    // this user is only the slot deposits arrive in
+    let custodial_balance = Nat::from(balances::get(caller_principal));
+    ic_cdk::println!("[WALLET_STATUS] Custodial balance: {}", custodial_balance);
+
+    let deposit_slot_account = Account {
+        owner: runtime::id(),  // Backend canister owns this
         subaccount: Some(user_subaccount),
     };
 
@@ -418,13 +434,13 @@ async fn get_wallet_status() -> Result<WalletStatus, String> {
         subaccount: None,
     };
 
-    let token_canister = get_token_canister()?;
+    let token_canister = config::ledger_id();
 
     // Query both balances
-    let custodial_result: CallResult<(Nat,)> = ic_cdk::call(
-        token_canister.clone(),
+    let deposit_slot_result: CallResult<(Nat,)> = ic_cdk::call(
+        token_canister,
         "icrc1_balance_of",
-        (custodial_account,)
+        (deposit_slot_account,)
     ).await;
 
     let personal_result: CallResult<(Nat,)> = ic_cdk::call(
@@ -433,13 +449,13 @@ async fn get_wallet_status() -> Result<WalletStatus, String> {
         (personal_account,)
     ).await;
 
-    let custodial_balance = match custodial_result {
+    let deposit_slot_balance = match deposit_slot_result {
         Ok((balance,)) => {
-            ic_cdk::println!("[WALLET_STATUS] Custodial balance: {}", balance);
+            ic_cdk::println!("[WALLET_STATUS] Deposit slot balance: {}", balance);
             balance
         },
         Err(e) => {
-            ic_cdk::println!("[WALLET_STATUS] Error getting custodial balance: {:?}", e);
+            ic_cdk::println!("[WALLET_STATUS] Error getting deposit slot balance: {:?}", e);
             Nat::from(0u64)
         }
     };
@@ -456,46 +472,52 @@ async fn get_wallet_status() -> Result<WalletStatus, String> {
     };
 
     let total = custodial_balance.clone() + personal_balance.clone();
-    let can_deposit = personal_balance.clone() > Nat::from(0u64);
+    let can_deposit = personal_balance > 0u64;
 
     Ok(WalletStatus {
         custodial_balance,
         personal_balance,
         total_available: total,
         can_deposit,
+        deposit_slot_balance,
     })
 }
 
-// Deposit user's personal funds into custody (backend's subaccount)
+// Deposit user's personal funds into custody (backend's subaccount).
+// The caller first approves the backend on the ledger (icrc2_approve) for the
+// amount plus the ledger fee; the backend then pulls the funds with
+// icrc2_transfer_from and credits the caller's virtual balance.
 #[update]
-async fn deposit_to_custody(amount: Nat) -> Result<DepositReceipt, String> {
+async fn deposit_to_custody(amount: Nat) -> Result<DepositReceipt, DepositError> {
+    deposit_to_custody_v2(amount).await.map_err(DepositError::from)
+}
+
+#[update]
+async fn deposit_to_custody_v2(amount: Nat) -> Result<DepositReceipt, WalletError> {
     let caller_principal = caller();
-    let user_subaccount = generate_subaccount_for_user(caller_principal);
+    Amount::parse(&amount)?;
+    pause::ensure_enabled(Operation::Deposit)?;
+    let _guard = PrincipalGuard::new(caller_principal)?;
+    let user_subaccount = custody::subaccount_for(caller_principal);
 
     ic_cdk::println!("[DEPOSIT_TO_CUSTODY] User {} depositing {} to custody", caller_principal, amount);
 
-    // First check user has sufficient personal balance
     let personal_account = Account {
         owner: caller_principal,
         subaccount: None,
     };
-
-    let token_canister = get_token_canister()?;
+    let backend_account = Account {
+        owner: runtime::id(),
+        subaccount: None,
+    };
 
     // Check personal balance
-    let balance_result: CallResult<(Nat,)> = ic_cdk::call(
-        token_canister.clone(),
-        "icrc1_balance_of",
-        (personal_account.clone(),)
-    ).await;
-
-    let personal_balance = match balance_result {
-        Ok((balance,)) => balance,
-        Err(e) => return Err(format!("Failed to check balance: {:?}", e)),
-    };
+    let personal_balance = IcLedger.balance_of(personal_account.clone())
+        .await
+        .map_err(WalletError::LedgerUnavailable)?;
 
     // Smart amount calculation for max deposit scenario
-    let fee = Nat::from(10u64);
+    let fee = Nat::from(token::fee(&IcLedger).await.map_err(WalletError::LedgerUnavailable)?);
 
     // Calculate maximum transferable amount (balance - fee)
     let max_transferable = if personal_balance > fee {
@@ -514,86 +536,78 @@ async fn deposit_to_custody(amount: Nat) -> Result<DepositReceipt, String> {
         amount.clone()
     };
 
-    // Verify user has enough for the actual amount + fee
-    let total_needed = actual_amount.clone() + fee.clone();
-    if personal_balance < total_needed {
-        return Err(format!(
-            "Insufficient personal balance. Balance: {} satoshis, Needed: {} satoshis (including 10 satoshi fee)",
-            personal_balance, total_needed
-        ));
+    // transfer_from consumes the amount plus the fee from both balance and allowance
+    let mut total_needed = actual_amount.clone() + fee.clone();
+    if actual_amount == 0u64 || personal_balance < total_needed {
+        return Err(WalletError::InsufficientFunds { balance: personal_balance, required: Some(total_needed) });
+    }
+
+    let allowance = ledger::allowance(personal_account.clone(), backend_account)
+        .await
+        .map_err(WalletError::LedgerUnavailable)?;
+    if allowance < total_needed {
+        return Err(WalletError::InsufficientAllowance { allowance, required: Some(total_needed) });
     }
 
-    // Transfer from user's personal account to backend's custodial subaccount
+    // Pull from the user's personal account into the backend's custodial subaccount
     let custodial_account = Account {
-        owner: ic_cdk::api::id(),  // Backend canister
+        owner: runtime::id(),  // Backend canister
         subaccount: Some(user_subaccount),  // User-specific subaccount
     };
 
-    let transfer_args = TransferArgs {
-        from_subaccount: None,  // User's default account
+    let mut transfer_args = ledger::TransferFromArgs {
+        spender_subaccount: None,
+        from: personal_account.clone(),
         to: custodial_account.clone(),
         amount: actual_amount.clone(),
         fee: Some(fee),
         memo: None,
-        created_at_time: Some(ic_cdk::api::time()),
+        created_at_time: Some(runtime::time()),
     };
 
-    // Use icrc2_transfer_from for transferring on behalf of user
-    // Note: This requires prior approval from the user
-    let transfer_result: CallResult<(Result<Nat, TransferError>,)> = ic_cdk::call(
-        token_canister.clone(),
-        "icrc1_transfer",
-        (transfer_args,)
-    ).await;
+    let mut result = ledger::transfer_from(transfer_args.clone()).await;
+    // The ledger fee changed since it was cached: retry once with the new fee
+    if let Ok(Err(ledger::TransferFromError::BadFee { expected_fee })) = &result {
+        token::update_fee(expected_fee).map_err(WalletError::LedgerRejected)?;
+        total_needed = actual_amount.clone() + expected_fee.clone();
+        transfer_args.fee = Some(expected_fee.clone());
+        result = ledger::transfer_from(transfer_args).await;
+    }
 
-    let block_index = match transfer_result {
-        Ok((Ok(index),)) => {
+    let block_index = match result {
+        Ok(Ok(index)) => {
             ic_cdk::println!("[DEPOSIT_TO_CUSTODY] Transfer successful, block: {}", index);
             index
         },
-        Ok((Err(e),)) => {
+        Ok(Err(ledger::TransferFromError::InsufficientAllowance { allowance })) => {
+            return Err(WalletError::InsufficientAllowance { allowance, required: Some(total_needed) });
+        },
+        Ok(Err(ledger::TransferFromError::InsufficientFunds { balance })) => {
+            return Err(WalletError::InsufficientFunds { balance, required: Some(total_needed) });
+        },
+        Ok(Err(e)) => {
             ic_cdk::println!("[DEPOSIT_TO_CUSTODY] Transfer error: {:?}", e);
-            return Err(format!("Transfer failed: {:?}", e));
+            return Err(e.into());
         },
         Err(e) => {
-            ic_cdk::println!("[DEPOSIT_TO_CUSTODY] Call error: {:?}", e);
-            return Err(format!("Failed to call transfer: {:?}", e));
+            ic_cdk::println!("[DEPOSIT_TO_CUSTODY] Call error: {}", e);
+            return Err(WalletError::LedgerUnavailable(e));
         }
     };
 
-    // Get updated balances
-    let custodial_balance_result: CallResult<(Nat,)> = ic_cdk::call(
-        token_canister.clone(),
-        "icrc1_balance_of",
-        (custodial_account,)
-    ).await;
-
-    let personal_balance_result: CallResult<(Nat,)> = ic_cdk::call(
-        token_canister,
-        "icrc1_balance_of",
-        (personal_account,)
-    ).await;
-
-    let new_custodial_balance = match custodial_balance_result {
-        Ok((balance,)) => balance,
-        Err(_) => Nat::from(0u64),
-    };
-
-    let remaining_personal_balance = match personal_balance_result {
-        Ok((balance,)) => balance,
-        Err(_) => Nat::from(0u64),
-    };
+    // Credit the virtual balance from the block the ledger created
+    let block = ledger::nat_to_u64(&block_index).map_err(WalletError::Internal)?;
+    let credited = Amount::from_nat(&actual_amount)?;
+    // The scanner may have credited the block while the transfer was awaited;
+    // the deposit went through either way
+    match deposits::credit(caller_principal, block, credited, Some(personal_account.clone()), custodial_account) {
+        Ok(_) | Err(WalletError::AlreadyCredited { .. }) => {}
+        Err(e) => return Err(e),
+    }
 
-    // Store transaction record
-    store_transaction(
-        TransactionType::Deposit,
-        "ckTestBTC".to_string(),
-        actual_amount.clone(),
-        caller_principal.to_text(),
-        "Custodial Wallet".to_string(),
-        TransactionStatus::Confirmed,
-        Some(block_index.clone()),
-    );
+    // Get updated balances
+    let new_custodial_balance = Nat::from(balances::get(caller_principal));
+    let remaining_personal_balance = IcLedger.balance_of(personal_account).await.unwrap_or_else(|_| Nat::from(0u64));
 
     Ok(DepositReceipt {
         block_index,
@@ -603,397 +617,493 @@ async fn deposit_to_custody(amount: Nat) -> Result<DepositReceipt, String> {
     })
 }
 
-// Notify backend of completed direct ledger deposit for balance tracking
+// Notify backend of a direct ledger transfer into the caller's custodial
+// subaccount. The block is read back from the ledger and credited to the
+// caller's virtual balance with its on-chain amount, at most once. The transfer
+// may come from any account: whatever lands in the caller's subaccount is theirs.
 #[update]
 async fn notify_deposit(block_index: Nat, amount: Nat) -> Result<DepositReceipt, String> {
+    notify_deposit_v2(block_index, amount).await.map_err(|e| e.to_string())
+}
+
+#[update]
+async fn notify_deposit_v2(block_index: Nat, amount: Nat) -> Result<DepositReceipt, WalletError> {
     let caller_principal = caller();
-    let user_subaccount = generate_subaccount_for_user(caller_principal);
+    let _guard = PrincipalGuard::new(caller_principal)?;
 
     ic_cdk::println!("[NOTIFY_DEPOSIT] User {} reporting deposit of {} at block {}", caller_principal, amount, block_index);
 
-    // Verify the transfer actually occurred by checking the custodial account balance
-    let custodial_account = Account {
-        owner: ic_cdk::api::id(),  // Backend canister
-        subaccount: Some(user_subaccount),  // User-specific subaccount
-    };
-
-    let token_canister = get_token_canister()?;
-
-    // Get current custodial balance to verify the deposit
-    let balance_result: CallResult<(Nat,)> = ic_cdk::call(
-        token_canister.clone(),
-        "icrc1_balance_of",
-        (custodial_account.clone(),)
-    ).await;
-
-    let new_custodial_balance = match balance_result {
-        Ok((balance,)) => balance,
-        Err(e) => return Err(format!("Failed to verify custodial balance: {:?}", e)),
-    };
+    let block = ledger::nat_to_u64(&block_index).map_err(WalletError::InvalidRequest)?;
+    let transfer = deposits::notify(&IcLedger, caller_principal, block, &amount).await?;
 
-    // Get user's personal balance for the receipt
-    let personal_account = Account {
+    // Balances for the receipt
+    let new_custodial_balance = Nat::from(balances::get(caller_principal));
+    let remaining_personal_balance = IcLedger.balance_of(Account {
         owner: caller_principal,
         subaccount: None,
-    };
-
-    let personal_balance_result: CallResult<(Nat,)> = ic_cdk::call(
-        token_canister.clone(),
-        "icrc1_balance_of",
-        (personal_account.clone(),)
-    ).await;
-
-    let remaining_personal_balance = match personal_balance_result {
-        Ok((balance,)) => balance,
-        Err(e) => return Err(format!("Failed to check personal balance: {:?}", e)),
-    };
-
-    // Store transaction record for the deposit
-    store_transaction(
-        TransactionType::Deposit,
-        "ckTestBTC".to_string(),
-        amount.clone(),
-        caller_principal.to_text(),
-        "Custodial Wallet".to_string(),
-        TransactionStatus::Confirmed,
-        Some(block_index.clone()),
-    );
+    })
+    .await
+    .unwrap_or_else(|_| Nat::from(0u64));
 
     ic_cdk::println!("[NOTIFY_DEPOSIT] Deposit verified. New custodial balance: {}", new_custodial_balance);
 
     Ok(DepositReceipt {
         block_index,
-        amount_deposited: amount,
+        amount_deposited: transfer.amount,
         new_custodial_balance,
         remaining_personal_balance,
     })
 }
 
-#[update]
-async fn deposit_funds(amount: Nat) -> Result<Nat, String> {
-    // Deprecated - use deposit_to_custody instead
-    // Keeping for backward compatibility
-    let user = caller();
-    let storable_user = StorablePrincipal::from(user);
-    let amount_satoshis = amount.0.to_u64_digits();
-
-    if amount_satoshis.len() != 1 {
-        return Err("Invalid amount format".to_string());
-    }
-
-    let amount_u64 = amount_satoshis[0];
-
-    ic_cdk::println!("[DEPOSIT] DEPRECATED - Use deposit_to_custody instead");
-
-    // First, transfer tokens FROM user TO backend canister
-    let backend_canister = ic_cdk::api::id();
-    let transfer_args = TransferArgs {
-        from_subaccount: None,
-        to: Account {
-            owner: backend_canister,
-            subaccount: None,
-        },
-        amount: amount.clone(),
-        fee: Some(Nat::from(10u64)), // 10 satoshi fee
-        memo: None,
-        created_at_time: Some(ic_cdk::api::time()),
-    };
+// Progress of the ledger scanner that credits unreported custodial deposits
+#[query]
+fn get_deposit_scanner_status() -> ScannerState {
+    deposits::scanner_state()
+}
 
-    let token_canister = get_token_canister()?;
+// Scan the ledger for deposits now instead of waiting for the timer.
+// Returns the number of deposits credited.
+#[update]
+async fn scan_deposits() -> Result<u64, String> {
+    scan_deposits_v2().await.map_err(|e| e.to_string())
+}
 
-    // Perform the on-chain transfer
-    let result: CallResult<(Result<Nat, TransferError>,)> = ic_cdk::call(
-        token_canister,
-        "icrc1_transfer",
-        (transfer_args,)
-    ).await;
+#[update]
+async fn scan_deposits_v2() -> Result<u64, WalletError> {
+    admin::authorize(Role::Operator, "scan_deposits")?;
+    Ok(deposits::scan(&IcLedger).await)
+}
 
-    match result {
-        Ok((Ok(block_index),)) => {
-            ic_cdk::println!("[DEPOSIT] On-chain transfer successful, block: {}", block_index);
+// Sweep custodial subaccounts into the omnibus account now instead of waiting
+// for the timer
+#[update]
+async fn sweep_custodial_subaccounts() -> Result<SweepReport, String> {
+    sweep_custodial_subaccounts_v2().await.map_err(|e| e.to_string())
+}
 
-            // Update user's virtual balance
-            USER_BALANCES.with(|balances| {
-                let mut balances_map = balances.borrow_mut();
-                let current_balance = balances_map.get(&storable_user).unwrap_or(0);
-                let new_balance = current_balance + amount_u64;
-                balances_map.insert(storable_user, new_balance);
+#[update]
+async fn sweep_custodial_subaccounts_v2() -> Result<SweepReport, WalletError> {
+    admin::authorize(Role::Operator, "sweep_custodial_subaccounts")?;
+    sweep::sweep(&IcLedger).await.ok_or_else(|| WalletError::InvalidRequest("A sweep or audit is already in progress".to_string()))
+}
 
-                ic_cdk::println!("[DEPOSIT] Virtual balance updated: {} -> {}", current_balance, new_balance);
-            });
+// Deprecated. The transfer deposit_funds made came out of the backend's own
+// account, so it credited the caller without moving any of their funds.
+// Deposits go through deposit_to_custody; these stay so existing callers get
+// an error saying so.
+#[update]
+fn deposit_funds(amount: Nat) -> Result<Nat, String> {
+    deposit_funds_v2(amount).map_err(|e| e.to_string())
+}
 
-            // Store the deposit transaction
-            store_custodial_transaction(
-                TransactionType::Deposit,
-                Some(user),
-                None, // Backend doesn't have a user representation
-                Some(amount_u64),
-                Some(amount.clone()),
-                TransactionStatus::Confirmed,
-                Some(block_index.clone()),
-            );
+#[update]
+fn deposit_funds_v2(_amount: Nat) -> Result<Nat, WalletError> {
+    Err(WalletError::InvalidRequest(
+        "deposit_funds has been removed: approve the backend and call deposit_to_custody instead".to_string(),
+    ))
+}
 
-            Ok(block_index)
-        }
-        Ok((Err(transfer_error),)) => {
-            ic_cdk::println!("[DEPOSIT] Transfer failed: {:?}", transfer_error);
-
-            // Store failed transaction
-            store_custodial_transaction(
-                TransactionType::Deposit,
-                Some(user),
-                None,
-                Some(amount_u64),
-                Some(amount),
-                TransactionStatus::Failed,
-                None,
-            );
-
-            Err(format!("Deposit transfer failed: {:?}", transfer_error))
-        }
-        Err(e) => {
-            ic_cdk::println!("[DEPOSIT] Call failed: {:?}", e);
-            Err(format!("Deposit call failed: {:?}", e))
-        }
-    }
+// Withdraw custodial funds to the caller's ledger account. The ledger fee is
+// charged to the caller: by default `amount` arrives and `amount` plus the fee
+// is debited; with DeductFromAmount `amount` is debited and `amount` minus the
+// fee arrives.
+#[update]
+async fn withdraw_funds(amount: Nat, fee_mode: Option<WithdrawFeeMode>) -> Result<Nat, String> {
+    withdraw_funds_v2(amount, fee_mode).await.map_err(|e| e.legacy_text(LegacyEndpoint::WithdrawFunds))
 }
 
 #[update]
-async fn withdraw_funds(amount: Nat) -> Result<Nat, String> {
+async fn withdraw_funds_v2(amount: Nat, fee_mode: Option<WithdrawFeeMode>) -> Result<Nat, WalletError> {
     let user = caller();
-    let amount_satoshis = amount.0.to_u64_digits();
+    let amount = Amount::parse(&amount)?;
+    pay_out(&IcLedger, user, user, amount, fee_mode.unwrap_or_default()).await
+}
 
-    if amount_satoshis.len() != 1 {
-        return Err("Invalid amount format".to_string());
+// Pay `amount` out of `user`'s virtual balance to `recipient`'s ledger account.
+// Shared by withdraw_funds and transfer.
+async fn pay_out(
+    ledger: &impl Ledger,
+    user: Principal,
+    recipient: Principal,
+    amount: Amount,
+    fee_mode: WithdrawFeeMode,
+) -> Result<Nat, WalletError> {
+    pause::ensure_enabled(Operation::WithdrawToLedger)?;
+
+    let _guard = PrincipalGuard::new(user)?;
+
+    // Counted against the withdrawal limits unless the transfer fails
+    let usage = limits::reserve(user, amount)?;
+    let result = withdraw_paying_current_fee(ledger, user, recipient, amount, fee_mode).await;
+    if result.is_err() {
+        limits::release(usage);
     }
+    result
+}
 
-    let amount_u64 = amount_satoshis[0];
+async fn withdraw_paying_current_fee(
+    ledger: &impl Ledger,
+    user: Principal,
+    recipient: Principal,
+    amount: Amount,
+    fee_mode: WithdrawFeeMode,
+) -> Result<Nat, WalletError> {
+    let fee = token::fee(ledger).await.map_err(WalletError::LedgerUnavailable)?;
+    let mut result = withdraw_from_pool(ledger, user, recipient, amount, fee_mode, fee).await?;
+
+    // The ledger fee changed since it was cached: retry once with the new fee
+    if let Err(TransferError::BadFee { expected_fee }) = &result {
+        let fee = token::update_fee(expected_fee).map_err(WalletError::LedgerRejected)?;
+        result = withdraw_from_pool(ledger, user, recipient, amount, fee_mode, fee).await?;
+    }
 
-    ic_cdk::println!("[WITHDRAW] User {} withdrawing {} satoshis", user, amount_u64);
+    result.map_err(WalletError::from)
+}
 
-    // Check user's virtual balance
-    let storable_user = StorablePrincipal::from(user);
-    let current_balance = USER_BALANCES.with(|balances| {
-        balances.borrow().get(&storable_user).unwrap_or(0)
-    });
+// One withdrawal attempt paying `fee` from `user`'s virtual balance to
+// `recipient`'s ledger account. The debit is returned if the ledger rejects the
+// transfer, which is reported as the inner error.
+async fn withdraw_from_pool(
+    ledger: &impl Ledger,
+    user: Principal,
+    recipient: Principal,
+    amount: Amount,
+    fee_mode: WithdrawFeeMode,
+    fee: u64,
+) -> Result<Result<Nat, TransferError>, WalletError> {
+    let ledger_fee = Amount::from_sats(fee);
+    let (debit_amount, send_amount) = match fee_mode {
+        WithdrawFeeMode::AddToAmount => (amount.checked_add(ledger_fee)?, amount),
+        WithdrawFeeMode::DeductFromAmount => {
+            // At least one satoshi has to arrive once the fee is deducted
+            let minimum = ledger_fee.checked_add(Amount::from_sats(1))?;
+            (amount.at_least(minimum)?, amount.checked_sub(ledger_fee)?)
+        }
+    };
 
-    if current_balance < amount_u64 {
-        store_custodial_transaction(
-            TransactionType::Withdraw,
-            None,
-            Some(user),
-            Some(amount_u64),
-            Some(amount),
-            TransactionStatus::Failed,
-            None,
-        );
+    ic_cdk::println!(
+        "[WITHDRAW] User {} withdrawing {} satoshis to {} (debit {}, fee {})",
+        user, send_amount, recipient, debit_amount, fee
+    );
 
-        return Err(format!("Insufficient virtual balance. Available: {}, Requested: {}", current_balance, amount_u64));
-    }
+    // Paying out to someone else is the user's Send; the recipient sees it as Receive
+    let (tx_type, from) = if recipient == user {
+        (TransactionType::Withdraw, Counterparty::account(runtime::id(), None))
+    } else {
+        (TransactionType::Send, Counterparty::Principal(user))
+    };
+    let record_withdrawal = |status: TransactionStatus, block_index: Option<Nat>| {
+        journal::record(JournalRecord {
+            tx_type: tx_type.clone(),
+            channel: TransactionChannel::Ledger,
+            from: Some(from.clone()),
+            to: Some(Counterparty::account(recipient, None)),
+            amount: Nat::from(send_amount),
+            virtual_amount: Some(debit_amount.sats()),
+            fee: Some(Nat::from(fee)),
+            status,
+            block_index,
+        })
+    };
+
+    // Debit before calling the ledger so interleaved calls see the reduced balance
+    let new_balance = match balances::debit(user, debit_amount) {
+        Ok(new_balance) => new_balance,
+        Err(e) => {
+            record_withdrawal(TransactionStatus::Failed, None);
+            return Err(e);
+        }
+    };
+    ic_cdk::println!("[WITHDRAW] Virtual balance updated: {} -> {}", new_balance.sats() + debit_amount.sats(), new_balance);
+
+    // Return the debited amount if the ledger transfer does not happen
+    let refund = || match balances::credit(user, debit_amount) {
+        Ok(balance) => ic_cdk::println!("[WITHDRAW] Refunded {} satoshis, balance: {}", debit_amount, balance),
+        Err(e) => ic_cdk::println!("[WITHDRAW] Failed to refund {} satoshis to {}: {}", debit_amount, user, e),
+    };
 
-    // Transfer tokens FROM backend canister TO user
+    // Transfer tokens FROM backend canister TO the recipient
     let transfer_args = TransferArgs {
         from_subaccount: None,
         to: Account {
-            owner: user,
+            owner: recipient,
             subaccount: None,
         },
-        amount: amount.clone(),
-        fee: Some(Nat::from(10u64)), // 10 satoshi fee
+        amount: Nat::from(send_amount),
+        fee: Some(Nat::from(fee)),
         memo: None,
-        created_at_time: Some(ic_cdk::api::time()),
+        created_at_time: Some(runtime::time()),
     };
 
-    let token_canister = get_token_canister()?;
-
-    // Perform the on-chain transfer
-    let result: CallResult<(Result<Nat, TransferError>,)> = ic_cdk::call(
-        token_canister,
-        "icrc1_transfer",
-        (transfer_args,)
-    ).await;
-
-    match result {
-        Ok((Ok(block_index),)) => {
+    match ledger.transfer(transfer_args).await {
+        Ok(Ok(block_index)) => {
             ic_cdk::println!("[WITHDRAW] On-chain transfer successful, block: {}", block_index);
 
-            // Update user's virtual balance
-            USER_BALANCES.with(|balances| {
-                let mut balances_map = balances.borrow_mut();
-                let new_balance = current_balance - amount_u64;
-                balances_map.insert(storable_user, new_balance);
-
-                ic_cdk::println!("[WITHDRAW] Virtual balance updated: {} -> {}", current_balance, new_balance);
-            });
+            record_withdrawal(TransactionStatus::Confirmed, Some(block_index.clone()));
+            fees::record_paid(fee);
 
-            // Store the withdrawal transaction
-            store_custodial_transaction(
-                TransactionType::Withdraw,
-                None,
-                Some(user),
-                Some(amount_u64),
-                Some(amount.clone()),
-                TransactionStatus::Confirmed,
-                Some(block_index.clone()),
-            );
-
-            Ok(block_index)
+            Ok(Ok(block_index))
         }
-        Ok((Err(transfer_error),)) => {
+        Ok(Err(transfer_error)) => {
             ic_cdk::println!("[WITHDRAW] Transfer failed: {:?}", transfer_error);
+            refund();
+            record_withdrawal(TransactionStatus::Failed, None);
 
-            // Store failed transaction
-            store_custodial_transaction(
-                TransactionType::Withdraw,
-                None,
-                Some(user),
-                Some(amount_u64),
-                Some(amount),
-                TransactionStatus::Failed,
-                None,
-            );
-
-            Err(format!("Withdrawal transfer failed: {:?}", transfer_error))
+            Ok(Err(transfer_error))
         }
         Err(e) => {
-            ic_cdk::println!("[WITHDRAW] Call failed: {:?}", e);
-            Err(format!("Withdrawal call failed: {:?}", e))
+            ic_cdk::println!("[WITHDRAW] Call failed: {}", e);
+            refund();
+            Err(WalletError::LedgerUnavailable(format!("Withdrawal call failed: {}", e)))
         }
     }
 }
 
 #[update]
 async fn virtual_transfer(to_user: Principal, amount: Nat) -> Result<u64, String> {
-    let from_user = caller();
-    let amount_satoshis = amount.0.to_u64_digits();
-
-    if amount_satoshis.len() != 1 {
-        return Err("Invalid amount format".to_string());
-    }
+    virtual_transfer_v2(to_user, amount).await.map_err(|e| e.legacy_text(LegacyEndpoint::VirtualTransfer))
+}
 
-    let amount_u64 = amount_satoshis[0];
+#[update]
+async fn virtual_transfer_v2(to_user: Principal, amount: Nat) -> Result<u64, WalletError> {
+    let from_user = caller();
+    let transfer_amount = Amount::parse(&amount)?;
 
-    ic_cdk::println!("[VIRTUAL_TRANSFER] DEBUG: from_user={}, to_user={}", from_user, to_user);
-    ic_cdk::println!("[VIRTUAL_TRANSFER] DEBUG: from_user.to_text()={}, to_user.to_text()={}", from_user.to_text(), to_user.to_text());
-    ic_cdk::println!("[VIRTUAL_TRANSFER] DEBUG: principals equal? {}", from_user == to_user);
+    pause::ensure_enabled(Operation::VirtualTransfer)?;
 
     if from_user == to_user {
-        return Err(format!("Cannot transfer to yourself: {} -> {}", from_user.to_text(), to_user.to_text()));
+        return Err(WalletError::InvalidRequest(format!(
+            "Cannot transfer to yourself: {} -> {}",
+            from_user.to_text(),
+            to_user.to_text()
+        )));
     }
 
-    ic_cdk::println!("[VIRTUAL_TRANSFER] {} -> {}: {} satoshis", from_user, to_user, amount_u64);
+    ic_cdk::println!("[VIRTUAL_TRANSFER] {} -> {}: {} satoshis", from_user, to_user, transfer_amount);
 
     // Update both users' virtual balances atomically
-    let storable_from_user = StorablePrincipal::from(from_user);
-    let storable_to_user = StorablePrincipal::from(to_user);
+    let result = balances::transfer(from_user, to_user, transfer_amount).map(|(new_from_balance, new_to_balance)| {
+        ic_cdk::println!("[VIRTUAL_TRANSFER] Balances updated - From: {}, To: {}", new_from_balance, new_to_balance);
+    });
 
-    let result = USER_BALANCES.with(|balances| {
-        let mut balances_map = balances.borrow_mut();
+    let status = match result {
+        Ok(()) => TransactionStatus::Confirmed,
+        Err(_) => TransactionStatus::Failed,
+    };
 
-        let from_balance = balances_map.get(&storable_from_user).unwrap_or(0);
-        let to_balance = balances_map.get(&storable_to_user).unwrap_or(0);
+    // Store the virtual transfer transaction (no on-chain transaction or block index)
+    let tx_id = journal::record(JournalRecord {
+        tx_type: TransactionType::Send, // Virtual transfer is recorded as Send
+        channel: TransactionChannel::Virtual,
+        from: Some(Counterparty::Principal(from_user)),
+        to: Some(Counterparty::Principal(to_user)),
+        amount,
+        virtual_amount: Some(transfer_amount.sats()),
+        fee: None,
+        status,
+        block_index: None,
+    });
 
-        if from_balance < amount_u64 {
-            return Err(format!("Insufficient virtual balance. Available: {}, Requested: {}", from_balance, amount_u64));
-        }
+    result.map(|()| tx_id)
+}
 
-        // Perform the transfer
-        let new_from_balance = from_balance - amount_u64;
-        let new_to_balance = to_balance + amount_u64;
+// Total ckTestBTC the backend holds on the ledger: its default (omnibus)
+// account plus every custodial subaccount
+#[update]
+async fn get_backend_total_balance() -> Result<Nat, String> {
+    get_backend_total_balance_v2().await.map_err(|e| e.to_string())
+}
 
-        balances_map.insert(storable_from_user, new_from_balance);
-        balances_map.insert(storable_to_user, new_to_balance);
+#[update]
+async fn get_backend_total_balance_v2() -> Result<Nat, WalletError> {
+    admin::authorize(Role::Operator, "get_backend_total_balance")?;
+    let buckets = reserves::fetch_buckets(&IcLedger).await.map_err(WalletError::LedgerUnavailable)?;
+    Ok(reserves::total_held(&buckets))
+}
 
-        ic_cdk::println!("[VIRTUAL_TRANSFER] Balances updated - From: {} -> {}, To: {} -> {}",
-            from_balance, new_from_balance, to_balance, new_to_balance);
+// Compare the backend's ledger holdings with the virtual balances owed to users
+#[update]
+async fn get_reserve_status() -> Result<ReserveStatus, String> {
+    get_reserve_status_v2().await.map_err(|e| e.to_string())
+}
 
-        Ok(())
-    });
+#[update]
+async fn get_reserve_status_v2() -> Result<ReserveStatus, WalletError> {
+    admin::authorize(Role::Operator, "get_reserve_status")?;
+    let status = reserves::check(&IcLedger).await.map_err(WalletError::LedgerUnavailable)?;
 
-    match result {
-        Ok(()) => {
-            // Store the virtual transfer transaction
-            let tx_id = store_custodial_transaction(
-                TransactionType::Send, // Virtual transfer is recorded as Send
-                Some(from_user),
-                Some(to_user),
-                Some(amount_u64),
-                None, // No on-chain transaction
-                TransactionStatus::Confirmed,
-                None, // No block index for virtual transfers
-            );
-
-            Ok(tx_id)
-        }
-        Err(e) => {
-            // Store failed transaction
-            store_custodial_transaction(
-                TransactionType::Send,
-                Some(from_user),
-                Some(to_user),
-                Some(amount_u64),
-                None,
-                TransactionStatus::Failed,
-                None,
-            );
-
-            Err(e)
-        }
-    }
+    ic_cdk::println!(
+        "[RESERVES] Held {} against {} owed ({} buckets), solvent: {}",
+        status.backend_actual_balance, status.total_virtual_balances, status.buckets.len(), status.is_solvent
+    );
+
+    Ok(status)
 }
 
+// Ledger fees the custody pool has paid on withdrawals and sweeps
 #[query]
-fn get_backend_total_balance() -> Result<Nat, String> {
-    // This would query the backend canister's own balance on the ckTestBTC ledger
-    // For now, return placeholder - would need async implementation
-    Ok(Nat::from(0u64))
+fn get_pool_fees() -> PoolFees {
+    fees::totals()
 }
 
+// ============================================================
+// SOLVENCY AUDITS - Circuit breaker for withdrawals and transfers
+// ============================================================
+
+// Most recent solvency audits, newest first (at most 100)
 #[query]
-fn get_reserve_status() -> ReserveStatus {
-    let total_virtual = USER_BALANCES.with(|balances| {
-        balances.borrow().iter().map(|(_, balance)| balance).sum::<u64>()
-    });
+fn get_solvency_audits(limit: u32) -> Vec<AuditRecord> {
+    audit::latest(limit.min(100) as usize)
+}
 
-    // For now, using placeholder values - would need to query actual backend balance
-    let backend_actual = 0u64; // Placeholder
-    let reserve_ratio = if total_virtual > 0 {
-        backend_actual as f64 / total_virtual as f64
-    } else {
-        1.0
-    };
+#[query]
+fn get_circuit_breaker() -> CircuitBreaker {
+    audit::circuit_breaker()
+}
 
-    ReserveStatus {
-        total_virtual_balances: total_virtual,
-        backend_actual_balance: backend_actual,
-        reserve_ratio,
-        is_solvent: backend_actual >= total_virtual,
+// Run a solvency audit now instead of waiting for the timer
+#[update]
+async fn run_solvency_audit() -> Result<AuditRecord, String> {
+    run_solvency_audit_v2().await.map_err(|e| e.to_string())
+}
+
+#[update]
+async fn run_solvency_audit_v2() -> Result<AuditRecord, WalletError> {
+    admin::authorize(Role::Operator, "run_solvency_audit")?;
+    audit::run(&IcLedger).await.ok_or(WalletError::OperationInProgress)
+}
+
+// Acknowledge a tripped circuit breaker and resume withdrawals and transfers
+#[update]
+fn resume_operations() -> Result<CircuitBreaker, String> {
+    resume_operations_v2().map_err(|e| e.to_string())
+}
+
+#[update]
+fn resume_operations_v2() -> Result<CircuitBreaker, WalletError> {
+    let admin = admin::authorize(Role::Admin, "resume_operations")?;
+    let state = audit::resume(admin)?;
+    ic_cdk::println!("[AUDIT] Circuit breaker acknowledged by {}, operations resumed", admin);
+    Ok(state)
+}
+
+// Per-user differences between virtual balances and deposit subaccounts
+#[update]
+async fn get_reconciliation_report() -> Result<ReconciliationReport, String> {
+    get_reconciliation_report_v2().await.map_err(|e| e.to_string())
+}
+
+#[update]
+async fn get_reconciliation_report_v2() -> Result<ReconciliationReport, WalletError> {
+    admin::authorize(Role::Operator, "get_reconciliation_report")?;
+    reserves::reconcile(&IcLedger).await.map_err(WalletError::LedgerUnavailable)
+}
+
+#[query]
+fn get_config() -> Config {
+    config::get()
+}
+
+// Change the runtime configuration without an upgrade; fields left out keep
+// their current value. Controllers only.
+#[update]
+fn update_config(args: UpgradeArgs) -> Result<Config, WalletError> {
+    admin::authorize_controller(&format!("update_config {:?}", args))?;
+    reconfigure(args)
+}
+
+// Apply `args` on top of the stored configuration. The ledger and the minter
+// hold what users are owed, so they can only be switched while nobody is owed
+// anything; a new ledger also restarts deposit crediting from its first block.
+fn reconfigure(args: UpgradeArgs) -> Result<Config, WalletError> {
+    let previous = config::get();
+    let switches_canister = args.ledger_id.is_some_and(|id| id != previous.ledger_id)
+        || args.minter_id.is_some_and(|id| id != previous.minter_id);
+    let owed = reserves::total_virtual_balances();
+    if switches_canister && owed > 0 {
+        return Err(WalletError::InvalidRequest(format!(
+            "The ledger and minter cannot be changed while users are owed {} satoshis",
+            owed
+        )));
     }
+
+    let updated = config::update(args)?;
+    if updated.ledger_id != previous.ledger_id {
+        token::invalidate();
+        deposits::reset_for_new_ledger();
+    }
+    Ok(updated)
+}
+
+// Grant `principal` a role, Admin unless given; replaces any role it held
+#[update]
+fn add_admin(principal: Principal, role: Option<Role>) -> Result<RoleAssignment, WalletError> {
+    let role = role.unwrap_or(Role::Admin);
+    let admin = admin::authorize(Role::Admin, &format!("add_admin {} {:?}", principal, role))?;
+    admin::grant(principal, role, Some(admin))
+}
+
+#[update]
+fn remove_admin(principal: Principal) -> Result<RoleAssignment, WalletError> {
+    admin::authorize(Role::Admin, &format!("remove_admin {}", principal))?;
+    admin::revoke(principal)
+}
+
+// Principals holding a role; controllers are admins without being listed
+#[query]
+fn list_admins() -> Result<Vec<RoleAssignment>, WalletError> {
+    admin::require(Role::Operator)?;
+    Ok(admin::assignments())
+}
+
+// Privileged calls, most recent first
+#[query]
+fn get_admin_log(limit: u32) -> Result<Vec<AdminAction>, WalletError> {
+    admin::require(Role::Admin)?;
+    Ok(admin::latest_actions(limit.min(100) as usize))
+}
+
+#[query]
+fn get_my_role() -> Option<Role> {
+    admin::role_of(&caller())
+}
+
+// What the caller may still withdraw under the configured limits
+#[query]
+fn get_withdrawal_allowance() -> WithdrawalAllowance {
+    limits::allowance(caller())
+}
+
+#[query]
+fn get_pause_status() -> PauseState {
+    pause::state()
+}
+
+// Pause or resume one operation, or every operation when `operation` is None.
+// Operators may pause; resuming takes an admin.
+#[update]
+fn set_paused(operation: Option<Operation>, paused: bool, reason: Option<String>) -> Result<PauseState, WalletError> {
+    let required = if paused { Role::Operator } else { Role::Admin };
+    let action = format!("set_paused {:?} {} {:?}", operation, paused, reason);
+    let by = admin::authorize(required, &action)?;
+    Ok(pause::set(operation, paused, reason, by))
 }
 
+// Caller's custodial history: last 100 entries that moved virtual balances
+// and where the caller is the sender or recipient, most recent first
 #[query]
 fn get_custodial_transaction_history() -> Vec<CustodialTransaction> {
-    STABLE_TRANSACTIONS.with(|txs| {
-        let transactions = txs.borrow();
-        let mut result = Vec::new();
-
-        // Get the last 100 transactions
-        let start_idx = if transactions.len() > 100 {
-            transactions.len() - 100
-        } else {
-            0
-        };
-
-        for i in start_idx..transactions.len() {
-            if let Some(tx) = transactions.get(i as u64) {
-                result.push(tx);
-            }
-        }
+    journal::latest_for_user(caller(), 100, journal::JournalEntry::is_custodial)
+        .iter()
+        .map(journal::JournalEntry::to_custodial_transaction)
+        .collect()
+}
 
-        // Return in reverse order (most recent first)
-        result.reverse();
-        result
-    })
+// Ledger fee, decimals and metadata as last read from the ledger; None until
+// the first read after install or upgrade has completed
+#[query]
+fn get_token_info() -> Option<TokenInfo> {
+    token::cached()
 }
 
 #[query]
@@ -1006,7 +1116,16 @@ fn get_principal() -> Principal {
 #[cfg(feature = "development")]
 #[update]
 async fn faucet() -> TextResult {
-    let caller_principal = caller();
+    match faucet_v2().await {
+        Ok(_) => TextResult::Ok(format!("Successfully minted 1 ckTestBTC to {}", caller())),
+        Err(e) => TextResult::Err(e.legacy_text(LegacyEndpoint::Faucet)),
+    }
+}
+
+#[cfg(feature = "development")]
+#[update]
+async fn faucet_v2() -> Result<Nat, WalletError> {
+    let caller_principal = admin::authorize(Role::Operator, "faucet")?;
     let account = Account {
         owner: caller_principal,
         subaccount: None,
@@ -1015,356 +1134,201 @@ async fn faucet() -> TextResult {
     // Mint 1 ckTestBTC (100,000,000 smallest units) to the caller
     let amount = Nat::from(100_000_000u64);
 
-    let token_canister = match get_token_canister() {
-        Ok(canister) => canister,
-        Err(e) => return TextResult::Err(e),
-    };
+    let token_canister = config::ledger_id();
 
     // Call the mint function on mock ledger
     let result: CallResult<(Result<Nat, TransferError>,)> =
-        ic_cdk::call(token_canister, "mint", (account, amount.clone())).await;
+        ic_cdk::call(token_canister, "mint", (account.clone(), amount.clone())).await;
 
     match result {
         Ok((Ok(block_index),)) => {
             // Record the mint transaction
-            let _tx_id = store_transaction(
-                TransactionType::Mint,
-                "ckTestBTC".to_string(),
-                amount.clone(),
-                "faucet".to_string(), // From faucet
-                caller_principal.to_string(), // To caller
-                TransactionStatus::Confirmed,
-                Some(block_index.clone()),
-            );
-
-            TextResult::Ok(format!("Successfully minted 1 ckTestBTC to {}", caller_principal))
+            journal::record(JournalRecord {
+                tx_type: TransactionType::Mint,
+                channel: TransactionChannel::Ledger,
+                from: None, // Minted by the faucet
+                to: Some(Counterparty::Account(account)),
+                amount: amount.clone(),
+                virtual_amount: None,
+                fee: None,
+                status: TransactionStatus::Confirmed,
+                block_index: Some(block_index.clone()),
+            });
+
+            Ok(block_index)
         },
-        Ok((Err(e),)) => TextResult::Err(format!("Mint failed: {:?}", e)),
-        Err(e) => TextResult::Err(format!("Call failed: {:?}", e)),
+        Ok((Err(e),)) => Err(e.into()),
+        Err(e) => Err(WalletError::LedgerUnavailable(format!("Call failed: {:?}", e))),
     }
 }
 
 #[update]
 async fn get_btc_address() -> TextResult {
-    let caller_principal = caller();
-
-    #[cfg(feature = "development")]
-    {
-        // Development: Generate a mock TestBTC address
-        // Create a deterministic mock address based on the principal
-        // Format: tb1q{hash_of_principal}... (testnet bech32 format)
-        let principal_bytes = caller_principal.as_slice();
-        let mut hasher = Sha256::new();
-        hasher.update(principal_bytes);
-        let hash = hasher.finalize();
-
-        // Take first 20 bytes of hash and encode as hex for a mock address
-        let addr_suffix = hex::encode(&hash[..20]);
-        let mock_address = format!("tb1q{}", &addr_suffix[..32]); // tb1q prefix for TestBTC testnet
-
-        return TextResult::Ok(mock_address);
+    match get_btc_address_v2().await {
+        Ok(address) => TextResult::Ok(address),
+        Err(e) => TextResult::Err(e.to_string()),
     }
+}
 
-    #[cfg(not(feature = "development"))]
-    {
-        // Production: Call actual minter for real BTC address derivation
-        let minter_canister = match get_minter_canister() {
-            Ok(canister) => canister,
-            Err(e) => return TextResult::Err(e),
-        };
-
-        // Prepare subaccount for the user
-        let user_subaccount = generate_subaccount_for_user(caller_principal);
-
-        // Prepare arguments for minter's get_btc_address
-        #[derive(CandidType, Serialize)]
-        struct GetBtcAddressArgs {
-            owner: Option<Principal>,
-            subaccount: Option<Vec<u8>>,
-        }
-
-        let args = GetBtcAddressArgs {
-            owner: Some(caller_principal),
-            subaccount: Some(user_subaccount),
-        };
-
-        // Call minter's get_btc_address function
-        let result: CallResult<(String,)> =
-            ic_cdk::call(minter_canister, "get_btc_address", (args,)).await;
+#[update]
+async fn get_btc_address_v2() -> Result<String, WalletError> {
+    let caller_principal = caller();
 
-        match result {
-            Ok((address,)) => TextResult::Ok(address),
-            Err(e) => TextResult::Err(format!("Failed to get deposit address: {:?}", e)),
-        }
-    }
+    // The minter derives the address; on a local replica that is the mock
+    // minter, which hands out valid P2WPKH testnet addresses
+    let user_subaccount = custody::subaccount_for(caller_principal);
+    minter::get_btc_address(caller_principal, Some(user_subaccount))
+        .await
+        .map_err(WalletError::MinterUnavailable)
 }
 
 // Get deposit address from minter
 #[update]
 async fn get_deposit_address() -> TextResult {
-    let caller_principal = caller();
-
-    // Try to get the minter canister
-    let minter_canister = match get_minter_canister() {
-        Ok(canister) => canister,
-        Err(e) => return TextResult::Err(e),
-    };
-
-    // Prepare arguments for minter's get_btc_address
-    #[derive(CandidType, Serialize)]
-    struct GetBtcAddressArgs {
-        owner: Option<Principal>,
-        subaccount: Option<Vec<u8>>,
+    match get_deposit_address_v2().await {
+        Ok(address) => TextResult::Ok(address),
+        Err(e) => TextResult::Err(e.to_string()),
     }
+}
 
-    // Use the custodial subaccount to ensure unique addresses per user
-    let user_subaccount = generate_subaccount_for_user(caller_principal);
-    let args = GetBtcAddressArgs {
-        owner: Some(caller_principal),
-        subaccount: Some(user_subaccount),
-    };
-
-    // Call minter's get_btc_address function
-    let result: CallResult<(String,)> =
-        ic_cdk::call(minter_canister, "get_btc_address", (args,)).await;
+#[update]
+async fn get_deposit_address_v2() -> Result<String, WalletError> {
+    let caller_principal = caller();
 
-    match result {
-        Ok((address,)) => TextResult::Ok(address),
-        Err(e) => TextResult::Err(format!("Failed to get deposit address: {:?}", e)),
-    }
+    // Use the custodial subaccount to ensure unique addresses per user
+    let user_subaccount = custody::subaccount_for(caller_principal);
+    minter::get_btc_address(caller_principal, Some(user_subaccount))
+        .await
+        .map_err(WalletError::MinterUnavailable)
 }
 
 // Withdraw TestBTC to BTC TestNet
 #[update]
 async fn withdraw_testbtc(address: String, amount: Nat) -> TextResult {
-    let caller_principal = caller();
-
-    // Validate address format (basic check)
-    if !address.starts_with("tb1") && !address.starts_with("2") &&
-       !address.starts_with("m") && !address.starts_with("n") {
-        return TextResult::Err("Invalid TestBTC address format".to_string());
+    match withdraw_testbtc_v2(address, amount).await {
+        Ok(BtcWithdrawal::Submitted { block_index }) => {
+            TextResult::Ok(format!("Withdrawal initiated. Block index: {}", block_index))
+        }
+        Ok(BtcWithdrawal::Queued(queued)) => TextResult::Ok(format!(
+            "Withdrawal {} queued. It can be cancelled until it is sent at {}",
+            queued.id, queued.execute_after
+        )),
+        Err(e) => TextResult::Err(e.legacy_text(LegacyEndpoint::WithdrawTestbtc)),
     }
+}
 
-    // Get minter canister
-    let minter_canister = match get_minter_canister() {
-        Ok(canister) => canister,
-        Err(e) => return TextResult::Err(e),
-    };
+// Debits the caller's virtual balance and sends the amount to `address`, or
+// queues it when a withdrawal delay is configured. Works against the mock
+// minter only; see withdrawals.
+#[update]
+async fn withdraw_testbtc_v2(address: String, amount: Nat) -> Result<BtcWithdrawal, WalletError> {
+    let caller_principal = caller();
 
-    // Prepare withdrawal arguments
-    #[derive(CandidType, Serialize)]
-    struct RetrieveBtcArgs {
-        address: String,
-        amount: u64,
-    }
+    pause::ensure_enabled(Operation::WithdrawToBtc)?;
+    let _guard = PrincipalGuard::new(caller_principal)?;
 
-    // Convert Nat to u64 (assuming amount is in satoshis)
-    let amount_u64 = amount.0.to_u64_digits()[0];
+    let address_type = check_btc_address(&address)?;
+    allowlist::check(caller_principal, &address)?;
 
-    let args = RetrieveBtcArgs {
-        address: address.clone(),
-        amount: amount_u64,
-    };
+    let withdrawal = Amount::parse(&amount)?;
+    ic_cdk::println!(
+        "[WITHDRAW] User {} withdrawing {} satoshis to {:?} address {}",
+        caller_principal, withdrawal, address_type, address
+    );
+    let usage = limits::reserve(caller_principal, withdrawal)?;
+    withdrawals::request(&IcMinter, caller_principal, address, withdrawal, usage, config::btc_withdrawal_delay()).await
+}
 
-    // Call minter's retrieve_btc function
-    #[derive(CandidType, Deserialize)]
-    struct RetrieveBtcOk {
-        block_index: u64,
+// Accept only addresses that fully decode as testnet addresses, or as regtest
+// addresses when the backend runs against a regtest minter
+fn check_btc_address(address: &str) -> Result<AddressType, WalletError> {
+    let invalid = |reason: String| WalletError::InvalidAddress(format!("Invalid TestBTC address: {}", reason));
+    let parsed = btc_address::parse(address).map_err(|e| invalid(e.to_string()))?;
+    if parsed.network == btc_address::Network::Regtest && config::network() != Network::Regtest {
+        return Err(invalid("regtest addresses are only accepted on regtest".to_string()));
     }
+    Ok(parsed.address_type)
+}
 
-    type RetrieveBtcResult = Result<RetrieveBtcOk, String>;
+// Cancel a queued withdraw_testbtc request before it is sent; the amount is
+// returned to the caller's virtual balance
+#[update]
+fn cancel_btc_withdrawal(id: u64) -> Result<QueuedWithdrawal, WalletError> {
+    withdrawals::cancel(caller(), id)
+}
 
-    let result: CallResult<(RetrieveBtcResult,)> =
-        ic_cdk::call(minter_canister, "retrieve_btc", (args,)).await;
+// Caller's withdraw_testbtc requests waiting out the withdrawal delay
+#[query]
+fn get_queued_btc_withdrawals() -> Vec<QueuedWithdrawal> {
+    withdrawals::queued_for(caller())
+}
 
-    match result {
-        Ok((Ok(retrieve_ok),)) => {
-            // Store withdrawal transaction
-            store_transaction(
-                TransactionType::Withdraw,
-                "ckTestBTC".to_string(),
-                amount,
-                caller_principal.to_text(),
-                address,
-                TransactionStatus::Pending,
-                Some(Nat::from(retrieve_ok.block_index)),
-            );
-            TextResult::Ok(format!("Withdrawal initiated. Block index: {}", retrieve_ok.block_index))
-        },
-        Ok((Err(e),)) => {
-            // Store failed withdrawal
-            store_transaction(
-                TransactionType::Withdraw,
-                "ckTestBTC".to_string(),
-                amount,
-                caller_principal.to_text(),
-                address,
-                TransactionStatus::Failed,
-                None,
-            );
-            TextResult::Err(format!("Withdrawal failed: {}", e))
-        },
-        Err(e) => TextResult::Err(format!("Call failed: {:?}", e)),
-    }
+// Register a withdrawal address; it can be withdrawn to once the allowlist
+// cooling-off period has passed
+#[update]
+fn add_withdrawal_address(address: String, label: Option<String>) -> Result<AllowlistEntry, WalletError> {
+    check_btc_address(&address)?;
+    allowlist::add(caller(), address, label)
+}
+
+#[update]
+fn remove_withdrawal_address(address: String) -> Result<AllowlistEntry, WalletError> {
+    allowlist::remove(caller(), &address)
 }
 
+// Restrict withdraw_testbtc to the caller's allowlist, or lift the restriction
+// once the cooling-off period has passed
+#[update]
+fn set_withdrawal_allowlist_enabled(enabled: bool) -> WithdrawalAllowlist {
+    allowlist::set_enabled(caller(), enabled)
+}
+
+#[query]
+fn get_withdrawal_allowlist() -> WithdrawalAllowlist {
+    allowlist::get(caller())
+}
 
 // Transaction History Functions
 
+// Caller's history: last 100 transactions the caller is a party to, most recent first
 #[query]
 fn get_transaction_history() -> Vec<Transaction> {
-    TRANSACTIONS.with(|txs| {
-        let transactions = txs.borrow();
-        // Return last 100 transactions, most recent first
-        let result: Vec<Transaction> = transactions.iter()
-            .rev()
-            .take(100)
-            .cloned()
-            .collect();
-        result
-    })
+    let user = caller();
+    journal::latest_for_user(user, 100, |_| true)
+        .iter()
+        .map(|entry| entry.to_transaction_for(user))
+        .collect()
 }
 
+// Caller's full history, one page at a time. Pass `next_cursor` from the previous
+// page as `cursor` to continue; a missing `next_cursor` means the history is exhausted.
+#[query]
+fn get_transaction_history_v2(request: HistoryRequest) -> HistoryPage {
+    journal::history_page(caller(), request)
+}
+
+// Transactions of other users are reported as not found
 #[query]
 fn get_transaction(id: u64) -> Option<Transaction> {
-    TRANSACTIONS.with(|txs| {
-        txs.borrow()
-            .iter()
-            .find(|tx| tx.id == id)
-            .cloned()
-    })
+    let user = caller();
+    journal::get(id)
+        .filter(|entry| entry.involves(user))
+        .map(|entry| entry.to_transaction_for(user))
 }
 
-// Transfer ckTestBTC tokens
+// Send ckTestBTC from the caller's virtual balance to `to_principal`'s ledger
+// account. `amount` arrives and the caller is debited `amount` plus the ledger
+// fee; the transfer counts against the caller's withdrawal limits.
 #[update]
 async fn transfer(to_principal: Principal, amount: Nat) -> Result<Nat, String> {
-    let from_principal = caller();
-
-    ic_cdk::println!("[TRANSFER] Called by principal: {}", from_principal);
-    ic_cdk::println!("[TRANSFER] Transferring {} to {}", amount, to_principal);
-
-    // Create transfer arguments with proper user principal
-    let transfer_args = TransferArgs {
-        from_subaccount: None,
-        to: Account {
-            owner: to_principal,
-            subaccount: None,
-        },
-        amount: amount.clone(),
-        fee: Some(Nat::from(10u64)), // 10 satoshi fee
-        memo: None,
-        created_at_time: Some(ic_cdk::api::time()),
-    };
-
-    let token_canister = get_token_canister()?;
-    ic_cdk::println!("[TRANSFER] Token canister: {}", token_canister);
-
-    // Create the from account using the caller's principal (not the canister's)
-    let from_account = Account {
-        owner: from_principal,
-        subaccount: None,
-    };
-
-    // First check the balance
-    let balance_result: CallResult<(Nat,)> = ic_cdk::call(
-        token_canister.clone(),
-        "icrc1_balance_of",
-        (from_account.clone(),)
-    ).await;
-
-    match balance_result {
-        Ok((balance,)) => {
-            ic_cdk::println!("[TRANSFER] User balance: {}", balance);
-
-            // Check if user has sufficient balance (amount + fee)
-            let required = amount.clone() + Nat::from(10u64);
-            if balance < required {
-                store_transaction(
-                    TransactionType::Send,
-                    "ckTestBTC".to_string(),
-                    amount.clone(),
-                    from_principal.to_text(),
-                    to_principal.to_text(),
-                    TransactionStatus::Failed,
-                    None,
-                );
-                return Err(format!("Insufficient balance. Required: {}, Available: {}", required, balance));
-            }
-        }
-        Err(e) => {
-            return Err(format!("Failed to check balance: {:?}", e));
-        }
-    }
-
-    // Now perform the transfer using icrc1_transfer
-    // This will use the caller's principal for the transfer
-    let result: CallResult<(Result<Nat, TransferError>,)> = ic_cdk::call(
-        token_canister,
-        "icrc1_transfer",
-        (transfer_args,)
-    ).await;
-
-    match result {
-        Ok((Ok(block_index),)) => {
-            ic_cdk::println!("[TRANSFER] Transfer successful, block index: {}", block_index);
-
-            // Store successful transaction
-            store_transaction(
-                TransactionType::Send,
-                "ckTestBTC".to_string(),
-                amount.clone(),
-                from_principal.to_text(),
-                to_principal.to_text(),
-                TransactionStatus::Confirmed,
-                Some(block_index.clone()),
-            );
-
-            // Also store the receive transaction for the recipient
-            store_transaction(
-                TransactionType::Receive,
-                "ckTestBTC".to_string(),
-                amount,
-                from_principal.to_text(),
-                to_principal.to_text(),
-                TransactionStatus::Confirmed,
-                Some(block_index.clone()),
-            );
+    transfer_v2(to_principal, amount).await.map_err(|e| e.legacy_text(LegacyEndpoint::Transfer))
+}
 
-            Ok(block_index)
-        }
-        Ok((Err(err),)) => {
-            let error_msg = format_transfer_error(&err);
-            ic_cdk::println!("[TRANSFER] Transfer failed: {}", error_msg);
-
-            // Store failed transaction
-            store_transaction(
-                TransactionType::Send,
-                "ckTestBTC".to_string(),
-                amount.clone(),
-                from_principal.to_text(),
-                to_principal.to_text(),
-                TransactionStatus::Failed,
-                None,
-            );
-
-            Err(error_msg)
-        }
-        Err(e) => {
-            ic_cdk::println!("[TRANSFER] Call failed: {:?}", e);
-
-            store_transaction(
-                TransactionType::Send,
-                "ckTestBTC".to_string(),
-                amount,
-                from_principal.to_text(),
-                to_principal.to_text(),
-                TransactionStatus::Failed,
-                None,
-            );
-
-            Err(format!("Call failed: {:?}", e))
-        }
-    }
+#[update]
+async fn transfer_v2(to_principal: Principal, amount: Nat) -> Result<Nat, WalletError> {
+    let user = caller();
+    let amount = Amount::parse(&amount)?;
+    pay_out(&IcLedger, user, to_principal, amount, WithdrawFeeMode::AddToAmount).await
 }
 
 // Helper function to format transfer errors
@@ -1394,3 +1358,429 @@ fn format_transfer_error(error: &TransferError) -> String {
 }
 
 ic_cdk::export_candid!();
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_stable_structures::{StableVec, VectorMemory};
+    use std::pin::pin;
+    use std::task::Poll;
+    use testing::{backend_account, block_on, poll_once, principal, user_account, MockLedger};
+
+    fn legacy_transfer(id: u64, from: Principal, to: Principal, sats: u64) -> CustodialTransaction {
+        CustodialTransaction {
+            id,
+            tx_type: TransactionType::Send,
+            from_user: Some(from),
+            to_user: Some(to),
+            virtual_amount: Some(sats),
+            on_chain_amount: None,
+            block_index: None,
+            status: TransactionStatus::Confirmed,
+            timestamp: 1_000 * id,
+        }
+    }
+
+    // Canisters from before the memory manager kept every structure on raw
+    // stable memory at offset 0; the one initialised first owns it
+    #[test]
+    fn upgrade_keeps_unmanaged_transaction_history() {
+        let (alice, bob) = (principal(1), principal(2));
+        let history = vec![
+            legacy_transfer(1, alice, bob, 500),
+            legacy_transfer(2, bob, alice, 200),
+            legacy_transfer(3, alice, bob, 50),
+        ];
+        let raw = VectorMemory::default();
+        let legacy: StableVec<CustodialTransaction, VectorMemory> = StableVec::init(raw.clone()).unwrap();
+        for tx in &history {
+            legacy.push(tx).unwrap();
+        }
+
+        let state = memory::read_unmanaged_state(raw);
+        assert!(state.balances.is_empty() && state.deposit_addresses.is_empty());
+        assert_eq!(journal::migrate_legacy_logs(state.custodial_transactions), history.len());
+
+        assert_eq!(journal::len(), history.len() as u64);
+        for tx in &history {
+            let migrated = journal::get(tx.id).unwrap().to_custodial_transaction();
+            assert_eq!(migrated.id, tx.id);
+            assert_eq!(migrated.from_user, tx.from_user);
+            assert_eq!(migrated.to_user, tx.to_user);
+            assert_eq!(migrated.virtual_amount, tx.virtual_amount);
+            assert_eq!(migrated.timestamp, tx.timestamp);
+        }
+    }
+
+    fn legacy_ledger_transaction(id: u64, tx_type: TransactionType, from: &str, to: &str, sats: u64) -> Transaction {
+        Transaction {
+            id,
+            tx_type,
+            token: "ckTestBTC".to_string(),
+            amount: Nat::from(sats),
+            from: from.to_string(),
+            to: to.to_string(),
+            status: TransactionStatus::Confirmed,
+            timestamp: 1_000 * id,
+            block_index: Some(Nat::from(id)),
+        }
+    }
+
+    // Both legacy logs numbered from 1 and the custodial counter restarted on
+    // upgrade; ids taken by an earlier entry move up by multiples of 2^32
+    #[test]
+    fn upgrade_keeps_legacy_ids_of_both_logs() {
+        let (alice, bob, carol) = (principal(1), principal(2), principal(3));
+        let namespace = 1u64 << 32;
+
+        let existing = journal::record(JournalRecord {
+            tx_type: TransactionType::Send,
+            channel: TransactionChannel::Virtual,
+            from: Some(Counterparty::Principal(alice)),
+            to: Some(Counterparty::Principal(bob)),
+            amount: Nat::from(7u64),
+            virtual_amount: Some(7),
+            fee: None,
+            status: TransactionStatus::Confirmed,
+            block_index: None,
+        });
+        assert_eq!(existing, 1);
+
+        let ledger_log: StableVec<Transaction, Memory> =
+            StableVec::init(memory::get(memory::TRANSACTIONS_MEMORY_ID)).unwrap();
+        let sent = legacy_ledger_transaction(1, TransactionType::Send, &alice.to_text(), &carol.to_text(), 300);
+        let received = legacy_ledger_transaction(2, TransactionType::Receive, &alice.to_text(), &carol.to_text(), 300);
+        let withdrawn = legacy_ledger_transaction(3, TransactionType::Withdraw, &alice.to_text(), "tb1qexample", 900);
+        for tx in [&sent, &received, &withdrawn] {
+            ledger_log.push(tx).unwrap();
+        }
+        let custodial_log: StableVec<CustodialTransaction, Memory> =
+            StableVec::init(memory::get(memory::STABLE_TRANSACTIONS_MEMORY_ID)).unwrap();
+        let custodial = [legacy_transfer(1, alice, bob, 500), legacy_transfer(2, bob, alice, 200)];
+        for tx in &custodial {
+            custodial_log.push(tx).unwrap();
+        }
+        let unmanaged = vec![legacy_transfer(1, bob, carol, 40)];
+
+        // The Receive entry only mirrored its Send
+        assert_eq!(journal::migrate_legacy_logs(unmanaged), 5);
+        assert_eq!(journal::len(), 6);
+
+        let transaction = |id: u64| journal::get(id).unwrap().to_transaction();
+        let custodial_transaction = |id: u64| journal::get(id).unwrap().to_custodial_transaction();
+
+        assert_eq!(custodial_transaction(existing).virtual_amount, Some(7));
+
+        let migrated_send = transaction(1 + namespace);
+        assert_eq!(migrated_send.tx_type, TransactionType::Send);
+        assert_eq!((migrated_send.from, migrated_send.to), (alice.to_text(), carol.to_text()));
+        assert_eq!(migrated_send.timestamp, sent.timestamp);
+        let migrated_withdrawal = transaction(3);
+        assert_eq!(migrated_withdrawal.tx_type, TransactionType::Withdraw);
+        assert_eq!(migrated_withdrawal.to, "tb1qexample");
+        assert_eq!(migrated_withdrawal.amount, Nat::from(900u64));
+
+        for (id, legacy) in [(1 + 2 * namespace, &custodial[0]), (2, &custodial[1])] {
+            let migrated = custodial_transaction(id);
+            assert_eq!(migrated.id, id);
+            assert_eq!(migrated.id as u32 as u64, legacy.id);
+            assert_eq!((migrated.from_user, migrated.to_user), (legacy.from_user, legacy.to_user));
+            assert_eq!(migrated.virtual_amount, legacy.virtual_amount);
+            assert_eq!(migrated.timestamp, legacy.timestamp);
+        }
+        let salvaged = custodial_transaction(1 + 3 * namespace);
+        assert_eq!((salvaged.from_user, salvaged.to_user), (Some(bob), Some(carol)));
+
+        // New entries continue above every migrated id
+        let next = journal::record(JournalRecord {
+            tx_type: TransactionType::Send,
+            channel: TransactionChannel::Virtual,
+            from: Some(Counterparty::Principal(carol)),
+            to: Some(Counterparty::Principal(alice)),
+            amount: Nat::from(1u64),
+            virtual_amount: Some(1),
+            fee: None,
+            status: TransactionStatus::Confirmed,
+            block_index: None,
+        });
+        assert_eq!(next, 2 + 3 * namespace);
+    }
+
+    #[test]
+    fn upgrade_keeps_unmanaged_balances_and_addresses() {
+        let (alice, bob, carol) = (principal(1), principal(2), principal(3));
+        let raw = VectorMemory::default();
+        let mut legacy_balances: StableBTreeMap<StorablePrincipal, u64, VectorMemory> =
+            StableBTreeMap::init(raw.clone());
+        legacy_balances.insert(StorablePrincipal(alice), 1_500);
+        legacy_balances.insert(StorablePrincipal(bob), 0);
+        // Baseline USER_DEPOSIT_ADDRESSES opened the same tree
+        let mut legacy_addresses: StableBTreeMap<StorablePrincipal, String, VectorMemory> =
+            StableBTreeMap::init(raw.clone());
+        legacy_addresses.insert(StorablePrincipal(carol), "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx".to_string());
+
+        let state = memory::read_unmanaged_state(raw);
+        assert!(state.custodial_transactions.is_empty());
+        restore_unmanaged_accounts(state.balances, state.deposit_addresses);
+
+        assert_eq!(balances::get(alice), Amount::from_sats(1_500));
+        assert_eq!(balances::get(bob), Amount::ZERO);
+        assert_eq!(balances::get(carol), Amount::ZERO);
+        assert_eq!(USER_BALANCES.with(|b| b.borrow().len()), 2);
+        assert_eq!(
+            USER_DEPOSIT_ADDRESSES.with(|a| a.borrow().get(&StorablePrincipal(carol))),
+            Some("tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx".to_string())
+        );
+    }
+
+    #[test]
+    fn fresh_memory_has_no_unmanaged_state() {
+        let state = memory::read_unmanaged_state(VectorMemory::default());
+        assert!(state.custodial_transactions.is_empty());
+        assert!(state.balances.is_empty() && state.deposit_addresses.is_empty());
+    }
+
+    // Backend with `pool` satoshis in its omnibus account and `user` owed `owed`
+    fn funded_pool(fee: u64, pool: u64, user: Principal, owed: u64) -> MockLedger {
+        let ledger = MockLedger::new(fee);
+        ledger.mint(backend_account(None), pool);
+        balances::credit(user, Amount::from_sats(owed)).unwrap();
+        ledger
+    }
+
+    fn withdraw(ledger: &MockLedger, user: Principal, sats: u64, fee_mode: WithdrawFeeMode) -> Result<Nat, WalletError> {
+        block_on(withdraw_paying_current_fee(ledger, user, user, Amount::from_sats(sats), fee_mode))
+    }
+
+    fn latest_entry(user: Principal) -> journal::JournalEntry {
+        journal::latest_for_user(user, 1, |_| true).remove(0)
+    }
+
+    #[test]
+    fn withdrawal_charges_the_fee_to_the_user() {
+        let alice = principal(1);
+        let ledger = funded_pool(10, 10_000, alice, 1_000);
+
+        withdraw(&ledger, alice, 300, WithdrawFeeMode::AddToAmount).unwrap();
+        assert_eq!(balances::get(alice), Amount::from_sats(690));
+        assert_eq!(ledger.balance(&user_account(alice)), 300);
+        assert_eq!(ledger.balance(&backend_account(None)), 10_000 - 310);
+
+        withdraw(&ledger, alice, 300, WithdrawFeeMode::DeductFromAmount).unwrap();
+        assert_eq!(balances::get(alice), Amount::from_sats(390));
+        assert_eq!(ledger.balance(&user_account(alice)), 590);
+
+        let entry = latest_entry(alice);
+        assert_eq!(entry.tx_type, TransactionType::Withdraw);
+        assert_eq!(entry.status, TransactionStatus::Confirmed);
+        assert_eq!((entry.amount, entry.virtual_amount), (Nat::from(290u64), Some(300)));
+        assert_eq!(fees::totals().total_paid, 20);
+    }
+
+    #[test]
+    fn rejected_withdrawal_is_refunded() {
+        let alice = principal(1);
+        let ledger = funded_pool(10, 10_000, alice, 1_000);
+
+        ledger.respond_next(Ok(Err(TransferError::TemporarilyUnavailable)));
+        let result = withdraw(&ledger, alice, 300, WithdrawFeeMode::AddToAmount);
+        assert!(matches!(result, Err(WalletError::LedgerUnavailable(_))));
+        assert_eq!(balances::get(alice), Amount::from_sats(1_000));
+        assert_eq!(latest_entry(alice).status, TransactionStatus::Failed);
+
+        // A call that never reached the ledger is refunded as well
+        ledger.respond_next(Err("Canister unreachable".to_string()));
+        let result = withdraw(&ledger, alice, 300, WithdrawFeeMode::AddToAmount);
+        assert!(matches!(result, Err(WalletError::LedgerUnavailable(_))));
+        assert_eq!(balances::get(alice), Amount::from_sats(1_000));
+
+        assert_eq!(ledger.balance(&backend_account(None)), 10_000);
+        assert_eq!(fees::totals().total_paid, 0);
+    }
+
+    #[test]
+    fn bad_fee_is_retried_with_the_ledgers_fee() {
+        let alice = principal(1);
+        let ledger = funded_pool(10, 10_000, alice, 1_000);
+        block_on(token::fee(&ledger)).unwrap();
+
+        // The ledger raised its fee after it was cached
+        ledger.fee.set(20);
+        withdraw(&ledger, alice, 300, WithdrawFeeMode::AddToAmount).unwrap();
+
+        let fees_offered: Vec<Option<Nat>> = ledger.transfers.borrow().iter().map(|args| args.fee.clone()).collect();
+        assert_eq!(fees_offered, vec![Some(Nat::from(10u64)), Some(Nat::from(20u64))]);
+        assert_eq!(balances::get(alice), Amount::from_sats(680));
+        assert_eq!(token::cached().unwrap().fee, 20);
+        assert_eq!(fees::totals().total_paid, 20);
+    }
+
+    #[test]
+    fn bad_fee_against_a_configured_fee_is_refunded() {
+        let alice = principal(1);
+        let ledger = funded_pool(20, 10_000, alice, 1_000);
+        config::update(UpgradeArgs { ledger_fee: Some(Some(10)), ..Default::default() }).unwrap();
+
+        let result = withdraw(&ledger, alice, 300, WithdrawFeeMode::AddToAmount);
+        assert!(matches!(result, Err(WalletError::LedgerRejected(_))));
+        assert_eq!(ledger.transfers.borrow().len(), 1);
+        assert_eq!(balances::get(alice), Amount::from_sats(1_000));
+    }
+
+    #[test]
+    fn interleaved_withdrawals_cannot_overdraw() {
+        let alice = principal(1);
+        let ledger = funded_pool(10, 10_000, alice, 1_000);
+        block_on(token::fee(&ledger)).unwrap();
+        ledger.yield_on_transfer.set(true);
+
+        // The second withdrawal runs while the first is waiting for the ledger
+        let mut first = pin!(withdraw_from_pool(&ledger, alice, alice, Amount::from_sats(600), WithdrawFeeMode::AddToAmount, 10));
+        assert!(poll_once(first.as_mut()).is_pending());
+        let second = block_on(withdraw_from_pool(&ledger, alice, alice, Amount::from_sats(600), WithdrawFeeMode::AddToAmount, 10));
+        assert_eq!(
+            second.err(),
+            Some(WalletError::InsufficientFunds { balance: Nat::from(390u64), required: Some(Nat::from(610u64)) })
+        );
+        assert!(matches!(poll_once(first.as_mut()), Poll::Ready(Ok(Ok(_)))));
+        assert_eq!(balances::get(alice), Amount::from_sats(390));
+
+        // Funds in flight are spendable again once their transfer failed
+        ledger.respond_next(Ok(Err(TransferError::TemporarilyUnavailable)));
+        let mut first = pin!(withdraw_from_pool(&ledger, alice, alice, Amount::from_sats(380), WithdrawFeeMode::AddToAmount, 10));
+        assert!(poll_once(first.as_mut()).is_pending());
+        assert!(block_on(withdraw_from_pool(&ledger, alice, alice, Amount::from_sats(380), WithdrawFeeMode::AddToAmount, 10)).is_err());
+        assert!(matches!(poll_once(first.as_mut()), Poll::Ready(Ok(Err(_)))));
+        assert!(block_on(withdraw_from_pool(&ledger, alice, alice, Amount::from_sats(380), WithdrawFeeMode::AddToAmount, 10)).is_ok());
+        assert_eq!(balances::get(alice), Amount::ZERO);
+    }
+
+    #[test]
+    fn a_second_payout_waits_for_the_first() {
+        let alice = principal(1);
+        let ledger = funded_pool(10, 10_000, alice, 1_000);
+        ledger.yield_on_transfer.set(true);
+
+        let mut first = pin!(pay_out(&ledger, alice, alice, Amount::from_sats(100), WithdrawFeeMode::AddToAmount));
+        assert!(poll_once(first.as_mut()).is_pending());
+        // An interleaved call from the same user stops at the guard before debiting
+        let second = block_on(pay_out(&ledger, alice, alice, Amount::from_sats(100), WithdrawFeeMode::AddToAmount));
+        assert_eq!(second, Err(WalletError::OperationInProgress));
+        assert_eq!(balances::get(alice), Amount::from_sats(890));
+
+        assert!(block_on(first).is_ok());
+        assert!(block_on(pay_out(&ledger, alice, alice, Amount::from_sats(100), WithdrawFeeMode::AddToAmount)).is_ok());
+        assert_eq!(balances::get(alice), Amount::from_sats(780));
+    }
+
+    #[test]
+    fn transfer_pays_out_of_the_senders_virtual_balance() {
+        let (alice, bob) = (principal(1), principal(2));
+        let ledger = funded_pool(10, 10_000, alice, 1_000);
+
+        block_on(pay_out(&ledger, alice, bob, Amount::from_sats(300), WithdrawFeeMode::AddToAmount)).unwrap();
+        assert_eq!(balances::get(alice), Amount::from_sats(690));
+        assert_eq!(balances::get(bob), Amount::ZERO);
+        assert_eq!(ledger.balance(&user_account(bob)), 300);
+        assert_eq!(ledger.balance(&user_account(alice)), 0);
+        assert_eq!(ledger.balance(&backend_account(None)), 10_000 - 310);
+        assert_eq!(limits::allowance(alice).user_withdrawn, 300);
+
+        // More than the sender holds is refused and not counted against the limits
+        let result = block_on(pay_out(&ledger, alice, bob, Amount::from_sats(690), WithdrawFeeMode::AddToAmount));
+        assert!(result.is_err());
+        assert_eq!(balances::get(alice), Amount::from_sats(690));
+        assert_eq!(limits::allowance(alice).user_withdrawn, 300);
+    }
+
+    #[test]
+    fn deposit_funds_is_gone() {
+        let result = deposit_funds_v2(Nat::from(1_000u64));
+        assert!(matches!(result, Err(WalletError::InvalidRequest(message)) if message.contains("deposit_to_custody")));
+    }
+
+    #[test]
+    fn only_controllers_change_the_config() {
+        let (admin, controller) = (principal(1), principal(2));
+        admin::grant(admin, Role::Admin, None).unwrap();
+        runtime::add_controller(controller);
+        let args = UpgradeArgs { btc_withdrawal_delay: Some(Some(60)), ..UpgradeArgs::default() };
+
+        runtime::set_caller(admin);
+        assert_eq!(update_config(args.clone()).err(), Some(WalletError::Unauthorized));
+        assert_eq!(config::get().btc_withdrawal_delay, None);
+
+        runtime::set_caller(controller);
+        assert_eq!(update_config(args).unwrap().btc_withdrawal_delay, Some(60));
+        let logged = admin::latest_actions(1).remove(0);
+        assert_eq!(logged.caller, controller);
+        assert!(logged.action.starts_with("update_config"));
+    }
+
+    #[test]
+    fn invalid_config_is_refused() {
+        let controller = principal(2);
+        runtime::add_controller(controller);
+        runtime::set_caller(controller);
+
+        let limits = config::WithdrawalLimits { min_withdrawal: 1_000, max_withdrawal: Some(500), ..Default::default() };
+        let result = update_config(UpgradeArgs { limits: Some(limits), ..UpgradeArgs::default() });
+        assert!(matches!(result, Err(WalletError::InvalidRequest(_))));
+        let result = update_config(UpgradeArgs { ledger_id: Some(Principal::anonymous()), ..UpgradeArgs::default() });
+        assert!(matches!(result, Err(WalletError::InvalidRequest(_))));
+
+        let config = config::get();
+        assert_eq!(config.limits, config::WithdrawalLimits::default());
+        assert_ne!(config.ledger_id, Principal::anonymous());
+    }
+
+    #[test]
+    fn ledger_moves_only_while_nobody_is_owed() {
+        let (alice, controller) = (principal(1), principal(2));
+        runtime::add_controller(controller);
+        runtime::set_caller(controller);
+        let ledger = MockLedger::new(10);
+        ledger.mint(user_account(alice), 1_000);
+        let deposit = ledger.send(user_account(alice), backend_account(Some(generate_subaccount_for_user(alice))), 500);
+        assert_eq!(block_on(deposits::scan(&ledger)), 1);
+
+        let new_ledger = UpgradeArgs { ledger_id: Some(principal(7)), ..UpgradeArgs::default() };
+        let new_minter = UpgradeArgs { minter_id: Some(principal(8)), ..UpgradeArgs::default() };
+        for args in [new_ledger.clone(), new_minter] {
+            assert!(matches!(update_config(args), Err(WalletError::InvalidRequest(_))));
+        }
+        assert_ne!(config::ledger_id(), principal(7));
+        assert_ne!(config::minter_id(), principal(8));
+
+        // Once nothing is owed the switch goes through and block indices start over
+        balances::debit(alice, Amount::from_sats(500)).unwrap();
+        assert_eq!(update_config(new_ledger).unwrap().ledger_id, principal(7));
+        assert!(!deposits::is_credited(deposit));
+        assert_eq!(deposits::scanner_state().next_block, 0);
+    }
+
+    #[test]
+    fn operators_pause_and_admins_resume() {
+        let (operator, controller) = (principal(2), principal(3));
+        admin::grant(operator, Role::Operator, None).unwrap();
+        runtime::add_controller(controller);
+
+        runtime::set_caller(operator);
+        let state = set_paused(Some(Operation::WithdrawToLedger), true, Some("incident".to_string())).unwrap();
+        assert!(state.withdraw_to_ledger.paused);
+        assert_eq!(set_paused(Some(Operation::WithdrawToLedger), false, None).err(), Some(WalletError::Unauthorized));
+        assert_eq!(set_paused(None, false, None).err(), Some(WalletError::Unauthorized));
+        assert!(pause::state().withdraw_to_ledger.paused);
+
+        // A controller is an admin without holding the role
+        runtime::set_caller(controller);
+        let state = set_paused(Some(Operation::WithdrawToLedger), false, None).unwrap();
+        assert!(!state.withdraw_to_ledger.paused);
+        assert_eq!(state.withdraw_to_ledger.changed_by, Some(controller));
+
+        runtime::set_caller(principal(4));
+        assert_eq!(set_paused(None, true, None).err(), Some(WalletError::Unauthorized));
+    }
+}
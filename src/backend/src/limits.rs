@@ -0,0 +1,272 @@
+// Withdrawal limits.
+//
+// Every withdrawal out of custody (withdraw_funds and withdraw_testbtc) is
+// checked against the configured limits before the ledger or minter is called:
+// a minimum and maximum per transaction, a cap on what one user may withdraw in
+// any 24 hour window and a cap on what all users together may withdraw in it.
+//
+// The windows slide: each withdrawal is kept in a stable log with its time, and
+// the usage of a window is the sum of the entries of the last 24 hours. An entry
+// is reserved before the outgoing call and released again if the withdrawal does
+// not happen, so concurrent calls cannot overrun a limit between them.
+
+use candid::{CandidType, Deserialize, Nat, Principal};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use serde::Serialize;
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+use crate::amount::Amount;
+use crate::config;
+use crate::error::WalletError;
+use crate::memory::{self, Memory};
+use crate::runtime;
+
+pub const WINDOW_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+// Which limit a withdrawal ran into
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LimitScope {
+    PerTransaction,
+    UserDaily,
+    GlobalDaily,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+struct WithdrawalUsage {
+    user: Principal,
+    amount: u64,
+    at: u64,
+}
+
+impl Storable for WithdrawalUsage {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let bytes = candid::encode_one(self).expect("Failed to encode WithdrawalUsage");
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode WithdrawalUsage")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// What the caller may still withdraw; None where no limit is configured
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct WithdrawalAllowance {
+    pub min_withdrawal: u64,
+    pub max_withdrawal: Option<u64>,
+    pub user_daily_limit: Option<u64>,
+    pub user_withdrawn: u64,             // By the caller in the last 24 hours
+    pub user_remaining: Option<u64>,
+    pub global_remaining: Option<u64>,
+    pub available: Option<u64>,          // Largest withdrawal every limit allows right now
+    pub window_resets_at: Option<u64>,   // When the caller's oldest withdrawal in the window expires
+}
+
+thread_local! {
+    // Withdrawals of the last 24 hours by id, ids assigned sequentially so they
+    // are ordered by time
+    static WITHDRAWAL_WINDOW: RefCell<StableBTreeMap<u64, WithdrawalUsage, Memory>> = RefCell::new(
+        StableBTreeMap::init(memory::get(memory::WITHDRAWAL_WINDOW_MEMORY_ID))
+    );
+}
+
+fn window_start(now: u64) -> u64 {
+    now.saturating_sub(WINDOW_NANOS)
+}
+
+// Drop the entries that have left the window
+fn prune(now: u64) {
+    WITHDRAWAL_WINDOW.with(|window| {
+        let mut window = window.borrow_mut();
+        while let Some((id, usage)) = window.first_key_value() {
+            if usage.at > window_start(now) {
+                break;
+            }
+            window.remove(&id);
+        }
+    });
+}
+
+// Sum withdrawn in the window ending at `now`, by `user` or by everyone
+fn withdrawn(user: Option<Principal>, now: u64) -> u64 {
+    WITHDRAWAL_WINDOW.with(|window| {
+        window
+            .borrow()
+            .iter()
+            .map(|(_, usage)| usage)
+            .filter(|usage| usage.at > window_start(now) && user.is_none_or(|user| usage.user == user))
+            .fold(0u64, |total, usage| total.saturating_add(usage.amount))
+    })
+}
+
+fn exceeded(scope: LimitScope, limit: u64, remaining: u64) -> WalletError {
+    WalletError::WithdrawalLimitExceeded {
+        scope,
+        limit: Nat::from(limit),
+        remaining: Nat::from(remaining),
+    }
+}
+
+// Check an amount against the per-transaction minimum and maximum
+pub fn check_amount(amount: Amount) -> Result<Amount, WalletError> {
+    let limits = config::get().limits;
+    amount.at_least(Amount::from_sats(limits.min_withdrawal))?;
+    match limits.max_withdrawal {
+        Some(max) if amount.sats() > max => Err(exceeded(LimitScope::PerTransaction, max, max)),
+        _ => Ok(amount),
+    }
+}
+
+// Check `amount` against every limit and count it against the windows.
+// Returns the id to pass to release if the withdrawal does not go through.
+pub fn reserve(user: Principal, amount: Amount) -> Result<u64, WalletError> {
+    check_amount(amount)?;
+    let limits = config::get().limits;
+    let now = runtime::time();
+    prune(now);
+
+    if let Some(limit) = limits.per_user_daily {
+        let remaining = limit.saturating_sub(withdrawn(Some(user), now));
+        if amount.sats() > remaining {
+            return Err(exceeded(LimitScope::UserDaily, limit, remaining));
+        }
+    }
+    if let Some(limit) = limits.global_daily {
+        let remaining = limit.saturating_sub(withdrawn(None, now));
+        if amount.sats() > remaining {
+            return Err(exceeded(LimitScope::GlobalDaily, limit, remaining));
+        }
+    }
+
+    Ok(WITHDRAWAL_WINDOW.with(|window| {
+        let mut window = window.borrow_mut();
+        let id = window.last_key_value().map(|(id, _)| id + 1).unwrap_or(1);
+        window.insert(id, WithdrawalUsage { user, amount: amount.sats(), at: now });
+        id
+    }))
+}
+
+// Give back a reservation whose withdrawal did not happen
+pub fn release(id: u64) {
+    WITHDRAWAL_WINDOW.with(|window| window.borrow_mut().remove(&id));
+}
+
+pub fn allowance(user: Principal) -> WithdrawalAllowance {
+    let limits = config::get().limits;
+    let now = runtime::time();
+    let user_withdrawn = withdrawn(Some(user), now);
+    let user_remaining = limits.per_user_daily.map(|limit| limit.saturating_sub(user_withdrawn));
+    let global_remaining = limits.global_daily.map(|limit| limit.saturating_sub(withdrawn(None, now)));
+
+    let available = [limits.max_withdrawal, user_remaining, global_remaining]
+        .into_iter()
+        .flatten()
+        .min();
+    let window_resets_at = WITHDRAWAL_WINDOW.with(|window| {
+        window
+            .borrow()
+            .iter()
+            .map(|(_, usage)| usage)
+            .find(|usage| usage.user == user && usage.at > window_start(now))
+            .map(|usage| usage.at + WINDOW_NANOS)
+    });
+
+    WithdrawalAllowance {
+        min_withdrawal: limits.min_withdrawal,
+        max_withdrawal: limits.max_withdrawal,
+        user_daily_limit: limits.per_user_daily,
+        user_withdrawn,
+        user_remaining,
+        global_remaining,
+        available,
+        window_resets_at,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{UpgradeArgs, WithdrawalLimits};
+    use crate::testing::principal;
+
+    const HOUR: u64 = 60 * 60 * 1_000_000_000;
+
+    fn set_limits(limits: WithdrawalLimits) {
+        config::update(UpgradeArgs { limits: Some(limits), ..UpgradeArgs::default() }).unwrap();
+    }
+
+    fn sats(amount: u64) -> Amount {
+        Amount::from_sats(amount)
+    }
+
+    #[test]
+    fn user_window_slides_over_24_hours() {
+        let alice = principal(1);
+        set_limits(WithdrawalLimits { per_user_daily: Some(1_000), ..WithdrawalLimits::default() });
+
+        let first_at = runtime::time();
+        reserve(alice, sats(600)).unwrap();
+        assert_eq!(reserve(alice, sats(500)), Err(exceeded(LimitScope::UserDaily, 1_000, 400)));
+
+        runtime::advance(12 * HOUR);
+        reserve(alice, sats(400)).unwrap();
+        let current = allowance(alice);
+        assert_eq!((current.user_withdrawn, current.user_remaining), (1_000, Some(0)));
+        assert_eq!(current.window_resets_at, Some(first_at + WINDOW_NANOS));
+
+        // The first withdrawal leaves the window exactly 24 hours after it was made
+        runtime::advance(12 * HOUR - 1);
+        assert!(reserve(alice, sats(1)).is_err());
+        runtime::advance(1);
+        assert_eq!(allowance(alice).user_remaining, Some(600));
+        reserve(alice, sats(600)).unwrap();
+    }
+
+    #[test]
+    fn released_reservations_do_not_count() {
+        let alice = principal(1);
+        set_limits(WithdrawalLimits { per_user_daily: Some(1_000), ..WithdrawalLimits::default() });
+
+        let usage = reserve(alice, sats(1_000)).unwrap();
+        assert!(reserve(alice, sats(1)).is_err());
+        release(usage);
+        assert_eq!(allowance(alice).user_withdrawn, 0);
+        reserve(alice, sats(1_000)).unwrap();
+    }
+
+    #[test]
+    fn global_window_spans_all_users() {
+        let (alice, bob) = (principal(1), principal(2));
+        set_limits(WithdrawalLimits {
+            per_user_daily: Some(800),
+            global_daily: Some(1_000),
+            ..WithdrawalLimits::default()
+        });
+
+        reserve(alice, sats(700)).unwrap();
+        assert_eq!(reserve(bob, sats(400)), Err(exceeded(LimitScope::GlobalDaily, 1_000, 300)));
+        let current = allowance(bob);
+        assert_eq!((current.user_remaining, current.global_remaining), (Some(800), Some(300)));
+        assert_eq!(current.available, Some(300));
+        reserve(bob, sats(300)).unwrap();
+    }
+
+    #[test]
+    fn amount_must_fit_the_per_transaction_bounds() {
+        set_limits(WithdrawalLimits {
+            min_withdrawal: 100,
+            max_withdrawal: Some(5_000),
+            ..WithdrawalLimits::default()
+        });
+
+        assert!(matches!(check_amount(sats(99)), Err(WalletError::AmountTooLow { .. })));
+        assert_eq!(check_amount(sats(100)), Ok(sats(100)));
+        assert_eq!(check_amount(sats(5_001)), Err(exceeded(LimitScope::PerTransaction, 5_000, 5_000)));
+        assert!(reserve(principal(1), sats(5_001)).is_err());
+        assert_eq!(allowance(principal(1)).user_withdrawn, 0);
+    }
+}
@@ -0,0 +1,201 @@
+// Stable memory layout for the backend canister.
+//
+// Every stable structure lives in its own virtual memory handed out by the
+// MemoryManager, so structures can grow independently without overwriting each
+// other's pages. Memory ids are part of the on-chain data format:
+//
+//   - never renumber or reuse an id, even after a structure is retired
+//   - new structures take the next free id
+//   - bump STORAGE_LAYOUT_VERSION whenever stored data needs a migration
+//
+// | Id | Structure               | Contents                                        |
+// |----|-------------------------|-------------------------------------------------|
+// |  0 | layout header           | StorageLayout (layout version of stored data)   |
+// |  1 | USER_BALANCES           | principal -> virtual balance (satoshis)         |
+// |  2 | USER_DEPOSIT_ADDRESSES  | principal -> Bitcoin testnet deposit address    |
+// |  3 | (retired in v2)         | legacy custodial transaction log                |
+// |  4 | (retired in v2)         | legacy ledger/minter transaction log            |
+// |  5 | JOURNAL                 | unified transaction journal                     |
+// |  6 | USER_TRANSACTIONS       | (principal, journal id) index of each user's    |
+// |    |                         | transactions                                    |
+// |  7 | CUSTODIAL_SUBACCOUNTS   | custodial subaccount -> user it belongs to      |
+// |  8 | AUDIT_LOG               | solvency audit results                          |
+// |  9 | CIRCUIT_BREAKER         | withdrawal circuit breaker state                |
+// | 10 | CREDITED_BLOCKS         | ledger block index -> journal id of its deposit |
+// | 11 | DEPOSIT_SCANNER         | ledger scan cursor                              |
+// | 12 | POOL_FEES               | running total of ledger fees paid by the pool   |
+// | 13 | CONFIG                  | runtime configuration                           |
+// | 14 | ROLES                   | principal -> admin or operator role             |
+// | 15 | ADMIN_LOG               | privileged calls with caller and time           |
+// | 16 | PAUSE_STATE             | per-operation and global pause switches         |
+// | 17 | WITHDRAWAL_WINDOW       | withdrawals of the last 24 hours                |
+// | 18 | WITHDRAWAL_QUEUE        | delayed Bitcoin withdrawals not yet submitted   |
+// | 19 | WITHDRAWAL_QUEUE_IDS    | last id handed out to a queued withdrawal       |
+// | 20 | ADDRESS_ALLOWLISTS      | principal -> withdrawal address allowlist       |
+
+use candid::{CandidType, Deserialize, Principal};
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{
+    DefaultMemoryImpl, Memory as StableMemory, StableBTreeMap, StableCell, StableVec, Storable,
+};
+use serde::Serialize;
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+use crate::amount::Amount;
+use crate::{CustodialTransaction, StorablePrincipal};
+
+pub type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+pub const LAYOUT_MEMORY_ID: MemoryId = MemoryId::new(0);
+pub const USER_BALANCES_MEMORY_ID: MemoryId = MemoryId::new(1);
+pub const USER_DEPOSIT_ADDRESSES_MEMORY_ID: MemoryId = MemoryId::new(2);
+pub const STABLE_TRANSACTIONS_MEMORY_ID: MemoryId = MemoryId::new(3);
+pub const TRANSACTIONS_MEMORY_ID: MemoryId = MemoryId::new(4);
+pub const JOURNAL_MEMORY_ID: MemoryId = MemoryId::new(5);
+pub const USER_TRANSACTIONS_MEMORY_ID: MemoryId = MemoryId::new(6);
+pub const CUSTODIAL_SUBACCOUNTS_MEMORY_ID: MemoryId = MemoryId::new(7);
+pub const AUDIT_LOG_MEMORY_ID: MemoryId = MemoryId::new(8);
+pub const CIRCUIT_BREAKER_MEMORY_ID: MemoryId = MemoryId::new(9);
+pub const CREDITED_BLOCKS_MEMORY_ID: MemoryId = MemoryId::new(10);
+pub const DEPOSIT_SCANNER_MEMORY_ID: MemoryId = MemoryId::new(11);
+pub const POOL_FEES_MEMORY_ID: MemoryId = MemoryId::new(12);
+pub const CONFIG_MEMORY_ID: MemoryId = MemoryId::new(13);
+pub const ROLES_MEMORY_ID: MemoryId = MemoryId::new(14);
+pub const ADMIN_LOG_MEMORY_ID: MemoryId = MemoryId::new(15);
+pub const PAUSE_STATE_MEMORY_ID: MemoryId = MemoryId::new(16);
+pub const WITHDRAWAL_WINDOW_MEMORY_ID: MemoryId = MemoryId::new(17);
+pub const WITHDRAWAL_QUEUE_MEMORY_ID: MemoryId = MemoryId::new(18);
+pub const WITHDRAWAL_QUEUE_IDS_MEMORY_ID: MemoryId = MemoryId::new(19);
+pub const ADDRESS_ALLOWLISTS_MEMORY_ID: MemoryId = MemoryId::new(20);
+
+// Version of the data layout written by this build.
+// 0 = unversioned (written before the layout header existed, same ids as v1)
+// 1 = one virtual memory per structure
+// 2 = legacy transaction logs (ids 3 and 4) merged into the journal
+// 3 = per-user journal index
+// 4 = custodial subaccount registry
+pub const STORAGE_LAYOUT_VERSION: u32 = 4;
+
+// Header stored in LAYOUT_MEMORY_ID. Candid-encoded so fields can be added later.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct StorageLayout {
+    pub version: u32,
+}
+
+impl Storable for StorageLayout {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let bytes = candid::encode_one(self).expect("Failed to encode StorageLayout");
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode StorageLayout")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
+        MemoryManager::init(DefaultMemoryImpl::default())
+    );
+
+    static LAYOUT: RefCell<StableCell<StorageLayout, Memory>> = RefCell::new(
+        StableCell::init(get(LAYOUT_MEMORY_ID), StorageLayout { version: 0 })
+            .expect("Failed to init storage layout header")
+    );
+}
+
+// Get the virtual memory assigned to a structure
+pub fn get(id: MemoryId) -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(id))
+}
+
+pub fn stored_layout_version() -> u32 {
+    LAYOUT.with(|layout| layout.borrow().get().version)
+}
+
+pub fn set_layout_version(version: u32) {
+    LAYOUT.with(|layout| {
+        layout
+            .borrow_mut()
+            .set(StorageLayout { version })
+            .expect("Failed to write storage layout header");
+    });
+}
+
+// Check the stored layout against this build and return the version the stored
+// data was written with. Traps on data from a newer build so a downgrade is
+// rolled back instead of misreading memory.
+pub fn check_layout_version() -> u32 {
+    let stored = stored_layout_version();
+    if stored > STORAGE_LAYOUT_VERSION {
+        ic_cdk::trap(&format!(
+            "Stable memory layout v{} is newer than this build (v{}), refusing to downgrade",
+            stored, STORAGE_LAYOUT_VERSION
+        ));
+    }
+    stored
+}
+
+// Stable data of a canister installed before the memory manager was introduced
+#[derive(Debug, Default)]
+pub struct UnmanagedState {
+    pub custodial_transactions: Vec<CustodialTransaction>,
+    pub balances: Vec<(Principal, Amount)>,
+    pub deposit_addresses: Vec<(Principal, String)>,
+}
+
+// Canisters installed before the memory manager was introduced initialised every
+// structure directly on raw stable memory, all of them at offset 0, so only the
+// structure initialised first survived: either the custodial transaction log or
+// the map shared by USER_BALANCES and USER_DEPOSIT_ADDRESSES. Copy it out before
+// the memory manager claims the memory so it can be migrated.
+//
+// Must run before anything touches MEMORY_MANAGER.
+pub fn take_unmanaged_state() -> UnmanagedState {
+    read_unmanaged_state(DefaultMemoryImpl::default())
+}
+
+pub(crate) fn read_unmanaged_state<M: StableMemory>(raw_memory: M) -> UnmanagedState {
+    if raw_memory.size() == 0 {
+        return UnmanagedState::default();
+    }
+
+    let mut magic = [0u8; 3];
+    raw_memory.read(0, &mut magic);
+    match &magic {
+        b"SVC" => match StableVec::<CustodialTransaction, M>::init(raw_memory) {
+            Ok(legacy) => UnmanagedState {
+                custodial_transactions: legacy.iter().collect(),
+                ..UnmanagedState::default()
+            },
+            Err(e) => {
+                ic_cdk::println!("[UPGRADE] Unreadable legacy transaction log, skipping: {:?}", e);
+                UnmanagedState::default()
+            }
+        },
+        b"BTR" => read_unmanaged_accounts(raw_memory),
+        _ => UnmanagedState::default(),
+    }
+}
+
+// Balances and deposit addresses were kept in one tree keyed by principal. A
+// balance is an 8-byte u64; anything else is an address, which is never that short.
+fn read_unmanaged_accounts<M: StableMemory>(raw_memory: M) -> UnmanagedState {
+    let legacy: StableBTreeMap<StorablePrincipal, Vec<u8>, M> = StableBTreeMap::load(raw_memory);
+    let mut state = UnmanagedState::default();
+    for (user, value) in legacy.iter() {
+        if value.len() == 8 {
+            state.balances.push((user.0, Amount::from_bytes(Cow::Owned(value))));
+            continue;
+        }
+        match String::from_utf8(value) {
+            Ok(address) => state.deposit_addresses.push((user.0, address)),
+            Err(_) => ic_cdk::println!("[UPGRADE] Unreadable legacy entry for {}, skipping", user.0),
+        }
+    }
+    state
+}
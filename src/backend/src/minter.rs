@@ -0,0 +1,68 @@
+// Calls to the ckTestBTC minter.
+//
+// Bitcoin withdrawals reach the minter through the Minter trait, so the
+// withdrawal queue can be unit-tested against a scripted minter. IcMinter is
+// the minter canister from the configuration.
+
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::call::CallResult;
+use serde::Serialize;
+
+use crate::config;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct GetBtcAddressArgs {
+    pub owner: Option<Principal>,
+    pub subaccount: Option<Vec<u8>>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct RetrieveBtcArgs {
+    pub address: String,
+    pub amount: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct RetrieveBtcOk {
+    pub block_index: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub enum RetrieveBtcError {
+    MalformedAddress(String),
+    AlreadyProcessing,
+    AmountTooLow(u64),
+    InsufficientFunds { balance: u64 },
+    TemporarilyUnavailable(String),
+    GenericError { error_message: String, error_code: u64 },
+}
+
+// Bitcoin testnet address the minter watches for deposits to `owner`'s account
+pub async fn get_btc_address(owner: Principal, subaccount: Option<Vec<u8>>) -> Result<String, String> {
+    let minter_canister = config::minter_id();
+    let args = GetBtcAddressArgs { owner: Some(owner), subaccount };
+    let result: CallResult<(String,)> =
+        ic_cdk::call(minter_canister, "get_btc_address", (args,)).await;
+    result
+        .map(|(address,)| address)
+        .map_err(|e| format!("Failed to get deposit address: {:?}", e))
+}
+
+pub trait Minter {
+    // Ask the minter to send `args.amount` satoshis to `args.address`
+    async fn retrieve_btc(&self, args: RetrieveBtcArgs) -> Result<Result<RetrieveBtcOk, RetrieveBtcError>, String>;
+}
+
+// The configured minter canister
+pub struct IcMinter;
+
+impl Minter for IcMinter {
+    async fn retrieve_btc(&self, args: RetrieveBtcArgs) -> Result<Result<RetrieveBtcOk, RetrieveBtcError>, String> {
+        let minter_canister = config::minter_id();
+        let result: CallResult<(Result<RetrieveBtcOk, RetrieveBtcError>,)> =
+            ic_cdk::call(minter_canister, "retrieve_btc", (args,)).await;
+        result
+            .map(|(result,)| result)
+            .map_err(|e| format!("Failed to call retrieve_btc: {:?}", e))
+    }
+}
@@ -0,0 +1,209 @@
+// Pause switches.
+//
+// The operations that move funds can be halted one at a time, or all at once
+// with the global switch, without stopping the canister. The switches live in
+// stable memory so a pause survives upgrades. Operators may pause; only admins
+// may resume.
+//
+// Pausing deposits stops the backend from pulling funds (deposit_to_custody).
+// Transfers users already made into their deposit slot are still credited by
+// notify_deposit and the scanner, so nothing sent is lost.
+//
+// Withdrawals and virtual transfers are also refused while the solvency circuit
+// breaker is tripped (see audit).
+
+use candid::{CandidType, Deserialize, Principal};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableCell, Storable};
+use serde::Serialize;
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+use crate::audit;
+use crate::error::WalletError;
+use crate::memory::{self, Memory};
+use crate::runtime;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operation {
+    Deposit,
+    WithdrawToLedger,  // withdraw_funds, transfer
+    WithdrawToBtc,     // withdraw_testbtc
+    VirtualTransfer,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct PauseSwitch {
+    pub paused: bool,
+    pub reason: Option<String>,
+    pub changed_by: Option<Principal>,
+    pub changed_at: Option<u64>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct PauseState {
+    pub global: PauseSwitch,  // Pauses every operation below
+    pub deposit: PauseSwitch,
+    pub withdraw_to_ledger: PauseSwitch,
+    pub withdraw_to_btc: PauseSwitch,
+    pub virtual_transfer: PauseSwitch,
+}
+
+impl PauseState {
+    fn switch_mut(&mut self, operation: Option<Operation>) -> &mut PauseSwitch {
+        match operation {
+            None => &mut self.global,
+            Some(Operation::Deposit) => &mut self.deposit,
+            Some(Operation::WithdrawToLedger) => &mut self.withdraw_to_ledger,
+            Some(Operation::WithdrawToBtc) => &mut self.withdraw_to_btc,
+            Some(Operation::VirtualTransfer) => &mut self.virtual_transfer,
+        }
+    }
+
+    fn switch(&self, operation: Operation) -> &PauseSwitch {
+        match operation {
+            Operation::Deposit => &self.deposit,
+            Operation::WithdrawToLedger => &self.withdraw_to_ledger,
+            Operation::WithdrawToBtc => &self.withdraw_to_btc,
+            Operation::VirtualTransfer => &self.virtual_transfer,
+        }
+    }
+}
+
+impl Storable for PauseState {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let bytes = candid::encode_one(self).expect("Failed to encode PauseState");
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode PauseState")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    static PAUSE_STATE: RefCell<StableCell<PauseState, Memory>> = RefCell::new(
+        StableCell::init(memory::get(memory::PAUSE_STATE_MEMORY_ID), PauseState::default())
+            .expect("Failed to init pause switches")
+    );
+}
+
+pub fn state() -> PauseState {
+    PAUSE_STATE.with(|state| state.borrow().get().clone())
+}
+
+// Flip one switch, the global one when `operation` is None
+pub fn set(operation: Option<Operation>, paused: bool, reason: Option<String>, by: Principal) -> PauseState {
+    PAUSE_STATE.with(|cell| {
+        let mut cell = cell.borrow_mut();
+        let mut state = cell.get().clone();
+        *state.switch_mut(operation) = PauseSwitch {
+            paused,
+            reason,
+            changed_by: Some(by),
+            changed_at: Some(runtime::time()),
+        };
+        cell.set(state.clone()).expect("Failed to write pause switches");
+        state
+    })
+}
+
+// Refuse `operation` while it, or everything, is paused, and refuse anything
+// but deposits while the circuit breaker is tripped
+pub fn ensure_enabled(operation: Operation) -> Result<(), WalletError> {
+    let state = state();
+    let paused = |switch: &PauseSwitch, scope: &str| WalletError::Paused {
+        operation,
+        reason: match &switch.reason {
+            Some(reason) => format!("{} paused: {}", scope, reason),
+            None => format!("{} paused", scope),
+        },
+    };
+
+    if state.global.paused {
+        return Err(paused(&state.global, "All operations are"));
+    }
+    if state.switch(operation).paused {
+        return Err(paused(state.switch(operation), &format!("{:?} is", operation)));
+    }
+
+    let breaker = audit::circuit_breaker();
+    if breaker.tripped && operation != Operation::Deposit {
+        return Err(WalletError::Paused {
+            operation,
+            reason: format!(
+                "Withdrawals and transfers are paused: solvency audit {} found a reserve deficit",
+                breaker.tripped_by_audit.unwrap_or_default()
+            ),
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::Amount;
+    use crate::balances;
+    use crate::testing::{block_on, principal, MockLedger};
+
+    const ALL: [Operation; 4] = [
+        Operation::Deposit,
+        Operation::WithdrawToLedger,
+        Operation::WithdrawToBtc,
+        Operation::VirtualTransfer,
+    ];
+
+    fn enabled() -> Vec<Operation> {
+        ALL.into_iter().filter(|operation| ensure_enabled(*operation).is_ok()).collect()
+    }
+
+    #[test]
+    fn switches_pause_their_own_operation() {
+        let operator = principal(2);
+        assert_eq!(enabled(), ALL);
+
+        set(Some(Operation::WithdrawToBtc), true, Some("minter upgrade".to_string()), operator);
+        assert_eq!(
+            enabled(),
+            vec![Operation::Deposit, Operation::WithdrawToLedger, Operation::VirtualTransfer]
+        );
+        let result = ensure_enabled(Operation::WithdrawToBtc);
+        assert_eq!(result, Err(WalletError::Paused {
+            operation: Operation::WithdrawToBtc,
+            reason: "WithdrawToBtc is paused: minter upgrade".to_string(),
+        }));
+
+        // The global switch stops everything and is reported first
+        set(None, true, None, operator);
+        assert!(enabled().is_empty());
+        assert!(matches!(
+            ensure_enabled(Operation::WithdrawToBtc),
+            Err(WalletError::Paused { reason, .. }) if reason == "All operations are paused"
+        ));
+
+        // Lifting it leaves the operation's own switch in place
+        set(None, false, None, principal(1));
+        assert_eq!(enabled().len(), 3);
+        assert_eq!(state().global.changed_by, Some(principal(1)));
+    }
+
+    #[test]
+    fn tripped_breaker_pauses_everything_but_deposits() {
+        let ledger = MockLedger::new(10);
+        balances::credit(principal(3), Amount::from_sats(1_000)).unwrap();
+        block_on(audit::run(&ledger)).unwrap();
+        assert!(audit::circuit_breaker().tripped);
+        assert_eq!(enabled(), vec![Operation::Deposit]);
+
+        // Deposits still follow their own switch
+        set(Some(Operation::Deposit), true, None, principal(2));
+        assert!(enabled().is_empty());
+        set(Some(Operation::Deposit), false, None, principal(1));
+
+        audit::resume(principal(1)).unwrap();
+        assert_eq!(enabled(), ALL);
+    }
+}
@@ -0,0 +1,179 @@
+// Solvency reporting.
+//
+// The backend owes every user their virtual balance. What it actually holds is
+// spread over several ledger accounts it owns: the omnibus (default) account
+// that withdraw_funds and transfer pay out of, and one custodial subaccount per
+// user. A reserve check reads all of them from the ledger and compares the
+// total with the sum of virtual balances.
+
+use candid::{CandidType, Deserialize, Int, Nat, Principal};
+use serde::Serialize;
+use std::cell::Cell;
+
+use crate::ledger::Ledger;
+use crate::{balances, custody, runtime, withdrawals, Account, USER_BALANCES};
+
+thread_local! {
+    // Held by a running audit or sweep so they never overlap. A sweep moves funds
+    // between accounts that fetch_buckets reads one after another, so an audit
+    // running alongside it could miss the funds in flight and see a false deficit.
+    pub static POOL_TASK_RUNNING: Cell<bool> = const { Cell::new(false) };
+}
+
+// One ledger account held by the backend
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ReserveBucket {
+    pub account: Account,
+    pub user: Option<Principal>,  // Owner of a custodial subaccount, None for the omnibus account
+    pub balance: Nat,
+}
+
+// Reserve status for backend solvency monitoring
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ReserveStatus {
+    pub total_virtual_balances: u64,  // Sum of all user virtual balances
+    pub backend_actual_balance: u64,  // Actual ckTestBTC held by backend
+    pub reserve_ratio: f64,           // actual / virtual (should be >= 1.0)
+    pub is_solvent: bool,             // backend_actual >= total_virtual
+    pub omnibus_balance: Nat,         // Held in the backend's default account
+    pub custodial_balance: Nat,       // Held across all custodial subaccounts
+    pub surplus: u64,                 // backend_actual - total_virtual when solvent
+    pub deficit: u64,                 // total_virtual - backend_actual when insolvent
+    pub buckets: Vec<ReserveBucket>,  // Per-account breakdown, omnibus first
+    pub checked_at: u64,              // Time of the ledger reads
+}
+
+// Read the balance of every ledger account the backend holds. Fails if any
+// balance cannot be read, so a partial view is never reported as the reserve.
+pub async fn fetch_buckets(ledger: &impl Ledger) -> Result<Vec<ReserveBucket>, String> {
+    let backend = runtime::id();
+    let mut accounts = vec![(None, Account { owner: backend, subaccount: None })];
+    for (user, subaccount) in custody::accounts() {
+        accounts.push((Some(user), Account { owner: backend, subaccount: Some(subaccount) }));
+    }
+
+    let mut buckets = Vec::with_capacity(accounts.len());
+    for (user, account) in accounts {
+        let balance = ledger.balance_of(account.clone()).await?;
+        buckets.push(ReserveBucket { account, user, balance });
+    }
+    Ok(buckets)
+}
+
+pub fn total_held(buckets: &[ReserveBucket]) -> Nat {
+    buckets
+        .iter()
+        .fold(Nat::from(0u64), |total, bucket| total + bucket.balance.clone())
+}
+
+// Everything owed to users: their virtual balances plus the queued Bitcoin
+// withdrawals already debited from them but not yet paid out
+pub fn total_virtual_balances() -> u64 {
+    let balances = USER_BALANCES.with(|balances| {
+        balances.borrow().iter().map(|(_, balance)| balance.sats()).sum::<u64>()
+    });
+    balances.saturating_add(withdrawals::total_queued())
+}
+
+fn saturating_u64(amount: &Nat) -> u64 {
+    u64::try_from(&amount.0).unwrap_or(u64::MAX)
+}
+
+// Read the backend's holdings from the ledger and compare them with the
+// virtual balances owed to users
+pub async fn check(ledger: &impl Ledger) -> Result<ReserveStatus, String> {
+    let buckets = fetch_buckets(ledger).await?;
+    let checked_at = runtime::time();
+    let total_virtual = total_virtual_balances();
+
+    let (omnibus, custodial): (Vec<_>, Vec<_>) =
+        buckets.iter().cloned().partition(|bucket| bucket.user.is_none());
+    let backend_actual = saturating_u64(&total_held(&buckets));
+
+    let reserve_ratio = if total_virtual > 0 {
+        backend_actual as f64 / total_virtual as f64
+    } else {
+        1.0
+    };
+
+    Ok(ReserveStatus {
+        total_virtual_balances: total_virtual,
+        backend_actual_balance: backend_actual,
+        reserve_ratio,
+        is_solvent: backend_actual >= total_virtual,
+        omnibus_balance: total_held(&omnibus),
+        custodial_balance: total_held(&custodial),
+        surplus: backend_actual.saturating_sub(total_virtual),
+        deficit: total_virtual.saturating_sub(backend_actual),
+        buckets,
+        checked_at,
+    })
+}
+
+// One user's virtual balance next to the ledger balance of their deposit slot
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ReconciliationEntry {
+    pub user: Principal,
+    pub virtual_balance: u64,
+    pub subaccount_balance: Nat,
+    pub difference: Int,  // subaccount_balance - virtual_balance
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ReconciliationReport {
+    pub entries: Vec<ReconciliationEntry>,  // Users whose two balances differ
+    pub matched_users: u64,                 // Users whose two balances are equal
+    pub omnibus_balance: Nat,
+    pub total_subaccount_balances: Nat,
+    pub total_virtual_balances: u64,
+    pub generated_at: u64,
+}
+
+// Compare every user's virtual balance with their deposit subaccount. With the
+// virtual balance authoritative the two are expected to drift apart (virtual
+// transfers, payouts from the omnibus account, sweeps); the report shows where.
+pub async fn reconcile(ledger: &impl Ledger) -> Result<ReconciliationReport, String> {
+    // Users holding a balance get their subaccount registered so it is read too
+    let holders: Vec<Principal> = USER_BALANCES.with(|balances| {
+        balances.borrow().iter().map(|(user, _)| user.0).collect()
+    });
+    for user in holders {
+        custody::register(user);
+    }
+
+    let buckets = fetch_buckets(ledger).await?;
+    let generated_at = runtime::time();
+
+    let mut entries = Vec::new();
+    let mut matched_users = 0u64;
+    let mut omnibus_balance = Nat::from(0u64);
+    let mut total_subaccount_balances = Nat::from(0u64);
+    for bucket in buckets {
+        let Some(user) = bucket.user else {
+            omnibus_balance = bucket.balance;
+            continue;
+        };
+        total_subaccount_balances += bucket.balance.clone();
+
+        let virtual_balance = balances::get(user).sats();
+        if bucket.balance == virtual_balance {
+            matched_users += 1;
+            continue;
+        }
+        entries.push(ReconciliationEntry {
+            user,
+            virtual_balance,
+            difference: Int::from(bucket.balance.clone()) - Int::from(Nat::from(virtual_balance)),
+            subaccount_balance: bucket.balance,
+        });
+    }
+
+    Ok(ReconciliationReport {
+        entries,
+        matched_users,
+        omnibus_balance,
+        total_subaccount_balances,
+        total_virtual_balances: total_virtual_balances(),
+        generated_at,
+    })
+}
@@ -0,0 +1,75 @@
+// System API the backend's bookkeeping reads: the current time, the backend's
+// own canister id, the caller and the canister's controllers.
+//
+// Native unit tests run without a replica, where these calls trap. Under
+// cfg(test) they read a per-thread clock, a fixed canister id and a caller and
+// controllers set by the test instead; tests move the clock with `advance`.
+
+use candid::Principal;
+
+#[cfg(not(test))]
+pub fn time() -> u64 {
+    ic_cdk::api::time()
+}
+
+#[cfg(not(test))]
+pub fn id() -> Principal {
+    ic_cdk::api::id()
+}
+
+#[cfg(not(test))]
+pub fn caller() -> Principal {
+    ic_cdk::caller()
+}
+
+#[cfg(not(test))]
+pub fn is_controller(principal: &Principal) -> bool {
+    ic_cdk::api::is_controller(principal)
+}
+
+#[cfg(test)]
+pub use self::fake::*;
+
+#[cfg(test)]
+mod fake {
+    use super::Principal;
+    use std::cell::{Cell, RefCell};
+
+    // 2023-11-14, so that windows reaching back a day stay positive
+    const START: u64 = 1_700_000_000_000_000_000;
+
+    thread_local! {
+        static NOW: Cell<u64> = const { Cell::new(START) };
+        static CALLER: Cell<Principal> = const { Cell::new(Principal::anonymous()) };
+        static CONTROLLERS: RefCell<Vec<Principal>> = const { RefCell::new(Vec::new()) };
+    }
+
+    pub fn time() -> u64 {
+        NOW.with(Cell::get)
+    }
+
+    pub fn id() -> Principal {
+        Principal::from_slice(&[0xBA; 10])
+    }
+
+    pub fn caller() -> Principal {
+        CALLER.with(Cell::get)
+    }
+
+    pub fn is_controller(principal: &Principal) -> bool {
+        CONTROLLERS.with(|controllers| controllers.borrow().contains(principal))
+    }
+
+    pub fn advance(nanos: u64) {
+        NOW.with(|now| now.set(now.get() + nanos));
+    }
+
+    // Make the following calls on behalf of `principal`
+    pub fn set_caller(principal: Principal) {
+        CALLER.with(|caller| caller.set(principal));
+    }
+
+    pub fn add_controller(principal: Principal) {
+        CONTROLLERS.with(|controllers| controllers.borrow_mut().push(principal));
+    }
+}
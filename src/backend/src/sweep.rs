@@ -0,0 +1,293 @@
+// Sweeping of custodial subaccounts into the omnibus account.
+//
+// Deposits land in per-user custodial subaccounts and are credited to the
+// owner's virtual balance on arrival (see deposits), but withdraw_funds pays out
+// of the backend's default (omnibus) account. A timer periodically moves every
+// subaccount balance above SWEEP_FEE_MULTIPLE ledger fees into the omnibus
+// account so the pool can actually be paid out from.
+//
+// A sweep moves funds between two accounts of the same pool, but the ledger fee
+// it costs leaves the pool. The owner of the subaccount pays it: the fee is
+// debited from their virtual balance before the transfer and returned if the
+// transfer fails, so what the backend holds and what it owes drop together. An
+// owner whose virtual balance cannot cover the fee has their subaccount
+// reported as a failure and left for a later round.
+//
+// Sweeps and solvency audits exclude each other (see reserves).
+
+use candid::{CandidType, Deserialize, Nat, Principal};
+use serde::Serialize;
+use std::time::Duration;
+
+use crate::amount::Amount;
+use crate::guard::TaskGuard;
+use crate::journal::{self, Counterparty, JournalRecord, TransactionChannel};
+use crate::ledger::{self, IcLedger, Ledger};
+use crate::{
+    balances, custody, fees, format_transfer_error, reserves, runtime, token, Account, TransactionStatus,
+    TransactionType, TransferArgs, TransferError,
+};
+
+const SWEEP_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+// Subaccounts holding this many ledger fees or less are left alone: sweeping
+// them would spend a large share of the balance on the fee
+const SWEEP_FEE_MULTIPLE: u64 = 100;
+
+// A subaccount that could not be swept this round
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct SweepFailure {
+    pub user: Principal,
+    pub subaccount: Vec<u8>,
+    pub error: String,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct SweepReport {
+    pub swept_accounts: u64,
+    pub total_swept: Nat,          // Arrived in the omnibus account, fees excluded
+    pub total_fees: Nat,           // Charged to the owners of the swept subaccounts
+    pub skipped_accounts: u64,     // At or below the threshold
+    pub failures: Vec<SweepFailure>,
+    pub error: Option<String>,     // Set when the ledger fee could not be read; nothing was swept
+    pub started_at: u64,
+    pub finished_at: u64,
+}
+
+// Timers do not survive upgrades; call from both init and post_upgrade
+pub fn start_timer() {
+    ic_cdk_timers::set_timer_interval(SWEEP_INTERVAL, || ic_cdk::spawn(async {
+        sweep(&IcLedger).await;
+    }));
+}
+
+enum Outcome {
+    Swept { amount: u64, fee: u64 },
+    Skipped,
+}
+
+// Sweep every registered custodial subaccount. A subaccount that fails is
+// reported and left for the next round; it does not stop the others.
+// Returns None if another sweep or an audit is still in progress.
+pub async fn sweep(ledger: &impl Ledger) -> Option<SweepReport> {
+    let _running = TaskGuard::new(&reserves::POOL_TASK_RUNNING)?;
+
+    let mut report = SweepReport {
+        started_at: runtime::time(),
+        ..SweepReport::default()
+    };
+
+    let mut fee = match token::fee(ledger).await {
+        Ok(fee) => fee,
+        Err(e) => {
+            ic_cdk::println!("[SWEEP] Sweep skipped: {}", e);
+            report.error = Some(e);
+            report.finished_at = runtime::time();
+            return Some(report);
+        }
+    };
+
+    for (user, subaccount) in custody::accounts() {
+        let mut result = sweep_account(ledger, user, subaccount.clone(), fee).await;
+
+        // The ledger fee changed since it was cached: retry once with the new fee
+        if let Ok(Err(TransferError::BadFee { expected_fee })) = &result {
+            result = match token::update_fee(expected_fee) {
+                Ok(new_fee) => {
+                    fee = new_fee;
+                    sweep_account(ledger, user, subaccount.clone(), fee).await
+                }
+                Err(e) => Err(e),
+            };
+        }
+
+        let result = result.and_then(|result| {
+            result.map_err(|e| format!("Sweep transfer failed: {}", format_transfer_error(&e)))
+        });
+        match result {
+            Ok(Outcome::Swept { amount, fee }) => {
+                report.swept_accounts += 1;
+                report.total_swept += amount;
+                report.total_fees += fee;
+            }
+            Ok(Outcome::Skipped) => report.skipped_accounts += 1,
+            Err(error) => {
+                ic_cdk::println!("[SWEEP] Could not sweep subaccount of {}: {}", user, error);
+                report.failures.push(SweepFailure { user, subaccount, error });
+            }
+        }
+    }
+
+    report.finished_at = runtime::time();
+    ic_cdk::println!(
+        "[SWEEP] Swept {} subaccounts ({} satoshis, {} in fees), {} skipped, {} failed",
+        report.swept_accounts, report.total_swept, report.total_fees,
+        report.skipped_accounts, report.failures.len()
+    );
+    Some(report)
+}
+
+// Sweep one subaccount, charging `fee` to its owner `user`. A ledger rejection
+// is reported as the inner error.
+async fn sweep_account(
+    ledger: &impl Ledger,
+    user: Principal,
+    subaccount: Vec<u8>,
+    fee: u64,
+) -> Result<Result<Outcome, TransferError>, String> {
+    let backend = runtime::id();
+    let from = Account { owner: backend, subaccount: Some(subaccount.clone()) };
+    let to = Account { owner: backend, subaccount: None };
+
+    let balance = ledger::nat_to_u64(&ledger.balance_of(from.clone()).await?)?;
+    if balance <= fee.saturating_mul(SWEEP_FEE_MULTIPLE) {
+        return Ok(Ok(Outcome::Skipped));
+    }
+    let amount = balance - fee;
+
+    let fee_amount = Amount::from_sats(fee);
+    balances::debit(user, fee_amount).map_err(|e| format!("Owner cannot pay the sweep fee: {}", e))?;
+    // Return the fee if the ledger transfer does not happen
+    let refund = || {
+        if let Err(e) = balances::credit(user, fee_amount) {
+            ic_cdk::println!("[SWEEP] Failed to refund the {} satoshi fee to {}: {}", fee, user, e);
+        }
+    };
+
+    let record = |status: TransactionStatus, block_index: Option<Nat>| {
+        journal::record(JournalRecord {
+            tx_type: TransactionType::Sweep,
+            channel: TransactionChannel::Ledger,
+            from: Some(Counterparty::Account(from.clone())),
+            to: Some(Counterparty::Account(to.clone())),
+            amount: Nat::from(amount),
+            virtual_amount: Some(fee),
+            fee: Some(Nat::from(fee)),
+            status,
+            block_index,
+        })
+    };
+
+    let transfer_args = TransferArgs {
+        from_subaccount: Some(subaccount),
+        to: to.clone(),
+        amount: Nat::from(amount),
+        fee: Some(Nat::from(fee)),
+        memo: None,
+        created_at_time: Some(runtime::time()),
+    };
+
+    match ledger.transfer(transfer_args).await {
+        Ok(Ok(block_index)) => {
+            record(TransactionStatus::Confirmed, Some(block_index.clone()));
+            fees::record_paid(fee);
+            ic_cdk::println!(
+                "[SWEEP] Moved {} satoshis of {} into the omnibus account, block {}",
+                amount, user, block_index
+            );
+            Ok(Ok(Outcome::Swept { amount, fee }))
+        }
+        Ok(Err(e)) => {
+            refund();
+            record(TransactionStatus::Failed, None);
+            Ok(Err(e))
+        }
+        Err(e) => {
+            refund();
+            record(TransactionStatus::Failed, None);
+            Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deposits;
+    use crate::testing::{backend_account, block_on, principal, MockLedger};
+
+    fn slot_of(user: Principal) -> Account {
+        backend_account(Some(custody::subaccount_for(user)))
+    }
+
+    // Credit `user` a deposit of `sats` into their subaccount
+    fn deposit(ledger: &MockLedger, user: Principal, sats: u64) {
+        let block = ledger.mint(slot_of(user), sats);
+        deposits::credit(user, block, Amount::from_sats(sats), None, slot_of(user)).unwrap();
+    }
+
+    #[test]
+    fn small_balances_are_left_alone() {
+        let (alice, bob) = (principal(1), principal(2));
+        let ledger = MockLedger::new(10);
+        deposit(&ledger, alice, 1_000);
+        deposit(&ledger, bob, 1_001);
+
+        let report = block_on(sweep(&ledger)).unwrap();
+        assert_eq!((report.swept_accounts, report.skipped_accounts), (1, 1));
+        assert_eq!(ledger.balance(&slot_of(alice)), 1_000);
+        assert_eq!(ledger.balance(&slot_of(bob)), 0);
+        assert_eq!(ledger.balance(&backend_account(None)), 991);
+    }
+
+    #[test]
+    fn owner_pays_the_sweep_fee() {
+        let alice = principal(1);
+        let ledger = MockLedger::new(10);
+        deposit(&ledger, alice, 5_000);
+
+        let report = block_on(sweep(&ledger)).unwrap();
+        assert_eq!(report.total_swept, Nat::from(4_990u64));
+        assert_eq!(report.total_fees, Nat::from(10u64));
+        assert_eq!(balances::get(alice), Amount::from_sats(4_990));
+        assert_eq!(fees::totals().total_paid, 10);
+
+        // The pool still holds exactly what it owes
+        let status = block_on(reserves::check(&ledger)).unwrap();
+        assert_eq!(status.backend_actual_balance, 4_990);
+        assert_eq!(status.total_virtual_balances, 4_990);
+        assert_eq!(status.surplus, 0);
+
+        let entry = journal::latest_for_user(alice, 1, |_| true).remove(0);
+        assert_eq!(entry.tx_type, TransactionType::Sweep);
+        assert_eq!(entry.virtual_amount, Some(10));
+    }
+
+    #[test]
+    fn failed_sweep_refunds_the_fee_and_spares_the_others() {
+        let (alice, bob) = (principal(1), principal(2));
+        let ledger = MockLedger::new(10);
+        deposit(&ledger, alice, 5_000);
+        deposit(&ledger, bob, 5_000);
+        ledger.respond_next(Ok(Err(TransferError::TemporarilyUnavailable)));
+
+        let report = block_on(sweep(&ledger)).unwrap();
+        assert_eq!(report.swept_accounts, 1);
+        assert_eq!(report.failures.len(), 1);
+        let failed = report.failures[0].user;
+        let swept = if failed == alice { bob } else { alice };
+        assert_eq!(balances::get(failed), Amount::from_sats(5_000));
+        assert_eq!(ledger.balance(&slot_of(failed)), 5_000);
+        assert_eq!(balances::get(swept), Amount::from_sats(4_990));
+        assert_eq!(fees::totals().total_paid, 10);
+
+        // The next round picks up the subaccount that failed
+        let report = block_on(sweep(&ledger)).unwrap();
+        assert_eq!((report.swept_accounts, report.skipped_accounts), (1, 1));
+        assert_eq!(ledger.balance(&backend_account(None)), 9_980);
+    }
+
+    #[test]
+    fn owner_without_a_balance_is_not_swept() {
+        let alice = principal(1);
+        let ledger = MockLedger::new(10);
+        deposit(&ledger, alice, 5_000);
+        balances::debit(alice, Amount::from_sats(5_000)).unwrap();
+
+        let report = block_on(sweep(&ledger)).unwrap();
+        assert_eq!(report.swept_accounts, 0);
+        assert_eq!(report.failures.len(), 1);
+        assert!(ledger.transfers.borrow().is_empty());
+        assert_eq!(ledger.balance(&slot_of(alice)), 5_000);
+    }
+}
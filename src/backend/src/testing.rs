@@ -0,0 +1,244 @@
+// Helpers for the native unit tests: an executor for the backend's async
+// functions, a ledger held in memory and a scripted minter.
+
+use candid::{Nat, Principal};
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, VecDeque};
+use std::future::Future;
+use std::pin::{pin, Pin};
+use std::task::{Context, Poll, Waker};
+
+use crate::ledger::{BlockRange, Ledger, LedgerMint, LedgerTransaction, LedgerTransfer, MetadataValue};
+use crate::minter::{Minter, RetrieveBtcArgs, RetrieveBtcError, RetrieveBtcOk};
+use crate::{runtime, Account, TransferArgs, TransferError};
+
+pub fn principal(byte: u8) -> Principal {
+    Principal::from_slice(&[byte; 29])
+}
+
+// Account of the backend itself; `subaccount` None is the omnibus account
+pub fn backend_account(subaccount: Option<Vec<u8>>) -> Account {
+    Account { owner: runtime::id(), subaccount }
+}
+
+pub fn user_account(owner: Principal) -> Account {
+    Account { owner, subaccount: None }
+}
+
+// Drive `future` to completion. The test ledger never waits on anything, so
+// polling in a loop is enough.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    loop {
+        if let Poll::Ready(output) = poll_once(future.as_mut()) {
+            return output;
+        }
+    }
+}
+
+// Poll `future` once, e.g. to run an update call up to its first await
+pub fn poll_once<F: Future + ?Sized>(future: Pin<&mut F>) -> Poll<F::Output> {
+    future.poll(&mut Context::from_waker(Waker::noop()))
+}
+
+// Pending on the first poll, like an inter-canister call in flight
+struct YieldOnce(bool);
+
+impl Future for YieldOnce {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<()> {
+        if self.0 {
+            Poll::Ready(())
+        } else {
+            self.0 = true;
+            Poll::Pending
+        }
+    }
+}
+
+type TransferResponse = Result<Result<Nat, TransferError>, String>;
+type BalanceHook = Box<dyn FnMut(&MockLedger, &Account)>;
+
+// ICRC-1 ledger held in memory. Transfers check the fee and the balance, move
+// funds and append a block like the real ledger does.
+pub struct MockLedger {
+    pub fee: Cell<u64>,
+    balances: RefCell<BTreeMap<(Principal, Vec<u8>), u64>>,
+    blocks: RefCell<Vec<LedgerTransaction>>,
+    // Every transfer the backend asked for, accepted or not
+    pub transfers: RefCell<Vec<TransferArgs>>,
+    // Answers for the next transfers, given instead of executing them
+    responses: RefCell<VecDeque<TransferResponse>>,
+    // Transfers stay in flight for one poll so calls can interleave
+    pub yield_on_transfer: Cell<bool>,
+    // Oldest block get_transactions still returns, as if the ones before it
+    // had been archived somewhere the backend cannot reach
+    pub first_served_block: Cell<u64>,
+    // Error every balance query answers with while set
+    pub balance_error: RefCell<Option<String>>,
+    // Runs after every balance query, e.g. to credit a deposit mid-audit
+    balance_hook: RefCell<Option<BalanceHook>>,
+}
+
+impl MockLedger {
+    pub fn new(fee: u64) -> Self {
+        MockLedger {
+            fee: Cell::new(fee),
+            balances: RefCell::default(),
+            blocks: RefCell::default(),
+            transfers: RefCell::default(),
+            responses: RefCell::default(),
+            yield_on_transfer: Cell::new(false),
+            first_served_block: Cell::new(0),
+            balance_error: RefCell::default(),
+            balance_hook: RefCell::default(),
+        }
+    }
+
+    fn key(account: &Account) -> (Principal, Vec<u8>) {
+        (account.owner, account.subaccount.clone().unwrap_or_else(|| vec![0; 32]))
+    }
+
+    pub fn balance(&self, account: &Account) -> u64 {
+        self.balances.borrow().get(&Self::key(account)).copied().unwrap_or(0)
+    }
+
+    fn add(&self, account: &Account, sats: u64) {
+        *self.balances.borrow_mut().entry(Self::key(account)).or_default() += sats;
+    }
+
+    fn push_block(&self, block: LedgerTransaction) -> u64 {
+        let mut blocks = self.blocks.borrow_mut();
+        blocks.push(block);
+        blocks.len() as u64 - 1
+    }
+
+    pub fn mint(&self, to: Account, sats: u64) -> u64 {
+        self.add(&to, sats);
+        self.push_block(LedgerTransaction {
+            kind: "mint".to_string(),
+            mint: Some(LedgerMint { to, amount: Nat::from(sats) }),
+            transfer: None,
+            timestamp: runtime::time(),
+        })
+    }
+
+    // Transfer between two accounts, whoever owns them
+    pub fn send(&self, from: Account, to: Account, sats: u64) -> u64 {
+        let fee = self.fee.get();
+        let balance = self.balance(&from);
+        assert!(balance >= sats + fee, "{:?} cannot send {} satoshis", from, sats);
+        self.balances.borrow_mut().insert(Self::key(&from), balance - sats - fee);
+        self.add(&to, sats);
+        self.push_block(LedgerTransaction {
+            kind: "transfer".to_string(),
+            mint: None,
+            transfer: Some(LedgerTransfer {
+                from,
+                to,
+                spender: None,
+                amount: Nat::from(sats),
+                fee: Some(Nat::from(fee)),
+            }),
+            timestamp: runtime::time(),
+        })
+    }
+
+    // Call `hook` with each account whose balance was just read
+    pub fn after_balance_read(&self, hook: impl FnMut(&MockLedger, &Account) + 'static) {
+        *self.balance_hook.borrow_mut() = Some(Box::new(hook));
+    }
+
+    // Answer the next transfer with `response` without executing it
+    pub fn respond_next(&self, response: TransferResponse) {
+        self.responses.borrow_mut().push_back(response);
+    }
+}
+
+impl Ledger for MockLedger {
+    async fn balance_of(&self, account: Account) -> Result<Nat, String> {
+        if let Some(e) = self.balance_error.borrow().clone() {
+            return Err(e);
+        }
+        let balance = self.balance(&account);
+        let hook = self.balance_hook.borrow_mut().take();
+        if let Some(mut hook) = hook {
+            hook(self, &account);
+            *self.balance_hook.borrow_mut() = Some(hook);
+        }
+        Ok(Nat::from(balance))
+    }
+
+    async fn fee(&self) -> Result<u64, String> {
+        Ok(self.fee.get())
+    }
+
+    async fn decimals(&self) -> Result<u8, String> {
+        Ok(8)
+    }
+
+    async fn metadata(&self) -> Result<Vec<(String, MetadataValue)>, String> {
+        Ok(Vec::new())
+    }
+
+    async fn transfer(&self, args: TransferArgs) -> Result<Result<Nat, TransferError>, String> {
+        if self.yield_on_transfer.get() {
+            YieldOnce(false).await;
+        }
+        self.transfers.borrow_mut().push(args.clone());
+        if let Some(response) = self.responses.borrow_mut().pop_front() {
+            return response;
+        }
+
+        let fee = self.fee.get();
+        if args.fee.as_ref().is_some_and(|paid| *paid != fee) {
+            return Ok(Err(TransferError::BadFee { expected_fee: Nat::from(fee) }));
+        }
+        let from = backend_account(args.from_subaccount);
+        let amount = u64::try_from(&args.amount.0).unwrap();
+        let balance = self.balance(&from);
+        if balance < amount + fee {
+            return Ok(Err(TransferError::InsufficientFunds { balance: Nat::from(balance) }));
+        }
+        Ok(Ok(Nat::from(self.send(from, args.to, amount))))
+    }
+
+    async fn get_transactions(&self, start: u64, length: u64) -> Result<BlockRange, String> {
+        let blocks = self.blocks.borrow();
+        let end = start.saturating_add(length).min(blocks.len() as u64);
+        let start = start.max(self.first_served_block.get()).min(end);
+        Ok(BlockRange {
+            blocks: (start..end).map(|index| (index, blocks[index as usize].clone())).collect(),
+        })
+    }
+}
+
+type RetrieveBtcResponse = Result<Result<RetrieveBtcOk, RetrieveBtcError>, String>;
+
+// ckTestBTC minter that accepts every withdrawal unless told otherwise
+pub struct MockMinter {
+    // Every withdrawal the backend asked for, accepted or not
+    pub requests: RefCell<Vec<RetrieveBtcArgs>>,
+    responses: RefCell<VecDeque<RetrieveBtcResponse>>,
+}
+
+impl MockMinter {
+    pub fn new() -> Self {
+        MockMinter { requests: RefCell::default(), responses: RefCell::default() }
+    }
+
+    // Answer the next withdrawal with `response`
+    pub fn respond_next(&self, response: RetrieveBtcResponse) {
+        self.responses.borrow_mut().push_back(response);
+    }
+}
+
+impl Minter for MockMinter {
+    async fn retrieve_btc(&self, args: RetrieveBtcArgs) -> RetrieveBtcResponse {
+        let mut requests = self.requests.borrow_mut();
+        requests.push(args);
+        let block_index = requests.len() as u64 - 1;
+        self.responses.borrow_mut().pop_front().unwrap_or(Ok(Ok(RetrieveBtcOk { block_index })))
+    }
+}
@@ -0,0 +1,94 @@
+// Cached ckTestBTC token parameters.
+//
+// The ledger's fee, decimals and metadata are read once the canister starts and
+// refreshed on a timer, so transfers do not ask the ledger for the fee each
+// time. The cache lives on the heap and is rebuilt from the ledger after every
+// upgrade. A transfer the ledger rejects with BadFee updates the cached fee and
+// is retried once with the fee the ledger asked for.
+
+use candid::{CandidType, Deserialize, Nat};
+use serde::Serialize;
+use std::cell::RefCell;
+use std::time::Duration;
+
+use crate::ledger::{self, IcLedger, Ledger, MetadataValue};
+use crate::config;
+use crate::runtime;
+
+const REFRESH_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct TokenInfo {
+    pub fee: u64,                                  // Transfer fee in satoshis
+    pub decimals: u8,
+    pub metadata: Vec<(String, MetadataValue)>,    // icrc1_metadata as served by the ledger
+    pub refreshed_at: u64,
+}
+
+thread_local! {
+    static TOKEN_INFO: RefCell<Option<TokenInfo>> = const { RefCell::new(None) };
+}
+
+// Timers do not survive upgrades; call from both init and post_upgrade
+pub fn start_timer() {
+    let refresh_logged = || ic_cdk::spawn(async {
+        if let Err(e) = refresh(&IcLedger).await {
+            ic_cdk::println!("[TOKEN] Could not refresh token info: {}", e);
+        }
+    });
+    ic_cdk_timers::set_timer(Duration::ZERO, refresh_logged);
+    ic_cdk_timers::set_timer_interval(REFRESH_INTERVAL, refresh_logged);
+}
+
+// Read fee, decimals and metadata from the ledger and replace the cache
+pub async fn refresh(ledger: &impl Ledger) -> Result<TokenInfo, String> {
+    let fee = ledger.fee().await?;
+    let decimals = ledger.decimals().await?;
+    let metadata = ledger.metadata().await?;
+
+    let info = TokenInfo { fee, decimals, metadata, refreshed_at: runtime::time() };
+    TOKEN_INFO.with(|cache| *cache.borrow_mut() = Some(info.clone()));
+    Ok(info)
+}
+
+pub fn cached() -> Option<TokenInfo> {
+    TOKEN_INFO.with(|cache| cache.borrow().clone())
+}
+
+// Drop the cache, e.g. after the configured ledger changed; the next fee()
+// reads from the ledger again
+pub fn invalidate() {
+    TOKEN_INFO.with(|cache| *cache.borrow_mut() = None);
+}
+
+// Current transfer fee: the configured override if there is one, otherwise the
+// ledger's, read from the ledger if nothing is cached yet
+pub async fn fee(ledger: &impl Ledger) -> Result<u64, String> {
+    if let Some(fee) = config::get().ledger_fee {
+        return Ok(fee);
+    }
+    match cached() {
+        Some(info) => Ok(info.fee),
+        None => Ok(refresh(ledger).await?.fee),
+    }
+}
+
+// Adopt the fee the ledger asked for in a BadFee rejection and return it. A
+// configured override is not replaced: while it disagrees with the ledger every
+// transfer would be rejected, so this fails until the configuration is fixed.
+pub fn update_fee(expected_fee: &Nat) -> Result<u64, String> {
+    let fee = ledger::nat_to_u64(expected_fee)?;
+    if let Some(configured) = config::get().ledger_fee.filter(|configured| *configured != fee) {
+        return Err(format!(
+            "Configured ledger_fee of {} satoshis does not match the ledger's fee of {} satoshis; update ledger_fee",
+            configured, fee
+        ));
+    }
+    TOKEN_INFO.with(|cache| {
+        if let Some(info) = cache.borrow_mut().as_mut() {
+            info.fee = fee;
+        }
+    });
+    ic_cdk::println!("[TOKEN] Ledger fee changed to {} satoshis", fee);
+    Ok(fee)
+}
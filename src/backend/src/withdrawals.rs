@@ -0,0 +1,374 @@
+// Bitcoin withdrawals through the minter.
+//
+// withdraw_testbtc debits the amount from the user's virtual balance and asks
+// the minter to pay it out to a Bitcoin address; the debit is returned if the
+// minter refuses. With a withdrawal delay configured the request is not sent
+// right away: it is queued with the time it becomes due, and until then the
+// user can cancel it and get the amount back. A timer submits the queued
+// requests that have come due, unless Bitcoin withdrawals are paused, in which
+// case they wait for the next tick after the pause is lifted.
+//
+// The queue lives in stable memory. A request leaves the queue before it is
+// sent, so a request is submitted at most once even if the timer tick traps.
+//
+// Only the mock minter is supported. The real ckTestBTC minter pays out
+// ckTestBTC that the caller has first moved to the minter's withdrawal account,
+// and burns it; the backend calls retrieve_btc without that transfer, so the
+// real minter refuses every request for lack of funds. The mock minter accepts
+// the request as is.
+
+use candid::{CandidType, Deserialize, Nat, Principal};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, StableCell, Storable};
+use serde::Serialize;
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::time::Duration;
+
+use crate::amount::Amount;
+use crate::error::WalletError;
+use crate::guard::TaskGuard;
+use crate::journal::{self, Counterparty, JournalRecord, TransactionChannel};
+use crate::memory::{self, Memory};
+use crate::minter::{self, IcMinter, Minter};
+use crate::runtime;
+use crate::pause::{self, Operation};
+use crate::{balances, limits, TransactionStatus, TransactionType};
+
+const QUEUE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct QueuedWithdrawal {
+    pub id: u64,
+    pub user: Principal,
+    pub address: String,
+    pub amount: u64,          // Debited from the user's virtual balance when queued
+    pub requested_at: u64,
+    pub execute_after: u64,   // Submitted to the minter once this time has passed
+    pub limit_usage: u64,     // Reservation against the withdrawal limits
+}
+
+// What withdraw_testbtc did with a request
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub enum BtcWithdrawal {
+    Submitted { block_index: u64 },  // Minter's block index of the withdrawal
+    Queued(QueuedWithdrawal),
+}
+
+impl Storable for QueuedWithdrawal {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let bytes = candid::encode_one(self).expect("Failed to encode QueuedWithdrawal");
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode QueuedWithdrawal")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    // Queued withdrawals by id, ids assigned sequentially so they are ordered by
+    // request time
+    static QUEUE: RefCell<StableBTreeMap<u64, QueuedWithdrawal, Memory>> = RefCell::new(
+        StableBTreeMap::init(memory::get(memory::WITHDRAWAL_QUEUE_MEMORY_ID))
+    );
+
+    // Last id handed out. Kept apart from the queue so an id is never reused
+    // after the entries holding it have left.
+    static LAST_ID: RefCell<StableCell<u64, Memory>> = RefCell::new(
+        StableCell::init(memory::get(memory::WITHDRAWAL_QUEUE_IDS_MEMORY_ID), 0)
+            .expect("Failed to init withdrawal queue ids")
+    );
+
+    // Set while due withdrawals are waiting on the minter so timer ticks don't overlap
+    static QUEUE_RUNNING: Cell<bool> = const { Cell::new(false) };
+}
+
+// Timers do not survive upgrades; call from both init and post_upgrade
+pub fn start_timer() {
+    ic_cdk_timers::set_timer_interval(QUEUE_INTERVAL, || ic_cdk::spawn(async {
+        process_due(&IcMinter).await;
+    }));
+}
+
+// Withdraw `amount` of `user`'s virtual balance to `address`, holding it for
+// `delay` first when one is given. `limit_usage` is the limits reservation
+// made for the request; it is released if the withdrawal does not happen.
+pub async fn request(
+    minter: &impl Minter,
+    user: Principal,
+    address: String,
+    amount: Amount,
+    limit_usage: u64,
+    delay: Option<Duration>,
+) -> Result<BtcWithdrawal, WalletError> {
+    if let Err(e) = balances::debit(user, amount) {
+        limits::release(limit_usage);
+        return Err(e);
+    }
+
+    let Some(delay) = delay else {
+        let block_index = submit(minter, user, address, amount, limit_usage).await?;
+        return Ok(BtcWithdrawal::Submitted { block_index });
+    };
+
+    let now = runtime::time();
+    let id = LAST_ID.with(|cell| {
+        let mut cell = cell.borrow_mut();
+        let id = cell.get() + 1;
+        cell.set(id).expect("Failed to write withdrawal queue ids");
+        id
+    });
+    let queued = QueuedWithdrawal {
+        id,
+        user,
+        address,
+        amount: amount.sats(),
+        requested_at: now,
+        execute_after: now.saturating_add(u64::try_from(delay.as_nanos()).unwrap_or(u64::MAX)),
+        limit_usage,
+    };
+    QUEUE.with(|queue| queue.borrow_mut().insert(id, queued.clone()));
+    ic_cdk::println!(
+        "[WITHDRAW] Queued BTC withdrawal {} of {} satoshis for {}, due at {}",
+        id, amount, user, queued.execute_after
+    );
+    Ok(BtcWithdrawal::Queued(queued))
+}
+
+// Cancel one of `user`'s queued withdrawals before it is due and return the
+// amount to their virtual balance
+pub fn cancel(user: Principal, id: u64) -> Result<QueuedWithdrawal, WalletError> {
+    let queued = QUEUE.with(|queue| {
+        let mut queue = queue.borrow_mut();
+        let queued = match queue.get(&id) {
+            Some(queued) if queued.user == user => queued,
+            _ => return Err(WalletError::InvalidRequest(format!("No queued withdrawal {}", id))),
+        };
+        if runtime::time() >= queued.execute_after {
+            return Err(WalletError::InvalidRequest(format!(
+                "Withdrawal {} is due and can no longer be cancelled",
+                id
+            )));
+        }
+        queue.remove(&id);
+        Ok(queued)
+    })?;
+
+    refund(&queued.user, Amount::from_sats(queued.amount));
+    limits::release(queued.limit_usage);
+    ic_cdk::println!("[WITHDRAW] BTC withdrawal {} cancelled by {}", id, user);
+    Ok(queued)
+}
+
+// `user`'s queued withdrawals, oldest first
+pub fn queued_for(user: Principal) -> Vec<QueuedWithdrawal> {
+    QUEUE.with(|queue| {
+        queue.borrow().iter().map(|(_, queued)| queued).filter(|queued| queued.user == user).collect()
+    })
+}
+
+// Satoshis debited from users for withdrawals that have not been submitted yet.
+// The backend still holds them, so they count as owed in reserve checks.
+pub fn total_queued() -> u64 {
+    QUEUE.with(|queue| {
+        queue.borrow().iter().fold(0u64, |total, (_, queued)| total.saturating_add(queued.amount))
+    })
+}
+
+// Submit every queued withdrawal that has come due. A submission that fails is
+// refunded and dropped; it does not stop the others.
+async fn process_due(minter: &impl Minter) {
+    let Some(_running) = TaskGuard::new(&QUEUE_RUNNING) else {
+        return;
+    };
+    if let Err(e) = pause::ensure_enabled(Operation::WithdrawToBtc) {
+        ic_cdk::println!("[WITHDRAW] Queued BTC withdrawals held: {}", e);
+        return;
+    }
+
+    let now = runtime::time();
+    let due: Vec<QueuedWithdrawal> = QUEUE.with(|queue| {
+        queue.borrow().iter().map(|(_, queued)| queued).filter(|queued| queued.execute_after <= now).collect()
+    });
+
+    for queued in due {
+        QUEUE.with(|queue| queue.borrow_mut().remove(&queued.id));
+        let amount = Amount::from_sats(queued.amount);
+        match submit(minter, queued.user, queued.address, amount, queued.limit_usage).await {
+            Ok(block_index) => ic_cdk::println!(
+                "[WITHDRAW] Queued BTC withdrawal {} submitted, block index: {}",
+                queued.id, block_index
+            ),
+            Err(e) => ic_cdk::println!("[WITHDRAW] Queued BTC withdrawal {} failed: {}", queued.id, e),
+        }
+    }
+}
+
+fn refund(user: &Principal, amount: Amount) {
+    match balances::credit(*user, amount) {
+        Ok(balance) => ic_cdk::println!("[WITHDRAW] Refunded {} satoshis, balance: {}", amount, balance),
+        Err(e) => ic_cdk::println!("[WITHDRAW] Failed to refund {} satoshis to {}: {}", amount, user, e),
+    }
+}
+
+// Ask the minter to pay out an amount already debited from `user`. Returns
+// the minter's block index; on failure the debit and the limit reservation
+// are given back. No ckTestBTC is moved to the minter first, which only the
+// mock minter accepts.
+async fn submit(
+    minter: &impl Minter,
+    user: Principal,
+    address: String,
+    amount: Amount,
+    limit_usage: u64,
+) -> Result<u64, WalletError> {
+    let args = minter::RetrieveBtcArgs {
+        address: address.clone(),
+        amount: amount.sats(),
+    };
+
+    let record = |status: TransactionStatus, block_index: Option<u64>| {
+        journal::record(JournalRecord {
+            tx_type: TransactionType::Withdraw,
+            channel: TransactionChannel::Bitcoin,
+            from: Some(Counterparty::Principal(user)),
+            to: Some(Counterparty::BtcAddress(address.clone())),
+            amount: Nat::from(amount),
+            virtual_amount: Some(amount.sats()),
+            fee: None,
+            status,
+            block_index: block_index.map(Nat::from),
+        })
+    };
+
+    match minter.retrieve_btc(args).await {
+        Ok(Ok(retrieve_ok)) => {
+            record(TransactionStatus::Pending, Some(retrieve_ok.block_index));
+            Ok(retrieve_ok.block_index)
+        }
+        Ok(Err(e)) => {
+            refund(&user, amount);
+            limits::release(limit_usage);
+            record(TransactionStatus::Failed, None);
+            Err(e.into())
+        }
+        Err(e) => {
+            refund(&user, amount);
+            limits::release(limit_usage);
+            Err(WalletError::MinterUnavailable(e))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{self, UpgradeArgs, WithdrawalLimits};
+    use crate::minter::RetrieveBtcError;
+    use crate::testing::{block_on, principal, MockMinter};
+
+    const ADDRESS: &str = "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx";
+    const HOUR: Duration = Duration::from_secs(60 * 60);
+
+    // Give `user` a virtual balance and a daily limit to reserve against
+    fn funded(user: Principal, sats: u64) {
+        balances::credit(user, Amount::from_sats(sats)).unwrap();
+        let limits = WithdrawalLimits { per_user_daily: Some(10_000), ..WithdrawalLimits::default() };
+        config::update(UpgradeArgs { limits: Some(limits), ..UpgradeArgs::default() }).unwrap();
+    }
+
+    fn withdraw(minter: &MockMinter, user: Principal, sats: u64, delay: Option<Duration>) -> Result<BtcWithdrawal, WalletError> {
+        let amount = Amount::from_sats(sats);
+        let usage = limits::reserve(user, amount)?;
+        block_on(request(minter, user, ADDRESS.to_string(), amount, usage, delay))
+    }
+
+    fn latest_status(user: Principal) -> TransactionStatus {
+        journal::latest_for_user(user, 1, |_| true).remove(0).status
+    }
+
+    #[test]
+    fn minter_refusal_is_refunded() {
+        let alice = principal(1);
+        let minter = MockMinter::new();
+        funded(alice, 1_000);
+
+        minter.respond_next(Ok(Err(RetrieveBtcError::GenericError {
+            error_message: "no UTXOs".to_string(),
+            error_code: 3,
+        })));
+        let result = withdraw(&minter, alice, 400, None);
+        assert!(matches!(result, Err(WalletError::MinterRejected(_))));
+        assert_eq!(balances::get(alice), Amount::from_sats(1_000));
+        assert_eq!(limits::allowance(alice).user_withdrawn, 0);
+        assert_eq!(latest_status(alice), TransactionStatus::Failed);
+
+        minter.respond_next(Err("Failed to call retrieve_btc: canister stopped".to_string()));
+        let result = withdraw(&minter, alice, 400, None);
+        assert!(matches!(result, Err(WalletError::MinterUnavailable(_))));
+        assert_eq!(balances::get(alice), Amount::from_sats(1_000));
+        assert_eq!(limits::allowance(alice).user_withdrawn, 0);
+
+        let result = withdraw(&minter, alice, 400, None).unwrap();
+        assert!(matches!(result, BtcWithdrawal::Submitted { block_index: 2 }));
+        assert_eq!(balances::get(alice), Amount::from_sats(600));
+        assert_eq!(limits::allowance(alice).user_withdrawn, 400);
+        assert_eq!(latest_status(alice), TransactionStatus::Pending);
+    }
+
+    #[test]
+    fn cancelling_refunds_and_releases_the_limit() {
+        let (alice, bob) = (principal(1), principal(2));
+        let minter = MockMinter::new();
+        funded(alice, 1_000);
+
+        let BtcWithdrawal::Queued(queued) = withdraw(&minter, alice, 400, Some(HOUR)).unwrap() else {
+            panic!("withdrawal with a delay was not queued");
+        };
+        assert_eq!(balances::get(alice), Amount::from_sats(600));
+        assert_eq!(total_queued(), 400);
+        assert_eq!(limits::allowance(alice).user_withdrawn, 400);
+
+        assert!(matches!(cancel(bob, queued.id), Err(WalletError::InvalidRequest(_))));
+        cancel(alice, queued.id).unwrap();
+        assert_eq!(balances::get(alice), Amount::from_sats(1_000));
+        assert_eq!(limits::allowance(alice).user_withdrawn, 0);
+        assert!(queued_for(alice).is_empty());
+        assert!(matches!(cancel(alice, queued.id), Err(WalletError::InvalidRequest(_))));
+        assert!(minter.requests.borrow().is_empty());
+    }
+
+    #[test]
+    fn due_withdrawals_are_sent_once_and_no_longer_cancellable() {
+        let alice = principal(1);
+        let minter = MockMinter::new();
+        funded(alice, 1_000);
+        let BtcWithdrawal::Queued(queued) = withdraw(&minter, alice, 400, Some(HOUR)).unwrap() else {
+            panic!("withdrawal with a delay was not queued");
+        };
+
+        // Not due yet: the timer leaves it alone
+        block_on(process_due(&minter));
+        assert_eq!(queued_for(alice).len(), 1);
+
+        runtime::advance(HOUR.as_nanos() as u64);
+        assert!(matches!(cancel(alice, queued.id), Err(WalletError::InvalidRequest(_))));
+
+        // Held while Bitcoin withdrawals are paused
+        pause::set(Some(Operation::WithdrawToBtc), true, None, principal(9));
+        block_on(process_due(&minter));
+        assert_eq!(total_queued(), 400);
+        pause::set(Some(Operation::WithdrawToBtc), false, None, principal(9));
+
+        block_on(process_due(&minter));
+        block_on(process_due(&minter));
+        assert_eq!(minter.requests.borrow().len(), 1);
+        assert_eq!(minter.requests.borrow()[0].amount, 400);
+        assert_eq!(total_queued(), 0);
+        assert_eq!(balances::get(alice), Amount::from_sats(600));
+        assert_eq!(limits::allowance(alice).user_withdrawn, 400);
+    }
+}
@@ -0,0 +1,56 @@
+// Base58Check decoding.
+
+use sha2::{Digest, Sha256};
+
+use crate::AddressError;
+
+const ALPHABET: &[u8; 58] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+
+// Longest string a 25-byte address encodes to
+const MAX_LENGTH: usize = 35;
+
+fn decode(input: &str) -> Result<Vec<u8>, AddressError> {
+    // Base-256 digits of the number, least significant first
+    let mut digits: Vec<u8> = Vec::with_capacity(input.len());
+    for c in input.chars() {
+        let mut carry = ALPHABET
+            .iter()
+            .position(|&symbol| symbol as char == c)
+            .ok_or(AddressError::InvalidCharacter(c))? as u32;
+        for digit in digits.iter_mut() {
+            carry += *digit as u32 * 58;
+            *digit = carry as u8;
+            carry >>= 8;
+        }
+        while carry > 0 {
+            digits.push(carry as u8);
+            carry >>= 8;
+        }
+    }
+
+    // Each leading '1' stands for a leading zero byte
+    let zeros = input.bytes().take_while(|&b| b == ALPHABET[0]).count();
+    let mut bytes = vec![0u8; zeros];
+    bytes.extend(digits.iter().rev());
+    Ok(bytes)
+}
+
+pub(crate) fn double_sha256(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(Sha256::digest(data)).into()
+}
+
+// Decode and verify the four checksum bytes; returns the version byte and the payload
+pub(crate) fn decode_check(input: &str) -> Result<(u8, Vec<u8>), AddressError> {
+    if input.is_empty() || input.len() > MAX_LENGTH {
+        return Err(AddressError::InvalidLength(input.len()));
+    }
+    let bytes = decode(input)?;
+    if bytes.len() < 5 {
+        return Err(AddressError::InvalidLength(bytes.len()));
+    }
+    let (data, checksum) = bytes.split_at(bytes.len() - 4);
+    if double_sha256(data)[..4] != *checksum {
+        return Err(AddressError::InvalidChecksum);
+    }
+    Ok((data[0], data[1..].to_vec()))
+}
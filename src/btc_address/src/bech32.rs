@@ -0,0 +1,152 @@
+// Bech32 and Bech32m encoding and decoding of segwit addresses (BIP 173, BIP 350).
+
+use crate::AddressError;
+
+const CHARSET: &[u8; 32] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+const GENERATOR: [u32; 5] = [0x3b6a57b2, 0x26508e6d, 0x1ea119fa, 0x3d4233dd, 0x2a1462b3];
+const MAX_LENGTH: usize = 90;
+const CHECKSUM_LENGTH: usize = 6;
+
+// Value the checksum polynomial leaves behind for each variant
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Variant {
+    Bech32,   // Witness version 0
+    Bech32m,  // Witness versions 1 to 16
+}
+
+impl Variant {
+    fn constant(self) -> u32 {
+        match self {
+            Variant::Bech32 => 1,
+            Variant::Bech32m => 0x2bc830a3,
+        }
+    }
+}
+
+fn polymod(values: impl IntoIterator<Item = u8>) -> u32 {
+    values.into_iter().fold(1u32, |checksum, value| {
+        let top = checksum >> 25;
+        let checksum = ((checksum & 0x1ffffff) << 5) ^ value as u32;
+        GENERATOR
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| (top >> i) & 1 == 1)
+            .fold(checksum, |checksum, (_, generator)| checksum ^ generator)
+    })
+}
+
+fn expand_hrp(hrp: &str) -> impl Iterator<Item = u8> + '_ {
+    hrp.bytes()
+        .map(|b| b >> 5)
+        .chain(std::iter::once(0))
+        .chain(hrp.bytes().map(|b| b & 31))
+}
+
+// Regroup `data` from `from`-bit into `to`-bit values. Encoding pads the last
+// value with zero bits; decoding does not pad, so leftover bits must be fewer
+// than `from` and all zero.
+pub(crate) fn convert_bits(data: &[u8], from: u32, to: u32, pad: bool) -> Option<Vec<u8>> {
+    let mut accumulator = 0u32;
+    let mut bits = 0u32;
+    let mut result = Vec::with_capacity(data.len() * from as usize / to as usize + 1);
+    let max_value = (1u32 << to) - 1;
+    for &value in data {
+        if (value as u32) >> from != 0 {
+            return None;
+        }
+        accumulator = (accumulator << from) | value as u32;
+        bits += from;
+        while bits >= to {
+            bits -= to;
+            result.push(((accumulator >> bits) & max_value) as u8);
+        }
+    }
+    if pad {
+        if bits > 0 {
+            result.push(((accumulator << (to - bits)) & max_value) as u8);
+        }
+    } else if bits >= from || ((accumulator << (to - bits)) & max_value) != 0 {
+        return None;
+    }
+    Some(result)
+}
+
+// Split a bech32 string into its lowercase prefix and 5-bit data values and
+// return the checksum variant it verifies under
+fn decode(input: &str) -> Result<(String, Vec<u8>, Variant), AddressError> {
+    if input.len() > MAX_LENGTH {
+        return Err(AddressError::InvalidLength(input.len()));
+    }
+    if input.chars().any(|c| c.is_ascii_lowercase()) && input.chars().any(|c| c.is_ascii_uppercase()) {
+        return Err(AddressError::MixedCase);
+    }
+    let input = input.to_ascii_lowercase();
+    let separator = input.rfind('1').ok_or(AddressError::InvalidLength(input.len()))?;
+    let (hrp, data) = (&input[..separator], &input[separator + 1..]);
+    if hrp.is_empty() || data.len() < CHECKSUM_LENGTH {
+        return Err(AddressError::InvalidLength(input.len()));
+    }
+    if let Some(c) = hrp.chars().find(|c| !(33..=126).contains(&(*c as u32))) {
+        return Err(AddressError::InvalidCharacter(c));
+    }
+
+    let values = data
+        .chars()
+        .map(|c| {
+            CHARSET
+                .iter()
+                .position(|&symbol| symbol as char == c)
+                .map(|value| value as u8)
+                .ok_or(AddressError::InvalidCharacter(c))
+        })
+        .collect::<Result<Vec<u8>, _>>()?;
+
+    let residue = polymod(expand_hrp(hrp).chain(values.iter().copied()));
+    let variant = [Variant::Bech32, Variant::Bech32m]
+        .into_iter()
+        .find(|variant| variant.constant() == residue)
+        .ok_or(AddressError::InvalidChecksum)?;
+    Ok((hrp.to_string(), values[..values.len() - CHECKSUM_LENGTH].to_vec(), variant))
+}
+
+// Decode a segwit address into its prefix, witness version and witness program
+pub(crate) fn decode_segwit(input: &str) -> Result<(String, u8, Vec<u8>), AddressError> {
+    let (hrp, data, variant) = decode(input)?;
+    let (&witness_version, program) = data
+        .split_first()
+        .ok_or_else(|| AddressError::InvalidWitnessProgram("missing witness version".to_string()))?;
+    if witness_version > 16 {
+        return Err(AddressError::InvalidWitnessProgram(format!("witness version {}", witness_version)));
+    }
+    let expected_variant = if witness_version == 0 { Variant::Bech32 } else { Variant::Bech32m };
+    if variant != expected_variant {
+        return Err(AddressError::InvalidChecksum);
+    }
+
+    let program = convert_bits(program, 5, 8, false)
+        .ok_or_else(|| AddressError::InvalidWitnessProgram("invalid padding".to_string()))?;
+    if !(2..=40).contains(&program.len()) {
+        return Err(AddressError::InvalidWitnessProgram(format!("{} bytes", program.len())));
+    }
+    Ok((hrp, witness_version, program))
+}
+
+// Encode a witness program under `hrp`, with the checksum variant its version requires
+pub(crate) fn encode_segwit(hrp: &str, witness_version: u8, program: &[u8]) -> String {
+    let variant = if witness_version == 0 { Variant::Bech32 } else { Variant::Bech32m };
+    let mut values = vec![witness_version];
+    values.extend(convert_bits(program, 8, 5, true).expect("bytes regroup into 5-bit values"));
+
+    let residue = polymod(
+        expand_hrp(hrp)
+            .chain(values.iter().copied())
+            .chain([0u8; CHECKSUM_LENGTH]),
+    ) ^ variant.constant();
+    values.extend((0..CHECKSUM_LENGTH).map(|i| ((residue >> (5 * (5 - i))) & 31) as u8));
+
+    let mut address = String::with_capacity(hrp.len() + 1 + values.len());
+    address.push_str(hrp);
+    address.push('1');
+    address.extend(values.iter().map(|&value| CHARSET[value as usize] as char));
+    address
+}
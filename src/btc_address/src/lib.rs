@@ -0,0 +1,232 @@
+// WARNING: BITCOIN TESTNET4 (TestBTC) ONLY - NO MAINNET BITCOIN
+// Bitcoin address parsing and encoding shared by the backend and the mock minter.
+//
+// An address is accepted only if it fully decodes: Base58Check with a valid
+// checksum and a testnet P2PKH or P2SH version byte, or Bech32/Bech32m (BIP 173,
+// BIP 350) with a `tb` or `bcrt` prefix, the checksum variant its witness
+// version requires and a witness program of the right length. Mainnet
+// addresses decode too, but are refused with their own error so callers can
+// tell a mainnet address from a malformed one.
+//
+// Encoding is limited to what the mock minter hands out: P2WPKH addresses of a
+// compressed public key.
+
+mod base58;
+mod bech32;
+
+use ripemd::Ripemd160;
+use sha2::{Digest, Sha256};
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AddressType {
+    P2pkh,
+    P2sh,
+    P2wpkh,
+    P2wsh,
+    P2tr,
+}
+
+// Base58 addresses use the same version bytes on testnet and regtest and are
+// reported as Testnet
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Network {
+    Testnet,
+    Regtest,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BitcoinAddress {
+    pub address_type: AddressType,
+    pub network: Network,
+    pub payload: Vec<u8>,  // Public key or script hash, or witness program
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AddressError {
+    Mainnet,
+    InvalidCharacter(char),
+    InvalidChecksum,
+    InvalidLength(usize),
+    MixedCase,
+    UnknownVersion(u8),              // Base58 version byte
+    UnknownPrefix(String),           // Bech32 human-readable part
+    UnsupportedWitnessVersion(u8),
+    InvalidWitnessProgram(String),
+}
+
+impl fmt::Display for AddressError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AddressError::Mainnet => write!(f, "mainnet addresses are not accepted, use a testnet address"),
+            AddressError::InvalidCharacter(c) => write!(f, "invalid character {:?}", c),
+            AddressError::InvalidChecksum => write!(f, "invalid checksum"),
+            AddressError::InvalidLength(length) => write!(f, "invalid length {}", length),
+            AddressError::MixedCase => write!(f, "mixed upper and lower case"),
+            AddressError::UnknownVersion(version) => write!(f, "unknown version byte {:#04x}", version),
+            AddressError::UnknownPrefix(hrp) => write!(f, "unknown prefix {:?}", hrp),
+            AddressError::UnsupportedWitnessVersion(version) => {
+                write!(f, "unsupported witness version {}", version)
+            }
+            AddressError::InvalidWitnessProgram(message) => write!(f, "invalid witness program: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for AddressError {}
+
+// Bech32 prefix of each network; `bc` is mainnet
+const MAINNET_HRP: &str = "bc";
+const TESTNET_HRP: &str = "tb";
+const REGTEST_HRP: &str = "bcrt";
+
+// Base58Check version bytes
+const MAINNET_P2PKH: u8 = 0x00;
+const MAINNET_P2SH: u8 = 0x05;
+const TESTNET_P2PKH: u8 = 0x6f;
+const TESTNET_P2SH: u8 = 0xc4;
+
+pub fn parse(address: &str) -> Result<BitcoinAddress, AddressError> {
+    // No Base58 address of a known version starts with a segwit prefix and its
+    // '1' separator, so those are decoded as bech32 and everything else as Base58
+    let lower = address.to_ascii_lowercase();
+    if [MAINNET_HRP, TESTNET_HRP, REGTEST_HRP].iter().any(|hrp| lower.starts_with(&format!("{}1", hrp))) {
+        return parse_segwit(address);
+    }
+    parse_base58(address)
+}
+
+// RIPEMD-160 of the SHA-256 of `data`
+pub fn hash160(data: &[u8]) -> [u8; 20] {
+    Ripemd160::digest(Sha256::digest(data)).into()
+}
+
+// P2WPKH address paying to a 33-byte compressed secp256k1 public key
+pub fn p2wpkh_address(network: Network, compressed_public_key: &[u8; 33]) -> String {
+    let hrp = match network {
+        Network::Testnet => TESTNET_HRP,
+        Network::Regtest => REGTEST_HRP,
+    };
+    bech32::encode_segwit(hrp, 0, &hash160(compressed_public_key))
+}
+
+fn parse_base58(address: &str) -> Result<BitcoinAddress, AddressError> {
+    let (version, payload) = base58::decode_check(address)?;
+    if payload.len() != 20 {
+        return Err(AddressError::InvalidLength(payload.len() + 1));
+    }
+    let address_type = match version {
+        TESTNET_P2PKH => AddressType::P2pkh,
+        TESTNET_P2SH => AddressType::P2sh,
+        MAINNET_P2PKH | MAINNET_P2SH => return Err(AddressError::Mainnet),
+        other => return Err(AddressError::UnknownVersion(other)),
+    };
+    Ok(BitcoinAddress { address_type, network: Network::Testnet, payload })
+}
+
+fn parse_segwit(address: &str) -> Result<BitcoinAddress, AddressError> {
+    let (hrp, witness_version, program) = bech32::decode_segwit(address)?;
+    let network = match hrp.as_str() {
+        TESTNET_HRP => Network::Testnet,
+        REGTEST_HRP => Network::Regtest,
+        MAINNET_HRP => return Err(AddressError::Mainnet),
+        _ => return Err(AddressError::UnknownPrefix(hrp)),
+    };
+    let address_type = match (witness_version, program.len()) {
+        (0, 20) => AddressType::P2wpkh,
+        (0, 32) => AddressType::P2wsh,
+        (0, length) => {
+            return Err(AddressError::InvalidWitnessProgram(format!(
+                "version 0 programs are 20 or 32 bytes, not {}",
+                length
+            )))
+        }
+        (1, 32) => AddressType::P2tr,
+        (version, _) => return Err(AddressError::UnsupportedWitnessVersion(version)),
+    };
+    Ok(BitcoinAddress { address_type, network, payload: program })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parsed_type(address: &str) -> Result<(AddressType, Network), AddressError> {
+        parse(address).map(|parsed| (parsed.address_type, parsed.network))
+    }
+
+    #[test]
+    fn accepts_testnet_addresses() {
+        let cases = [
+            ("mipcBbFg9gMiCh81Kj8tqqdgoZub1ZJRfn", AddressType::P2pkh, Network::Testnet),
+            ("2MzQwSSnBHWHqSAqtTVQ6v47XtaisrJa1Vc", AddressType::P2sh, Network::Testnet),
+            ("tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx", AddressType::P2wpkh, Network::Testnet),
+            ("TB1QW508D6QEJXTDG4Y5R3ZARVARY0C5XW7KXPJZSX", AddressType::P2wpkh, Network::Testnet),
+            (
+                "tb1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3q0sl5k7",
+                AddressType::P2wsh,
+                Network::Testnet,
+            ),
+            (
+                "tb1pqqqqp399et2xygdj5xreqhjjvcmzhxw4aywxecjdzew6hylgvsesf3hn0c",
+                AddressType::P2tr,
+                Network::Testnet,
+            ),
+            ("bcrt1qqqqsyqcyq5rqwzqfpg9scrgwpugpzysnard0ew", AddressType::P2wpkh, Network::Regtest),
+        ];
+        for (address, address_type, network) in cases {
+            assert_eq!(parsed_type(address), Ok((address_type, network)), "{}", address);
+        }
+    }
+
+    #[test]
+    fn encodes_p2wpkh_addresses() {
+        // BIP 173 example: the secp256k1 generator point as a compressed public key
+        let public_key: [u8; 33] = [
+            0x02, 0x79, 0xbe, 0x66, 0x7e, 0xf9, 0xdc, 0xbb, 0xac, 0x55, 0xa0, 0x62, 0x95, 0xce, 0x87, 0x0b,
+            0x07, 0x02, 0x9b, 0xfc, 0xdb, 0x2d, 0xce, 0x28, 0xd9, 0x59, 0xf2, 0x81, 0x5b, 0x16, 0xf8, 0x17,
+            0x98,
+        ];
+        let address = p2wpkh_address(Network::Testnet, &public_key);
+        assert_eq!(address, "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx");
+
+        let regtest = p2wpkh_address(Network::Regtest, &public_key);
+        let parsed = parse(&regtest).unwrap();
+        assert_eq!((parsed.address_type, parsed.network), (AddressType::P2wpkh, Network::Regtest));
+        assert_eq!(parsed.payload, hash160(&public_key));
+    }
+
+    #[test]
+    fn rejects_mainnet_addresses() {
+        for address in [
+            "1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2",
+            "3J98t1WpEZ73CNmQviecrnyiWrnqRhWNLy",
+            "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4",
+        ] {
+            assert_eq!(parse(address), Err(AddressError::Mainnet), "{}", address);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_addresses() {
+        let cases = [
+            ("nonsense", AddressError::InvalidChecksum),
+            ("mipcBbFg9gMiCh81Kj8tqqdgoZub1ZJRf0", AddressError::InvalidCharacter('0')),
+            ("tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsb", AddressError::InvalidCharacter('b')),
+            ("", AddressError::InvalidLength(0)),
+            ("mipcBbFg9gMiCh81Kj8tqqdgoZub1ZJRfm", AddressError::InvalidChecksum),
+            ("tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsy", AddressError::InvalidChecksum),
+            ("tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzSX", AddressError::MixedCase),
+            // Version 0 with a bech32m checksum, version 1 with a bech32 one
+            ("tb1qqqqsyqcyq5rqwzqfpg9scrgwpugpzysn2kywt9", AddressError::InvalidChecksum),
+            (
+                "tb1pqqqsyqcyq5rqwzqfpg9scrgwpugpzysnzs23v9ccrydpk8qarc0s2qdcv0",
+                AddressError::InvalidChecksum,
+            ),
+            ("tb1zqqqsyqcyq5rqwzqfpg9scrgwpus059r6", AddressError::UnsupportedWitnessVersion(2)),
+        ];
+        for (address, error) in cases {
+            assert_eq!(parse(address), Err(error), "{}", address);
+        }
+    }
+}
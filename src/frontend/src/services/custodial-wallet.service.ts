@@ -219,7 +219,8 @@ export const withdrawFunds = async (amount: string): Promise<WithdrawResult> =>
     }
 
 
-    const result = await backend.withdraw_funds(BigInt(amountSatoshis));
+    // Default fee mode: the amount arrives in full and the ledger fee is debited on top
+    const result = await backend.withdraw_funds(BigInt(amountSatoshis), []);
 
     if ('Ok' in result) {
       const blockIndex = result.Ok.toString();
@@ -354,7 +355,17 @@ export const getReserveStatus = async (): Promise<CustodialServiceResult> => {
       };
     }
 
-    const status = await backend.get_reserve_status();
+    const result = await backend.get_reserve_status();
+
+    if ('Err' in result) {
+      console.error('[Custodial Service] Reserve check failed:', result.Err);
+      return {
+        success: false,
+        error: String(result.Err)
+      };
+    }
+
+    const status = result.Ok;
 
     return {
       success: true,
@@ -363,6 +374,9 @@ export const getReserveStatus = async (): Promise<CustodialServiceResult> => {
         backendActualBalance: (Number(status.backend_actual_balance) / 100000000).toFixed(8),
         reserveRatio: status.reserve_ratio,
         isSolvent: status.is_solvent,
+        surplus: (Number(status.surplus) / 100000000).toFixed(8),
+        deficit: (Number(status.deficit) / 100000000).toFixed(8),
+        checkedAt: status.checked_at,
       },
     };
   } catch (error: any) {
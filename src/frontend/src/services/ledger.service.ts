@@ -25,11 +25,28 @@ interface TransferResult {
   Err?: TransferError;
 }
 
+interface ApproveArgs {
+  from_subaccount: [] | [Uint8Array];
+  spender: Account;
+  amount: bigint;
+  expected_allowance: [] | [bigint];
+  expires_at: [] | [bigint];
+  fee: [] | [bigint];
+  memo: [] | [Uint8Array];
+  created_at_time: [] | [bigint];
+}
+
+interface ApproveResult {
+  Ok?: bigint;
+  Err?: Record<string, any>;
+}
+
 // Proper interface for ICRC-1 ledger actor
 interface LedgerActor {
   icrc1_balance_of(account: Account): Promise<bigint>;
   icrc1_transfer(args: TransferArgs): Promise<TransferResult>;
   icrc1_fee(): Promise<bigint>;
+  icrc2_approve(args: ApproveArgs): Promise<ApproveResult>;
 }
 
 interface TransferError {
@@ -116,6 +133,14 @@ const getLedgerActor = async (): Promise<LedgerActor> => {
   return ledgerActor;
 };
 
+/**
+ * Current transfer fee in satoshis, as reported by the ledger
+ */
+export const getTransferFee = async (): Promise<bigint> => {
+  const actor = await getLedgerActor();
+  return actor.icrc1_fee();
+};
+
 /**
  * Transfer tokens to a custodial account (with subaccount)
  * Used for depositing to backend canister with user-specific subaccount
@@ -138,7 +163,7 @@ export const transferToSubaccount = async (
         subaccount: [toSubaccount], // Array for Some(subaccount)
       },
       amount: amountBigInt,
-      fee: [BigInt(10)], // 10 satoshi fee
+      fee: [await actor.icrc1_fee()],
       memo: [],
       created_at_time: [BigInt(Date.now() * 1000000)],
     };
@@ -217,7 +242,7 @@ export const transferViaLedger = async (
         subaccount: [], // Use empty array for None in Candid optional
       },
       amount: amountBigInt,
-      fee: [BigInt(10)], // Use array for Some(value) in Candid optional
+      fee: [await actor.icrc1_fee()], // Use array for Some(value) in Candid optional
       memo: [], // Use empty array for None in Candid optional
       created_at_time: [BigInt(Date.now() * 1000000)], // Use array for Some(value) in Candid optional
     };
@@ -249,6 +274,42 @@ export const transferViaLedger = async (
   }
 };
 
+/**
+ * Approve a spender (e.g. the backend canister) to pull funds with icrc2_transfer_from
+ * Amount is in satoshis and must cover the transfer amount plus the ledger fee
+ */
+export const approveSpender = async (
+  spender: Principal,
+  amountSatoshis: bigint
+): Promise<{ success: boolean; blockIndex?: string; error?: string }> => {
+  try {
+    const actor = await getLedgerActor();
+
+    const result = await actor.icrc2_approve({
+      from_subaccount: [],
+      spender: { owner: spender, subaccount: [] },
+      amount: amountSatoshis,
+      expected_allowance: [],
+      expires_at: [BigInt((Date.now() + 10 * 60 * 1000) * 1000000)], // 10 minutes
+      fee: [await actor.icrc1_fee()],
+      memo: [],
+      created_at_time: [BigInt(Date.now() * 1000000)],
+    });
+
+    if ('Ok' in result && result.Ok !== undefined) {
+      console.log('[Ledger Service] Approval successful, block index:', result.Ok.toString());
+      return { success: true, blockIndex: result.Ok.toString() };
+    }
+    const error = `Approval failed: ${JSON.stringify(result.Err, (_, v) => typeof v === 'bigint' ? v.toString() : v)}`;
+    console.error('[Ledger Service]', error);
+    return { success: false, error };
+  } catch (error: unknown) {
+    const errorMessage = error instanceof Error ? error.message : 'Approval failed';
+    console.error('[Ledger Service] Approval error:', error);
+    return { success: false, error: errorMessage };
+  }
+};
+
 /**
  * Get transfer fee from ledger
  */
@@ -71,6 +71,22 @@ export const getWalletStatus = async (): Promise<{ success: boolean; status?: Wa
   }
 };
 
+const formatDepositError = (error: any): string => {
+  const toBtc = (satoshis: bigint) => (Number(satoshis) / 100000000).toFixed(8);
+  if ('InsufficientAllowance' in error) {
+    const { allowance, required } = error.InsufficientAllowance;
+    return `Insufficient allowance: approved ${toBtc(allowance)} ckTestBTC, deposit needs ${toBtc(required)} ckTestBTC`;
+  }
+  if ('InsufficientFunds' in error) {
+    const { balance, required } = error.InsufficientFunds;
+    return `Insufficient balance: ${toBtc(balance)} ckTestBTC available, ${toBtc(required)} ckTestBTC needed (including fee)`;
+  }
+  if ('LedgerError' in error) {
+    return error.LedgerError;
+  }
+  return error.Other;
+};
+
 /**
  * Deposit personal funds into custody
  */
@@ -84,6 +100,14 @@ export const depositToCustody = async (amount: string): Promise<{ success: boole
     // Convert amount to smallest units (satoshis)
     const amountInSatoshis = Math.floor(Number(amount) * 100000000);
 
+    // The backend pulls the funds with icrc2_transfer_from: approve amount + fee first
+    const { approveSpender, getTransferFee } = await import('./ledger.service');
+    const fee = await getTransferFee();
+    const approval = await approveSpender(Principal.fromText(getNetworkConfig().canisterId), BigInt(amountInSatoshis) + fee);
+    if (!approval.success) {
+      return { success: false, error: approval.error };
+    }
+
     const result = await backend.deposit_to_custody(BigInt(amountInSatoshis));
     if ('Ok' in result) {
       const receipt = result.Ok;
@@ -97,7 +121,7 @@ export const depositToCustody = async (amount: string): Promise<{ success: boole
         }
       };
     } else {
-      return { success: false, error: result.Err };
+      return { success: false, error: formatDepositError(result.Err) };
     }
   } catch (error: any) {
     return { success: false, error: error.message || 'Failed to deposit to custody' };
@@ -3,7 +3,7 @@
 // It implements ICRC-1/2 standards for ckTestBTC token operations.
 // NEVER processes mainnet Bitcoin (BTC) tokens.
 
-use candid::{CandidType, Deserialize, Nat, Principal};
+use candid::{CandidType, Deserialize, Int, Nat, Principal};
 use ic_cdk_macros::{init, query, update};
 use serde::Serialize;
 use std::cell::RefCell;
@@ -112,7 +112,7 @@ pub type TransferFromResult = Result<Nat, TransferFromError>;
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
 pub enum MetadataValue {
     Nat(Nat),
-    Int(i64),
+    Int(Int),
     Text(String),
     Blob(Vec<u8>),
 }
@@ -123,12 +123,114 @@ pub struct StandardRecord {
     pub url: String,
 }
 
+// Block log types, same shape as the ICRC-1 ledger's get_transactions
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Mint {
+    pub to: Account,
+    pub amount: Nat,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<Timestamp>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Burn {
+    pub from: Account,
+    pub spender: Option<Account>,
+    pub amount: Nat,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<Timestamp>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Transfer {
+    pub from: Account,
+    pub to: Account,
+    pub spender: Option<Account>,
+    pub amount: Nat,
+    pub fee: Option<Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<Timestamp>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Approve {
+    pub from: Account,
+    pub spender: Account,
+    pub amount: Nat,
+    pub expected_allowance: Option<Nat>,
+    pub expires_at: Option<Timestamp>,
+    pub fee: Option<Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<Timestamp>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Transaction {
+    pub kind: String,
+    pub mint: Option<Mint>,
+    pub burn: Option<Burn>,
+    pub transfer: Option<Transfer>,
+    pub approve: Option<Approve>,
+    pub timestamp: Timestamp,
+}
+
+impl Transaction {
+    fn new(kind: &str) -> Self {
+        Transaction {
+            kind: kind.to_string(),
+            mint: None,
+            burn: None,
+            transfer: None,
+            approve: None,
+            timestamp: ic_cdk::api::time(),
+        }
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct GetTransactionsRequest {
+    pub start: Nat,
+    pub length: Nat,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct TransactionRange {
+    pub transactions: Vec<Transaction>,
+}
+
+candid::define_function!(pub QueryArchiveFn : (GetTransactionsRequest) -> (TransactionRange) query);
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ArchivedRange {
+    pub start: Nat,
+    pub length: Nat,
+    pub callback: QueryArchiveFn,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct GetTransactionsResponse {
+    pub log_length: Nat,
+    pub first_index: Nat,
+    pub transactions: Vec<Transaction>,
+    pub archived_transactions: Vec<ArchivedRange>,
+}
+
 // Storage
 thread_local! {
     static BALANCES: RefCell<HashMap<Account, Nat>> = RefCell::new(HashMap::new());
     static ALLOWANCES: RefCell<HashMap<(Account, Account), Allowance>> = RefCell::new(HashMap::new());
     static TOTAL_SUPPLY: RefCell<Nat> = RefCell::new(Nat::from(0u64));
-    static BLOCK_INDEX: RefCell<Nat> = RefCell::new(Nat::from(0u64));
+    // Every block ever created; a block's index is its position. Never archived.
+    static BLOCKS: RefCell<Vec<Transaction>> = const { RefCell::new(Vec::new()) };
+}
+
+// Append a block to the log and return its index
+fn append_block(transaction: Transaction) -> BlockIndex {
+    BLOCKS.with(|blocks| {
+        let mut blocks = blocks.borrow_mut();
+        blocks.push(transaction);
+        Nat::from(blocks.len() as u64 - 1)
+    })
 }
 
 const TRANSFER_FEE: u64 = 10; // 0.00000010 ckTestBTC
@@ -209,7 +311,7 @@ fn icrc1_transfer(args: TransferArg) -> TransferResult {
 
     // Check fee
     let fee = args.fee.unwrap_or_else(|| Nat::from(TRANSFER_FEE));
-    if fee != Nat::from(TRANSFER_FEE) {
+    if fee != TRANSFER_FEE {
         return Err(TransferError::BadFee {
             expected_fee: Nat::from(TRANSFER_FEE),
         });
@@ -238,10 +340,10 @@ fn icrc1_transfer(args: TransferArg) -> TransferResult {
 
         // Deduct from sender (amount + fee)
         let new_sender_balance = sender_balance - total_amount;
-        if new_sender_balance == Nat::from(0u64) {
+        if new_sender_balance == 0u64 {
             balances.remove(&from_account);
         } else {
-            balances.insert(from_account, new_sender_balance);
+            balances.insert(from_account.clone(), new_sender_balance);
         }
 
         // Add to receiver (only the amount, fee is burned)
@@ -249,16 +351,21 @@ fn icrc1_transfer(args: TransferArg) -> TransferResult {
             .get(&args.to)
             .cloned()
             .unwrap_or_else(|| Nat::from(0u64));
-        balances.insert(args.to, receiver_balance + args.amount);
+        balances.insert(args.to.clone(), receiver_balance + args.amount.clone());
     });
 
-    // Return block index and increment
-    BLOCK_INDEX.with(|bi| {
-        let mut block_index = bi.borrow_mut();
-        let current_index = block_index.clone();
-        *block_index = current_index.clone() + Nat::from(1u64);
-        Ok(current_index)
-    })
+    Ok(append_block(Transaction {
+        transfer: Some(Transfer {
+            from: from_account,
+            to: args.to,
+            spender: None,
+            amount: args.amount,
+            fee: Some(fee),
+            memo: args.memo,
+            created_at_time: args.created_at_time,
+        }),
+        ..Transaction::new("transfer")
+    }))
 }
 
 #[query]
@@ -287,7 +394,7 @@ fn icrc2_approve(args: ApproveArgs) -> ApproveResult {
 
     // Check fee
     let fee = args.fee.unwrap_or_else(|| Nat::from(TRANSFER_FEE));
-    if fee != Nat::from(TRANSFER_FEE) {
+    if fee != TRANSFER_FEE {
         return Err(ApproveError::BadFee {
             expected_fee: Nat::from(TRANSFER_FEE),
         });
@@ -311,7 +418,7 @@ fn icrc2_approve(args: ApproveArgs) -> ApproveResult {
         allowances.insert(
             (from_account.clone(), args.spender.clone()),
             Allowance {
-                allowance: args.amount,
+                allowance: args.amount.clone(),
                 expires_at: args.expires_at,
             },
         );
@@ -320,21 +427,27 @@ fn icrc2_approve(args: ApproveArgs) -> ApproveResult {
     // Deduct fee
     BALANCES.with(|b| {
         let mut balances = b.borrow_mut();
-        let new_balance = balance - fee;
-        if new_balance == Nat::from(0u64) {
+        let new_balance = balance - fee.clone();
+        if new_balance == 0u64 {
             balances.remove(&from_account);
         } else {
-            balances.insert(from_account, new_balance);
+            balances.insert(from_account.clone(), new_balance);
         }
     });
 
-    // Return block index and increment
-    BLOCK_INDEX.with(|bi| {
-        let mut block_index = bi.borrow_mut();
-        let current_index = block_index.clone();
-        *block_index = current_index.clone() + Nat::from(1u64);
-        Ok(current_index)
-    })
+    Ok(append_block(Transaction {
+        approve: Some(Approve {
+            from: from_account,
+            spender: args.spender,
+            amount: args.amount,
+            expected_allowance: args.expected_allowance,
+            expires_at: args.expires_at,
+            fee: Some(fee),
+            memo: args.memo,
+            created_at_time: args.created_at_time,
+        }),
+        ..Transaction::new("approve")
+    }))
 }
 
 #[query]
@@ -398,7 +511,7 @@ fn icrc2_transfer_from(args: TransferFromArgs) -> TransferFromResult {
 
         // Deduct from sender
         let new_from_balance = from_balance - total_amount.clone();
-        if new_from_balance == Nat::from(0u64) {
+        if new_from_balance == 0u64 {
             balances.remove(&args.from);
         } else {
             balances.insert(args.from.clone(), new_from_balance);
@@ -409,18 +522,18 @@ fn icrc2_transfer_from(args: TransferFromArgs) -> TransferFromResult {
             .get(&args.to)
             .cloned()
             .unwrap_or_else(|| Nat::from(0u64));
-        balances.insert(args.to, to_balance + args.amount);
+        balances.insert(args.to.clone(), to_balance + args.amount.clone());
     });
 
     // Update allowance
     ALLOWANCES.with(|a| {
         let mut allowances = a.borrow_mut();
         let new_allowance = allowance.allowance - total_amount;
-        if new_allowance == Nat::from(0u64) {
-            allowances.remove(&(args.from, spender_account));
+        if new_allowance == 0u64 {
+            allowances.remove(&(args.from.clone(), spender_account.clone()));
         } else {
             allowances.insert(
-                (args.from, spender_account),
+                (args.from.clone(), spender_account.clone()),
                 Allowance {
                     allowance: new_allowance,
                     expires_at: allowance.expires_at,
@@ -429,13 +542,18 @@ fn icrc2_transfer_from(args: TransferFromArgs) -> TransferFromResult {
         }
     });
 
-    // Return block index and increment
-    BLOCK_INDEX.with(|bi| {
-        let mut block_index = bi.borrow_mut();
-        let current_index = block_index.clone();
-        *block_index = current_index.clone() + Nat::from(1u64);
-        Ok(current_index)
-    })
+    Ok(append_block(Transaction {
+        transfer: Some(Transfer {
+            from: args.from,
+            to: args.to,
+            spender: Some(spender_account),
+            amount: args.amount,
+            fee: Some(fee),
+            memo: args.memo,
+            created_at_time: args.created_at_time,
+        }),
+        ..Transaction::new("transfer")
+    }))
 }
 
 // Helper function for testing - mint tokens
@@ -457,7 +575,7 @@ pub fn mint(to: Account, amount: Nat) -> TransferResult {
 
     // Check if caller is authorized to mint
     let is_authorized = caller == minter_principal ||
-                       backend_principal.map_or(false, |p| p == caller);
+                       backend_principal == Some(caller);
 
     if !is_authorized {
         ic_cdk::println!(
@@ -481,21 +599,43 @@ pub fn mint(to: Account, amount: Nat) -> TransferResult {
             .get(&to)
             .cloned()
             .unwrap_or_else(|| Nat::from(0u64));
-        balances.insert(to, current_balance + amount.clone());
+        balances.insert(to.clone(), current_balance + amount.clone());
     });
 
     // Update total supply
     TOTAL_SUPPLY.with(|ts| {
         let mut total_supply = ts.borrow_mut();
-        *total_supply = total_supply.clone() + amount;
+        *total_supply = total_supply.clone() + amount.clone();
     });
 
-    // Return block index and increment
-    BLOCK_INDEX.with(|bi| {
-        let mut block_index = bi.borrow_mut();
-        let current_index = block_index.clone();
-        *block_index = current_index.clone() + Nat::from(1u64);
-        Ok(current_index)
+    Ok(append_block(Transaction {
+        mint: Some(Mint {
+            to,
+            amount,
+            memo: None,
+            created_at_time: None,
+        }),
+        ..Transaction::new("mint")
+    }))
+}
+
+// Block log in ICRC-1 ledger format. The mock keeps every block itself, so
+// archived_transactions is always empty.
+#[query]
+fn get_transactions(request: GetTransactionsRequest) -> GetTransactionsResponse {
+    BLOCKS.with(|blocks| {
+        let blocks = blocks.borrow();
+        let log_length = blocks.len();
+        let start = usize::try_from(&request.start.0).unwrap_or(usize::MAX).min(log_length);
+        let length = usize::try_from(&request.length.0).unwrap_or(usize::MAX);
+        let end = start.saturating_add(length).min(log_length);
+
+        GetTransactionsResponse {
+            log_length: Nat::from(log_length as u64),
+            first_index: Nat::from(start as u64),
+            transactions: blocks[start..end].to_vec(),
+            archived_transactions: Vec::new(),
+        }
     })
 }
 
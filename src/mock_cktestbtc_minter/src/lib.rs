@@ -4,10 +4,11 @@
 // NEVER processes mainnet Bitcoin (BTC) transactions.
 
 use candid::{CandidType, Deserialize, Principal};
-use ic_cdk_macros::{init, query, update};
+use ic_cdk_macros::{init, post_upgrade, query, update};
 use serde::Serialize;
+use k256::elliptic_curve::sec1::ToEncodedPoint;
 use sha2::{Digest, Sha256};
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
 
 // Types matching the Candid interface
@@ -135,7 +136,10 @@ thread_local! {
     static KNOWN_UTXOS: RefCell<HashMap<Account, Vec<Utxo>>> = RefCell::new(HashMap::new());
     static PENDING_UTXOS: RefCell<HashMap<Account, Vec<Utxo>>> = RefCell::new(HashMap::new());
     static WITHDRAWAL_REQUESTS: RefCell<HashMap<u64, RetrieveBtcStatus>> = RefCell::new(HashMap::new());
-    static BLOCK_INDEX: RefCell<u64> = RefCell::new(0u64);
+    static BLOCK_INDEX: RefCell<u64> = const { RefCell::new(0u64) };
+    // Network whose address prefix deposit addresses carry. Heap state does not
+    // survive upgrades, so post_upgrade sets it again from the install argument.
+    static NETWORK: Cell<btc_address::Network> = const { Cell::new(btc_address::Network::Testnet) };
 }
 
 const MIN_WITHDRAWAL_AMOUNT: u64 = 1000; // 0.00001000 TestBTC (1000 satoshi)
@@ -144,15 +148,64 @@ const MINTER_FEE: u64 = 100; // 100 satoshi minter fee
 const NETWORK_FEE: u64 = 5000; // 5000 satoshi network fee
 
 #[init]
-fn init() {
+fn init(network: Option<BtcNetwork>) {
     // Initialize storage
     ic_cdk::println!("[MOCK_MINTER] Initializing mock ckTestBTC minter canister...");
+    set_network(network);
     KNOWN_UTXOS.with(|utxos| utxos.borrow_mut().clear());
     PENDING_UTXOS.with(|pending| pending.borrow_mut().clear());
     WITHDRAWAL_REQUESTS.with(|withdrawals| withdrawals.borrow_mut().clear());
     ic_cdk::println!("[MOCK_MINTER] Initialization complete. Ready to process TestBTC operations.");
 }
 
+#[post_upgrade]
+fn post_upgrade(network: Option<BtcNetwork>) {
+    set_network(network);
+}
+
+fn set_network(network: Option<BtcNetwork>) {
+    let network = match network {
+        Some(BtcNetwork::Mainnet) => ic_cdk::trap("The mock minter only runs on testnet or regtest"),
+        Some(BtcNetwork::Testnet) | None => btc_address::Network::Testnet,
+        Some(BtcNetwork::Regtest) => btc_address::Network::Regtest,
+    };
+    NETWORK.with(|n| n.set(network));
+    ic_cdk::println!("[MOCK_MINTER] Handing out {:?} deposit addresses", network);
+}
+
+// Mock key derivation. The real minter derives each account's key from its
+// threshold ECDSA key; the mock derives a secp256k1 secret key per
+// (owner, subaccount) by hashing a fixed mock master key with the account,
+// so deposit addresses are valid and stable across calls and reinstalls.
+// The keys are public knowledge: never send real funds to these addresses.
+const MOCK_MASTER_KEY: &[u8] = b"mock_cktestbtc_minter master key (testnet only)";
+
+fn derive_public_key(account: &Account) -> [u8; 33] {
+    // A None subaccount is the default (all-zero) subaccount, as on the ledger
+    let subaccount = account.subaccount.clone().unwrap_or_else(|| vec![0u8; 32]);
+
+    // A hash outside the curve order is vanishingly rare; retry with a counter
+    for counter in 0u32.. {
+        let mut hasher = Sha256::new();
+        hasher.update(MOCK_MASTER_KEY);
+        hasher.update([account.owner.as_slice().len() as u8]);
+        hasher.update(account.owner.as_slice());
+        hasher.update(&subaccount);
+        hasher.update(counter.to_be_bytes());
+        if let Ok(secret_key) = k256::SecretKey::from_slice(&hasher.finalize()) {
+            let encoded = secret_key.public_key().to_encoded_point(true);
+            return encoded.as_bytes().try_into().expect("compressed public keys are 33 bytes");
+        }
+    }
+    unreachable!("no valid secret key derived")
+}
+
+// P2WPKH address of the account's deterministic mock key on the configured network
+fn deposit_address(account: &Account) -> String {
+    let public_key = derive_public_key(account);
+    btc_address::p2wpkh_address(NETWORK.with(|n| n.get()), &public_key)
+}
+
 // Convert TestBTC to ckTestBTC methods
 
 #[update]
@@ -166,18 +219,11 @@ fn get_btc_address(args: GetBtcAddressArgs) -> String {
         subaccount: args.subaccount.clone(),
     };
 
-    // Generate a deterministic mock TestBTC address based on the account
-    let mut hasher = Sha256::new();
-    hasher.update(account.owner.as_slice());
     if let Some(ref subaccount) = account.subaccount {
-        hasher.update(subaccount);
         ic_cdk::println!("[MOCK_MINTER] Including subaccount in address generation: {:?}", subaccount);
     }
-    let hash = hasher.finalize();
 
-    // Create a mock TestBTC address (tb1q format for bech32 testnet)
-    let addr_suffix = hex::encode(&hash[..20]);
-    let address = format!("tb1q{}", &addr_suffix[..32]);
+    let address = deposit_address(&account);
     ic_cdk::println!("[MOCK_MINTER] Generated TestBTC address: {}", address);
     address
 }
@@ -322,12 +368,13 @@ fn retrieve_btc(args: RetrieveBtcArgs) -> RetrieveBtcResult {
         caller, args.amount, args.address
     );
 
-    // Validate TestBTC address format (basic validation)
-    if !args.address.starts_with("tb1") && !args.address.starts_with("2") && !args.address.starts_with("m") && !args.address.starts_with("n") {
-        ic_cdk::println!("[MOCK_MINTER] ERROR: Invalid TestBTC address format: {}", args.address);
-        return Err(RetrieveBtcError::MalformedAddress(
-            "Invalid TestBTC address format".to_string(),
-        ));
+    // Validate TestBTC address: must decode as a testnet or regtest address
+    match btc_address::parse(&args.address) {
+        Ok(parsed) => ic_cdk::println!("[MOCK_MINTER] Destination is a {:?} address", parsed.address_type),
+        Err(e) => {
+            ic_cdk::println!("[MOCK_MINTER] ERROR: Invalid TestBTC address {}: {}", args.address, e);
+            return Err(RetrieveBtcError::MalformedAddress(e.to_string()));
+        }
     }
 
     // Check minimum withdrawal amount
@@ -349,8 +396,8 @@ fn retrieve_btc(args: RetrieveBtcArgs) -> RetrieveBtcResult {
     // Mock: Create a fake transaction ID
     let mut hasher = Sha256::new();
     hasher.update(args.address.as_bytes());
-    hasher.update(&args.amount.to_le_bytes());
-    hasher.update(&block_index.to_le_bytes());
+    hasher.update(args.amount.to_le_bytes());
+    hasher.update(block_index.to_le_bytes());
     let _txid = hasher.finalize().to_vec();
 
     // Store withdrawal status
@@ -407,8 +454,8 @@ pub fn simulate_testbtc_deposit(account: Account, amount: u64) {
     // Create a mock UTXO for testing
     let mut hasher = Sha256::new();
     hasher.update(account.owner.as_slice());
-    hasher.update(&amount.to_le_bytes());
-    hasher.update(&ic_cdk::api::time().to_le_bytes());
+    hasher.update(amount.to_le_bytes());
+    hasher.update(ic_cdk::api::time().to_le_bytes());
     let txid = hasher.finalize().to_vec();
 
     let utxo = Utxo {
@@ -435,4 +482,60 @@ pub fn update_withdrawal_status(block_index: u64, status: RetrieveBtcStatus) {
     });
 }
 
-ic_cdk::export_candid!();
\ No newline at end of file
+ic_cdk::export_candid!();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use btc_address::{AddressType, Network};
+
+    fn account(owner: u8, subaccount: Option<Vec<u8>>) -> Account {
+        Account {
+            owner: Principal::from_slice(&[owner; 29]),
+            subaccount,
+        }
+    }
+
+    #[test]
+    fn deposit_addresses_are_deterministic_and_decode() {
+        let user = account(1, None);
+        let address = deposit_address(&user);
+        assert_eq!(address, deposit_address(&user));
+        // None is the default subaccount
+        assert_eq!(address, deposit_address(&account(1, Some(vec![0u8; 32]))));
+        assert_ne!(address, deposit_address(&account(1, Some(vec![1u8; 32]))));
+        assert_ne!(address, deposit_address(&account(2, None)));
+
+        let parsed = btc_address::parse(&address).unwrap();
+        assert_eq!((parsed.address_type, parsed.network), (AddressType::P2wpkh, Network::Testnet));
+        assert_eq!(parsed.payload, btc_address::hash160(&derive_public_key(&user)));
+    }
+
+    #[test]
+    fn deposit_addresses_match_known_vectors() {
+        // Worked out independently of this crate from the derivation above
+        assert_eq!(deposit_address(&account(1, None)), "tb1q8k9hdykfn8th4cr2jzjzda7nqsza23l9sfl40n");
+        assert_eq!(
+            deposit_address(&account(1, Some(vec![7u8; 32]))),
+            "tb1qzn8w9vj7udn7fyfmtp0rv53pzj3curamqzd4yh"
+        );
+    }
+
+    #[test]
+    fn deposit_addresses_follow_the_configured_network() {
+        let user = account(1, None);
+        let testnet = deposit_address(&user);
+        set_network(Some(BtcNetwork::Regtest));
+        let regtest = deposit_address(&user);
+        assert!(regtest.starts_with("bcrt1q"), "{}", regtest);
+
+        // Same key under either prefix
+        let parsed = btc_address::parse(&regtest).unwrap();
+        assert_eq!((parsed.address_type, parsed.network), (AddressType::P2wpkh, Network::Regtest));
+        assert_eq!(parsed.payload, btc_address::parse(&testnet).unwrap().payload);
+
+        // No argument means testnet, on install and on upgrade alike
+        set_network(None);
+        assert_eq!(deposit_address(&user), testnet);
+    }
+}
\ No newline at end of file